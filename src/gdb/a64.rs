@@ -1,54 +1,95 @@
 use std::borrow::Borrow;
 use std::collections::hash_map::{Entry, HashMap};
-use std::convert::TryInto;
+use std::time::Duration;
+
+use core::num::NonZeroUsize;
 
 use gdbstub::arch::{Arch, RegId, Registers};
-use gdbstub::target::ext::base::singlethread::{SingleThreadOps, StopReason};
-use gdbstub::target::ext::base::{BaseOps, ResumeAction};
+use gdbstub::common::Signal;
+use gdbstub::outputln;
+use gdbstub::stub::SingleThreadStopReason as StopReason;
+use gdbstub::target::ext::base::singlethread::{
+    SingleThreadBase, SingleThreadResume, SingleThreadResumeOps, SingleThreadSingleStep,
+    SingleThreadSingleStepOps,
+};
+use gdbstub::target::ext::base::BaseOps;
 #[allow(unused)]
 use gdbstub::target::ext::breakpoints::{
     Breakpoints, BreakpointsOps, HwBreakpoint, HwBreakpointOps, SwBreakpoint, SwBreakpointOps,
 };
 use gdbstub::target::ext::monitor_cmd::{ConsoleOutput, MonitorCmd, MonitorCmdOps};
-use gdbstub::target::{Target, TargetResult};
-use gdbstub::outputln;
+use gdbstub::target::{Target, TargetError, TargetResult};
 
-use crate::{
-    breakpoint, instance_registry, memory, resource, simulation, simulation_time, step,
-    FastModelIris,
+use crate::gdb::{
+    check_space, disasm_monitor_cmd, pc_space, read_memory, read_resource, report,
+    resource_monitor_cmd, run_until_halt, write_memory, RunTarget, SIGALRM, SIGINT, SIGTRAP,
 };
+use crate::memory::Endianness;
+use crate::simulation::ResetKind;
+use crate::{breakpoint, instance_registry, resource, step, FastModelIris, IrisError};
 
 pub struct IrisGdbStub<'i> {
     pub iris: &'i mut FastModelIris,
     pub instance_id: u32,
     sim: u32,
     breakpoints: HashMap<u64, u64>,
+    /// The memory space used by `read_addrs` and `write_addrs`
+    space_id: u64,
+    /// How long a continue may run before the target is stopped
+    timeout: Option<Duration>,
+    /// Whether gdb last asked for a step rather than a continue
+    stepping: bool,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct GuestState {
-    pub regs: Vec<u64>,
+    /// X0-X30, SP, PC and CPSR, in the order given by `TARGET_XML`
+    pub regs: [u64; 34],
 }
 
 impl Default for GuestState {
     fn default() -> Self {
-        Self { regs: vec![0; 98] }
+        Self { regs: [0; 34] }
     }
 }
 
 impl<'i> IrisGdbStub<'i> {
-    pub fn from_instance(iris: &'i mut FastModelIris, instance_id: u32) -> std::io::Result<Self> {
+    pub fn from_instance(iris: &'i mut FastModelIris, instance_id: u32) -> Result<Self, IrisError> {
         let sim = instance_registry::get_instance_by_name(
             iris,
             "framework.SimulationEngine".to_string(),
         )?;
+        let space_id = pc_space(iris, instance_id)?;
         Ok(Self {
             iris,
             instance_id,
             breakpoints: HashMap::new(),
+            space_id,
+            timeout: None,
+            stepping: false,
             sim: sim.id,
         })
     }
+    /// Access memory through the space `space_id` rather than the space
+    /// the PC was in when the stub was created. On a TrustZone core this
+    /// selects between the Secure and Non-secure views of memory.
+    pub fn with_memory_space(mut self, space_id: u64) -> Result<Self, IrisError> {
+        check_space(self.iris, self.instance_id, space_id)?;
+        self.space_id = space_id;
+        Ok(self)
+    }
+
+    /// Stop the target and report `SIGALRM` to gdb when it runs for
+    /// longer than `timeout` after a continue.
+    pub fn with_resume_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Read the program counter of the core
+    fn read_pc(&mut self) -> Result<Option<u64>, IrisError> {
+        read_resource(self.iris, self.instance_id, "PC")
+    }
 }
 
 impl Registers for GuestState {
@@ -57,30 +98,43 @@ impl Registers for GuestState {
         self.regs[32]
     }
     fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
-        for reg in &self.regs {
-            for byte in reg.to_le_bytes().iter() {
+        for (num, reg) in self.regs.iter().enumerate() {
+            let width = if num == 33 { 4 } else { 8 };
+            for byte in reg.to_le_bytes()[..width].iter() {
                 write_byte(Some(*byte));
             }
         }
-        write_byte(Some(0));
-        write_byte(Some(0));
-        write_byte(Some(0));
-        write_byte(Some(0));
     }
     fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
-        if bytes.len() % 8 != 0 {
-            return Err(());
-        }
-        let mut regs = bytes
-            .chunks_exact(8)
-            .map(|c| u64::from_le_bytes(c.try_into().unwrap()));
-        for reg in &mut self.regs {
-            *reg = regs.next().ok_or(())?;
+        // X0-X30, SP and PC are 64 bits wide; they are followed by the 32 bit
+        // CPSR.
+        let mut offset = 0;
+        for (num, reg) in self.regs.iter_mut().enumerate() {
+            let width = if num == 33 { 4 } else { 8 };
+            let mut word = [0u8; 8];
+            word[..width].copy_from_slice(bytes.get(offset..offset + width).ok_or(())?);
+            *reg = u64::from_le_bytes(word);
+            offset += width;
         }
         Ok(())
     }
 }
 
+/// Map the name of an Iris resource to its index within `GuestState::regs`
+fn register_index(name: &str) -> Option<usize> {
+    Some(match name {
+        "PC" => 32,
+        "SP" => 31,
+        "XPSR" => 33,
+        "CPSR" => 33,
+        x if x.starts_with('X') => match x[1..].parse() {
+            Ok(regnum) if regnum < 31 => regnum,
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Register {
     X(u8),
@@ -90,7 +144,7 @@ pub enum Register {
 }
 
 impl RegId for Register {
-    fn from_raw_id(id: usize) -> Option<(Self, usize)> {
+    fn from_raw_id(id: usize) -> Option<(Self, Option<NonZeroUsize>)> {
         use Register::*;
         Some(match id {
             32 => PC,
@@ -99,7 +153,17 @@ impl RegId for Register {
             id if id < 31 => X(id as u8),
             _ => return None,
         })
-        .map(|r| (r, 0))
+        .map(|r| (r, None))
+    }
+}
+
+/// Take out the breakpoints gdb left behind, so the next session starts
+/// clean. Failures are ignored, as the model may be gone.
+impl Drop for IrisGdbStub<'_> {
+    fn drop(&mut self) {
+        for (_, id) in self.breakpoints.drain() {
+            let _ = breakpoint::delete(self.iris, self.instance_id, id);
+        }
     }
 }
 
@@ -110,111 +174,133 @@ impl<'i> Target for IrisGdbStub<'i> {
         BaseOps::SingleThread(self)
     }
 
-    fn breakpoints(&mut self) -> Option<BreakpointsOps<Self>> {
+    fn support_breakpoints(&mut self) -> Option<BreakpointsOps<'_, Self>> {
         Some(self)
     }
 
-    fn monitor_cmd(&mut self) -> Option<MonitorCmdOps<Self>> {
+    fn support_monitor_cmd(&mut self) -> Option<MonitorCmdOps<'_, Self>> {
         Some(self)
     }
 }
 
-impl SingleThreadOps for IrisGdbStub<'_> {
+impl SingleThreadBase for IrisGdbStub<'_> {
+    fn support_resume(&mut self) -> Option<SingleThreadResumeOps<'_, Self>> {
+        Some(self)
+    }
+
     fn read_registers(&mut self, regs: &mut GuestState) -> TargetResult<(), Self> {
-        for res in
-            resource::get_list(&mut self.iris, self.instance_id, None, None).map_err(|_| ())?
-        {
-            let regnum = match res.name.as_str() {
-                "PC" => 32,
-                "SP" => 31,
-                "XPSR" => 33,
-                "CPSR" => 33,
-                x if x.starts_with("X") => {
-                    if let Ok(regnum) = x[1..].parse() {
-                        regnum
-                    } else {
-                        continue;
-                    }
-                }
-                _ => continue,
-            };
-            let val =
-                resource::read(&mut self.iris, self.instance_id, vec![res.id]).map_err(|_| ())?;
-            if !val.data.is_empty() {
-                regs.regs[regnum] = val.data[0]
+        let resources = resource::cached_list(self.iris, self.instance_id).map_err(report)?;
+        let wanted: Vec<_> = resources
+            .iter()
+            .filter(|res| register_index(&res.name).is_some())
+            .collect();
+        let values = resource::read_values(self.iris, self.instance_id, &wanted).map_err(report)?;
+        for res in wanted {
+            if let (Some(regnum), Some(value)) = (register_index(&res.name), values.get(&res.id)) {
+                regs.regs[regnum] = *value
             }
         }
         Ok(())
     }
 
-    fn read_addrs(&mut self, start_addr: u64, data: &mut [u8]) -> TargetResult<(), Self> {
-        let mem = memory::read(
-            &mut self.iris,
+    /// Reads up to the first address that faults, failing if that is the
+    /// first one.
+    fn read_addrs(&mut self, start_addr: u64, data: &mut [u8]) -> TargetResult<usize, Self> {
+        let valid = read_memory(
+            self.iris,
             self.instance_id,
-            0,
-            start_addr as u64,
-            1,
-            data.len() as u64,
+            self.space_id,
+            start_addr,
+            data,
+            Endianness::Little,
         )
-        .map_err(|_| ())?;
-        for (offset, byte) in mem
-            .data
-            .into_iter()
-            .map(|u| u.to_le_bytes())
-            .flatten()
-            .enumerate()
-        {
-            if data.len() > offset {
-                data[offset] = byte;
-            }
+        .map_err(report)?;
+        if valid == 0 && !data.is_empty() {
+            return Err(TargetError::NonFatal);
         }
+        Ok(valid)
+    }
+
+    fn write_addrs(&mut self, start_addr: u64, data: &[u8]) -> TargetResult<(), Self> {
+        write_memory(
+            self.iris,
+            self.instance_id,
+            self.space_id,
+            start_addr,
+            data,
+            Endianness::Little,
+        )
+        .map_err(report)?;
         Ok(())
     }
+    fn write_registers(&mut self, regs: &GuestState) -> TargetResult<(), Self> {
+        let (ids, values) = resource::cached_list(self.iris, self.instance_id)
+            .map_err(report)?
+            .iter()
+            .filter_map(|res| register_index(&res.name).map(|regnum| (res.id, regs.regs[regnum])))
+            .unzip();
+        resource::write(self.iris, self.instance_id, ids, values).map_err(report)?;
+        Ok(())
+    }
+}
 
-    fn write_addrs(&mut self, _: u64, _: &[u8]) -> TargetResult<(), Self> {
+/// There's no process to deliver a signal to, so signals are dropped
+impl SingleThreadResume for IrisGdbStub<'_> {
+    fn resume(&mut self, _signal: Option<Signal>) -> Result<(), ()> {
+        self.stepping = false;
         Ok(())
     }
-    fn write_registers(&mut self, _: &GuestState) -> TargetResult<(), Self> {
-        // We don't support writing
+
+    fn support_single_step(&mut self) -> Option<SingleThreadSingleStepOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadSingleStep for IrisGdbStub<'_> {
+    fn step(&mut self, _signal: Option<Signal>) -> Result<(), ()> {
+        self.stepping = true;
         Ok(())
     }
+}
+
+impl RunTarget for IrisGdbStub<'_> {
+    type StopReason = StopReason<u64>;
 
-    fn resume(
+    fn wait_for_stop(
         &mut self,
-        act: ResumeAction,
-        intr: gdbstub::target::ext::base::GdbInterrupt<'_>,
-    ) -> Result<StopReason<u64>, ()> {
-        let mut interrupt = intr.no_async();
-        if act == ResumeAction::Step {
-            step::setup(self.iris, self.instance_id, 1, step::Unit::Instruction).map_err(|_| ())?
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> Result<Option<StopReason<u64>>, ()> {
+        if self.stepping {
+            step::instruction(self.iris, self.instance_id, 1).map_err(report)?;
+            return Ok(Some(StopReason::DoneStep));
         }
-        if act == ResumeAction::Step || act == ResumeAction::Continue {
-            simulation_time::run(self.iris, self.sim).map_err(|_| ())?;
-            while simulation_time::get(self.iris, self.sim)
-                .map_err(|_| ())?
-                .running
-            {
-                if interrupt.pending() {
-                    simulation_time::stop(self.iris, self.sim).map_err(|_| ())?;
-                    return Ok(StopReason::GdbInterrupt);
-                }
-            }
-            if act == ResumeAction::Step {
-                return Ok(StopReason::DoneStep);
-            } else {
-                return Ok(StopReason::HwBreak);
+        match run_until_halt(self.iris, self.sim, interrupted, self.timeout, None) {
+            Ok(true) => return Ok(None),
+            Ok(false) => {}
+            Err(IrisError::Timeout) => return Ok(Some(StopReason::Signal(Signal(SIGALRM)))),
+            Err(e) => {
+                report(e);
+                return Err(());
             }
         }
-        Err(())
+        match self.read_pc().map_err(report)? {
+            Some(pc) if self.breakpoints.contains_key(&pc) => Ok(Some(StopReason::HwBreak(()))),
+            _ => Ok(Some(StopReason::Signal(Signal(SIGTRAP)))),
+        }
+    }
+
+    fn interrupt(&mut self) -> Result<StopReason<u64>, ()> {
+        self.iris.stop(self.sim).map_err(report)?;
+        Ok(StopReason::Signal(Signal(SIGINT)))
     }
 }
 
 impl<'i> Breakpoints for IrisGdbStub<'i> {
-    fn hw_breakpoint(&mut self) -> Option<HwBreakpointOps<Self>> {
+    fn support_hw_breakpoint(&mut self) -> Option<HwBreakpointOps<'_, Self>> {
         Some(self)
     }
 
-    fn sw_breakpoint(&mut self) -> Option<SwBreakpointOps<Self>> {
+    fn support_sw_breakpoint(&mut self) -> Option<SwBreakpointOps<'_, Self>> {
         Some(self)
     }
 }
@@ -245,15 +331,10 @@ impl<'i> HwBreakpoint for IrisGdbStub<'i> {
         if self.breakpoints.contains_key(&addr) {
             return Ok(true);
         }
-        if let Ok(id) = breakpoint::code(
-            self.iris,
-            self.instance_id,
-            addr as u64,
-            None,
-            0,
-            true,
-            false,
-        ) {
+        let space = pc_space(self.iris, self.instance_id).map_err(report)?;
+        if let Ok(id) =
+            breakpoint::code(self.iris, self.instance_id, addr, None, space, true, false)
+        {
             self.breakpoints.insert(addr, id);
             Ok(true)
         } else {
@@ -281,25 +362,90 @@ impl<'i> HwBreakpoint for IrisGdbStub<'i> {
 impl<'i> MonitorCmd for IrisGdbStub<'i> {
     fn handle_monitor_cmd(&mut self, cmd: &[u8], mut out: ConsoleOutput<'_>) -> Result<(), ()> {
         match String::from_utf8_lossy(cmd).borrow() {
-            "reset" => {
-                simulation::reset(self.iris, self.sim, false).map_err(|_| ())?;
-                simulation::wait(self.iris, self.sim).map_err(|_| ())?;
+            c @ ("reset" | "reset cold" | "reset warm") => {
+                let kind = if c == "reset warm" {
+                    ResetKind::Warm
+                } else {
+                    ResetKind::Cold
+                };
+                self.iris.reset(self.sim, kind).map_err(report)?;
+                // gdb doesn't know that monitor commands change registers
+                outputln!(out, "Reset; run `flushregs` to refresh gdb's registers");
             }
-            c => {
-                outputln!(out, "Monitor command {} not supported", c);
+            c if c.split_whitespace().next() == Some("disassemble") => {
+                let pc = self.read_pc().map_err(report)?.unwrap_or(0);
+                // The model follows the execution state of the core itself
+                let res = disasm_monitor_cmd(self.iris, self.instance_id, c, pc, None, &mut out);
+                if let Err(e) = res {
+                    outputln!(out, "{}", e);
+                }
             }
+            c => match resource_monitor_cmd(self.iris, self.instance_id, c, &mut out) {
+                Ok(true) => {}
+                Ok(false) => outputln!(out, "Monitor command {} not supported", c),
+                Err(e) => outputln!(out, "{}", e),
+            },
         }
         Ok(())
     }
 }
 
+/// The AArch64 core registers. Without this gdb expects the SIMD and FP
+/// registers as well, which are not tracked.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>aarch64</architecture>
+  <feature name="org.gnu.gdb.aarch64.core">
+    <reg name="x0" bitsize="64"/>
+    <reg name="x1" bitsize="64"/>
+    <reg name="x2" bitsize="64"/>
+    <reg name="x3" bitsize="64"/>
+    <reg name="x4" bitsize="64"/>
+    <reg name="x5" bitsize="64"/>
+    <reg name="x6" bitsize="64"/>
+    <reg name="x7" bitsize="64"/>
+    <reg name="x8" bitsize="64"/>
+    <reg name="x9" bitsize="64"/>
+    <reg name="x10" bitsize="64"/>
+    <reg name="x11" bitsize="64"/>
+    <reg name="x12" bitsize="64"/>
+    <reg name="x13" bitsize="64"/>
+    <reg name="x14" bitsize="64"/>
+    <reg name="x15" bitsize="64"/>
+    <reg name="x16" bitsize="64"/>
+    <reg name="x17" bitsize="64"/>
+    <reg name="x18" bitsize="64"/>
+    <reg name="x19" bitsize="64"/>
+    <reg name="x20" bitsize="64"/>
+    <reg name="x21" bitsize="64"/>
+    <reg name="x22" bitsize="64"/>
+    <reg name="x23" bitsize="64"/>
+    <reg name="x24" bitsize="64"/>
+    <reg name="x25" bitsize="64"/>
+    <reg name="x26" bitsize="64"/>
+    <reg name="x27" bitsize="64"/>
+    <reg name="x28" bitsize="64"/>
+    <reg name="x29" bitsize="64"/>
+    <reg name="x30" bitsize="64"/>
+    <reg name="sp" bitsize="64" type="data_ptr"/>
+    <reg name="pc" bitsize="64" type="code_ptr"/>
+    <reg name="cpsr" bitsize="32"/>
+  </feature>
+</target>
+"#;
+
 pub enum Armv8aArch {}
 impl Arch for Armv8aArch {
     type Usize = u64;
     type Registers = GuestState;
     type RegId = Register;
     type BreakpointKind = usize;
-}
 
+    fn target_description_xml() -> Option<&'static str> {
+        Some(TARGET_XML)
+    }
+}
 
-pub use crate::gdb::t32::GdbOverPipe;
+#[cfg(feature = "std")]
+pub use crate::gdb::transport::{GdbOverPipe, GdbOverTcp};
@@ -0,0 +1,569 @@
+//! The Armv7-M registers as gdb sees them, and their serialization.
+//!
+//! Nothing here talks to the model or to gdb, so it builds without the
+//! `std` feature's transports and can be reused by other gdb stubs.
+
+use core::convert::TryInto;
+use core::fmt;
+use core::num::NonZeroUsize;
+
+use gdbstub::arch::{Arch, RegId, Registers};
+
+use crate::memory::Endianness;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GuestState {
+    /// The registers of `CORE_REGISTERS`, in the same order
+    pub regs: [u32; CORE_REGISTERS.len()],
+    /// S0-S31 followed by FPSCR, when the core has an FPU
+    pub fpu: Option<[u32; 33]>,
+    /// The byte order of the registers as gdb sends and receives them
+    pub endianness: Endianness,
+}
+
+impl GuestState {
+    /// The same registers in `endianness`. gdbstub deserializes the
+    /// registers gdb writes into a default, little endian, state, so on a
+    /// big endian target each word was read the wrong way round and has
+    /// its bytes swapped here.
+    pub fn in_order(&self, endianness: Endianness) -> GuestState {
+        let mut regs = self.clone();
+        if regs.endianness != endianness {
+            let fpu = regs.fpu.iter_mut().flatten();
+            for reg in regs.regs.iter_mut().chain(fpu) {
+                *reg = reg.swap_bytes();
+            }
+            regs.endianness = endianness;
+        }
+        regs
+    }
+
+    /// The value of a 32 bit register, or `None` for the D registers and
+    /// for FPSCR when the FPU registers aren't present.
+    pub fn get(&self, reg: Register) -> Option<u32> {
+        match (reg, &self.fpu) {
+            (Register::FPSCR, Some(fpu)) => Some(fpu[32]),
+            _ => reg.core_index().map(|regnum| self.regs[regnum]),
+        }
+    }
+
+    /// Set a 32 bit register, returning whether the state has it; see `get`.
+    pub fn set(&mut self, reg: Register, value: u32) -> bool {
+        let slot = match (reg, &mut self.fpu) {
+            (Register::FPSCR, Some(fpu)) => Some(&mut fpu[32]),
+            _ => reg.core_index().map(|regnum| &mut self.regs[regnum]),
+        };
+        match slot {
+            Some(slot) => {
+                *slot = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn sp(&self) -> u32 {
+        self.core(Register::SP)
+    }
+
+    pub fn lr(&self) -> u32 {
+        self.core(Register::LR)
+    }
+
+    pub fn xpsr(&self) -> u32 {
+        self.core(Register::XPSR)
+    }
+
+    /// XPSR split into its fields
+    pub fn xpsr_decoded(&self) -> Xpsr {
+        Xpsr(self.xpsr())
+    }
+
+    fn core(&self, reg: Register) -> u32 {
+        self.regs[reg.core_index().expect("a core register")]
+    }
+
+    /// The core registers, and FPSCR when both states have it, whose values
+    /// differ from those in `other`, as the register, its value in `other`
+    /// and its value here. The single precision registers are not compared.
+    pub fn changed_since(&self, other: &GuestState) -> Vec<(Register, u32, u32)> {
+        let mut changed: Vec<_> = CORE_REGISTERS
+            .iter()
+            .zip(other.regs.iter().zip(&self.regs))
+            .filter(|(_, (old, new))| old != new)
+            .map(|((reg, _), (&old, &new))| (*reg, old, new))
+            .collect();
+        if let (Some(old), Some(new)) = (&other.fpu, &self.fpu) {
+            if old[32] != new[32] {
+                changed.push((Register::FPSCR, old[32], new[32]));
+            }
+        }
+        changed
+    }
+}
+
+/// The Armv7-M XPSR, the union of the APSR, IPSR and EPSR views.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Xpsr(pub u32);
+
+impl Xpsr {
+    fn bit(self, n: u32) -> bool {
+        self.0 & (1 << n) != 0
+    }
+
+    /// Negative flag
+    pub fn n(self) -> bool {
+        self.bit(31)
+    }
+
+    /// Zero flag
+    pub fn z(self) -> bool {
+        self.bit(30)
+    }
+
+    /// Carry flag
+    pub fn c(self) -> bool {
+        self.bit(29)
+    }
+
+    /// Overflow flag
+    pub fn v(self) -> bool {
+        self.bit(28)
+    }
+
+    /// Saturation flag
+    pub fn q(self) -> bool {
+        self.bit(27)
+    }
+
+    /// The SIMD greater than or equal flags, with the DSP extension
+    pub fn ge(self) -> u8 {
+        ((self.0 >> 16) & 0xf) as u8
+    }
+
+    /// The exception being handled, 0 in Thread mode
+    pub fn exception(self) -> u16 {
+        (self.0 & 0x1ff) as u16
+    }
+
+    /// The Thumb bit. It's always set while the core runs, as clearing it
+    /// faults on the next instruction.
+    pub fn thumb(self) -> bool {
+        self.bit(24)
+    }
+
+    /// The IT/ICI bits, ordered as the IT state: bits 26-25 of XPSR are
+    /// IT[1:0] and bits 15-10 are IT[7:2]. Outside an IT block a non-zero
+    /// value is the ICI state of an interrupted load or store multiple.
+    pub fn it(self) -> u8 {
+        (((self.0 >> 25) & 0x3) | (((self.0 >> 10) & 0x3f) << 2)) as u8
+    }
+}
+
+/// The set flags in capitals and the clear ones in lower case, as in
+/// `NzCvq T GE=0x0 IT=0x00 exception 0`
+impl fmt::Display for Xpsr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = [
+            (self.n(), 'N'),
+            (self.z(), 'Z'),
+            (self.c(), 'C'),
+            (self.v(), 'V'),
+            (self.q(), 'Q'),
+        ];
+        for (set, name) in flags.iter() {
+            let name = if *set {
+                *name
+            } else {
+                name.to_ascii_lowercase()
+            };
+            write!(f, "{}", name)?;
+        }
+        write!(
+            f,
+            " {} GE={:#03x} IT={:#04x} exception {}",
+            if self.thumb() { 'T' } else { 't' },
+            self.ge(),
+            self.it(),
+            self.exception()
+        )
+    }
+}
+
+/// One register to a line, with the VFP registers as D0-D15, as gdb shows
+/// them
+impl fmt::Display for GuestState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for ((reg, _), value) in CORE_REGISTERS.iter().zip(&self.regs) {
+            writeln!(f, "{:<6}{:#010x}", reg.to_string(), value)?;
+        }
+        if let Some(fpu) = &self.fpu {
+            for (n, pair) in fpu[..32].chunks_exact(2).enumerate() {
+                let value = u64::from(pair[0]) | u64::from(pair[1]) << 32;
+                writeln!(f, "{:<6}{:#018x}", Register::D(n as u8).to_string(), value)?;
+            }
+            writeln!(f, "{:<6}{:#010x}", Register::FPSCR.to_string(), fpu[32])?;
+        }
+        Ok(())
+    }
+}
+
+impl Registers for GuestState {
+    type ProgramCounter = u32;
+    fn pc(&self) -> u32 {
+        self.core(Register::PC)
+    }
+    fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
+        // gdb sees the single precision registers in pairs, as D0-D15. On a
+        // little endian target the registers of a pair are sent in order.
+        let fpu = self.fpu.iter().flatten();
+        for reg in self.regs.iter().chain(fpu) {
+            for byte in self.endianness.u32_to_bytes(*reg).iter() {
+                write_byte(Some(*byte));
+            }
+        }
+    }
+    fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        let mut words = bytes.chunks_exact(4);
+        for reg in self.regs.iter_mut() {
+            let word = words.next().ok_or(())?;
+            *reg = self.endianness.u32_from_bytes(word.try_into().unwrap());
+        }
+        if words.len() == 0 {
+            return Ok(());
+        }
+        let mut fpu = [0; 33];
+        for reg in fpu.iter_mut() {
+            let word = words.next().ok_or(())?;
+            *reg = self.endianness.u32_from_bytes(word.try_into().unwrap());
+        }
+        self.fpu = Some(fpu);
+        Ok(())
+    }
+}
+
+/// The core registers in the order gdb numbers them, alongside the Iris
+/// resource holding each one. A register's position here is both its gdb
+/// register number and its index within `GuestState::regs`.
+pub(crate) const CORE_REGISTERS: [(Register, &str); 17] = [
+    (Register::R0, "R0"),
+    (Register::R1, "R1"),
+    (Register::R2, "R2"),
+    (Register::R3, "R3"),
+    (Register::R4, "R4"),
+    (Register::R5, "R5"),
+    (Register::R6, "R6"),
+    (Register::R7, "R7"),
+    (Register::R8, "R8"),
+    (Register::R9, "R9"),
+    (Register::R10, "R10"),
+    (Register::R11, "R11"),
+    (Register::R12, "R12"),
+    (Register::SP, "R13"),
+    (Register::LR, "R14"),
+    (Register::PC, "R15"),
+    (Register::XPSR, "XPSR"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Register {
+    R0,
+    R1,
+    R2,
+    R3,
+    R4,
+    R5,
+    R6,
+    R7,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    SP,
+    LR,
+    PC,
+    XPSR,
+    D(u8),
+    FPSCR,
+    /// One of `BANKED_REGISTERS`, by index
+    Banked(u8),
+}
+
+/// The stack pointers behind SP, as gdb names them and alongside the Iris
+/// resource holding each one: MSP and PSP, then their Non-secure and Secure
+/// banks on a core with the Security Extension. SP is whichever of them is
+/// active, so the others only show through these. A core has those of them
+/// it lists resources for.
+pub(crate) const BANKED_REGISTERS: [(&str, &str); 6] = [
+    ("msp", "MSP"),
+    ("psp", "PSP"),
+    ("msp_ns", "MSP_NS"),
+    ("psp_ns", "PSP_NS"),
+    ("msp_s", "MSP_S"),
+    ("psp_s", "PSP_S"),
+];
+
+/// gdb's register number for the first of `BANKED_REGISTERS`, after d0-d15
+/// and fpscr
+pub(crate) const BANKED_REGNUM: usize = CORE_REGISTERS.len() + 17;
+
+/// The name gdb gives the register
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::R0 => write!(f, "r0"),
+            Register::R1 => write!(f, "r1"),
+            Register::R2 => write!(f, "r2"),
+            Register::R3 => write!(f, "r3"),
+            Register::R4 => write!(f, "r4"),
+            Register::R5 => write!(f, "r5"),
+            Register::R6 => write!(f, "r6"),
+            Register::R7 => write!(f, "r7"),
+            Register::R8 => write!(f, "r8"),
+            Register::R9 => write!(f, "r9"),
+            Register::R10 => write!(f, "r10"),
+            Register::R11 => write!(f, "r11"),
+            Register::R12 => write!(f, "r12"),
+            Register::SP => write!(f, "sp"),
+            Register::LR => write!(f, "lr"),
+            Register::PC => write!(f, "pc"),
+            Register::XPSR => write!(f, "xpsr"),
+            Register::D(n) => write!(f, "d{}", n),
+            Register::FPSCR => write!(f, "fpscr"),
+            Register::Banked(n) => write!(f, "{}", BANKED_REGISTERS[*n as usize].0),
+        }
+    }
+}
+
+impl Register {
+    /// The core register gdb gives the name `name`, such as `r0` or `sp`
+    pub fn core_by_name(name: &str) -> Option<Register> {
+        CORE_REGISTERS
+            .iter()
+            .map(|&(reg, _)| reg)
+            .find(|reg| reg.to_string() == name)
+    }
+
+    /// The index of a core register within `GuestState::regs`
+    pub(crate) fn core_index(self) -> Option<usize> {
+        CORE_REGISTERS.iter().position(|(reg, _)| *reg == self)
+    }
+
+    /// The Iris resources holding the register, least significant word
+    /// first
+    pub(crate) fn iris_names(self) -> Vec<String> {
+        match self {
+            Register::D(n) => vec![format!("S{}", 2 * n), format!("S{}", 2 * n + 1)],
+            Register::FPSCR => vec!["FPSCR".to_string()],
+            Register::Banked(n) => vec![BANKED_REGISTERS[n as usize].1.to_string()],
+            reg => reg
+                .core_index()
+                .map(|regnum| CORE_REGISTERS[regnum].1.to_string())
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// Adjust a value read from Iris to what gdb expects in the register.
+    /// The PC is always reported halfword aligned: the execution state is
+    /// the T bit of XPSR, and gdb reads bit 0 of the PC as an address bit,
+    /// which would put it off by one when disassembling.
+    pub(crate) fn gdb_value(self, value: u32) -> u32 {
+        match self {
+            Register::PC => value & !1,
+            _ => value,
+        }
+    }
+
+    /// Adjust a value from gdb to what is written to Iris: the inverse of
+    /// `gdb_value`. A PC with bit 0 set, such as from `jump *func` with the
+    /// address of a Thumb function, is written halfword aligned, as the
+    /// core would fault on resuming otherwise.
+    pub(crate) fn iris_value(self, value: u32) -> u32 {
+        match self {
+            Register::PC => value & !1,
+            _ => value,
+        }
+    }
+
+    pub(crate) fn is_fpu(self) -> bool {
+        matches!(self, Register::D(_) | Register::FPSCR)
+    }
+
+    /// Whether this is one of the stack pointers SP may be
+    pub(crate) fn is_banked(self) -> bool {
+        matches!(self, Register::Banked(_))
+    }
+}
+
+/// gdb's register numbers, as laid out by the target description: r0-r12,
+/// sp, lr, pc and xpsr are 0-16, d0-d15 are 17-32, fpscr is 33 and the
+/// stack pointers of `BANKED_REGISTERS` are 34-39. The description leaves
+/// out the FPA registers of the A-profile layout, so there are no padding
+/// slots to fill. Each number below 40 is a register; reading one the core
+/// doesn't have, such as a VFP one from a core without the FPU, fails with
+/// an error reply. Numbers from 40 up are unknown, and gdbstub answers
+/// those with an empty reply, which gdb takes to mean the register isn't
+/// there.
+impl RegId for Register {
+    fn from_raw_id(id: usize) -> Option<(Self, Option<NonZeroUsize>)> {
+        let core = CORE_REGISTERS.len();
+        let (reg, size) = match id {
+            id if id < core => (CORE_REGISTERS[id].0, 4),
+            id if (core..core + 16).contains(&id) => (Register::D((id - core) as u8), 8),
+            id if id == core + 16 => (Register::FPSCR, 4),
+            id if (BANKED_REGNUM..BANKED_REGNUM + BANKED_REGISTERS.len()).contains(&id) => {
+                (Register::Banked((id - BANKED_REGNUM) as u8), 4)
+            }
+            _ => return None,
+        };
+        Some((reg, NonZeroUsize::new(size)))
+    }
+}
+
+pub enum Armv7mArch {}
+impl Arch for Armv7mArch {
+    type Usize = u32;
+    type Registers = GuestState;
+    type RegId = Register;
+    type BreakpointKind = usize;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_views_agree() {
+        for (regnum, &(reg, iris)) in CORE_REGISTERS.iter().enumerate() {
+            assert_eq!(reg.core_index(), Some(regnum));
+            assert_eq!(reg.iris_names(), [iris]);
+            assert_eq!(Register::core_by_name(&reg.to_string()), Some(reg));
+            assert_eq!(
+                Register::from_raw_id(regnum),
+                Some((reg, NonZeroUsize::new(4)))
+            );
+        }
+        let mut regs = GuestState::default();
+        regs.regs[15] = 0x100;
+        regs.regs[13] = 0x2000_0000;
+        assert_eq!(regs.pc(), 0x100);
+        assert_eq!(regs.sp(), 0x2000_0000);
+    }
+
+    fn serialized(regs: &GuestState) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        regs.gdb_serialize(|byte| bytes.push(byte.unwrap()));
+        bytes
+    }
+
+    #[test]
+    fn registers_serialize_in_either_byte_order() {
+        let mut regs = GuestState::default();
+        regs.set(Register::R0, 0x1122_3344);
+        let little = serialized(&regs);
+        assert_eq!(little[..4], [0x44, 0x33, 0x22, 0x11]);
+        regs.endianness = Endianness::Big;
+        let big = serialized(&regs);
+        assert_eq!(big[..4], [0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(big.len(), 17 * 4);
+        // gdbstub deserializes into a little endian state, which is then
+        // put in the target's order
+        let mut written = GuestState::default();
+        written.gdb_deserialize(&big).unwrap();
+        assert_eq!(written.in_order(Endianness::Big), regs);
+    }
+
+    #[test]
+    fn diff_lists_changed_registers() {
+        let mut before = GuestState::default();
+        before.set(Register::PC, 0x100);
+        let mut after = before.clone();
+        after.set(Register::R1, 5);
+        after.set(Register::PC, 0x102);
+        assert_eq!(
+            after.changed_since(&before),
+            [(Register::R1, 0, 5), (Register::PC, 0x100, 0x102)]
+        );
+        // FPSCR only when both states have the FPU registers
+        before.fpu = Some([0; 33]);
+        after.fpu = Some([0; 33]);
+        after.fpu.as_mut().unwrap()[32] = 0x0300_0000;
+        assert_eq!(
+            after.changed_since(&before)[2..],
+            [(Register::FPSCR, 0, 0x0300_0000)]
+        );
+        let dump = after.to_string();
+        let lines: Vec<_> = dump.lines().collect();
+        assert_eq!(lines[1], "r1    0x00000005");
+        assert_eq!(lines[15], "pc    0x00000102");
+        assert_eq!(lines[17], "d0    0x0000000000000000");
+        assert_eq!(lines.last(), Some(&"fpscr 0x03000000"));
+    }
+
+    #[test]
+    fn accessors_agree_with_get() {
+        let mut regs = GuestState::default();
+        for (n, &(reg, _)) in CORE_REGISTERS.iter().enumerate() {
+            assert!(regs.set(reg, 0x1000 + n as u32));
+        }
+        assert_eq!(regs.get(Register::PC), Some(regs.pc()));
+        assert_eq!(regs.get(Register::SP), Some(regs.sp()));
+        assert_eq!(regs.get(Register::LR), Some(regs.lr()));
+        assert_eq!(regs.get(Register::XPSR), Some(regs.xpsr()));
+        assert_eq!(regs.pc(), 0x100f);
+        // FPSCR is only there with the FPU registers, and D0 never is
+        assert!(!regs.set(Register::FPSCR, 1));
+        assert_eq!(regs.get(Register::FPSCR), None);
+        regs.fpu = Some([0; 33]);
+        assert!(regs.set(Register::FPSCR, 1));
+        assert_eq!(regs.get(Register::FPSCR), Some(1));
+        assert_eq!(regs.get(Register::D(0)), None);
+    }
+
+    #[test]
+    fn every_register_number_up_to_39_is_a_register() {
+        for id in 0..40 {
+            let (reg, size) = Register::from_raw_id(id).unwrap();
+            match id {
+                0..=16 => assert_eq!(reg.core_index(), Some(id)),
+                // What the A-profile layout has as FPA registers, 16-23, and
+                // their status, 24, are D registers here
+                17..=32 => assert_eq!(reg, Register::D(id as u8 - 17)),
+                33 => assert_eq!(reg, Register::FPSCR),
+                _ => assert_eq!(reg, Register::Banked((id - BANKED_REGNUM) as u8)),
+            }
+            let bytes = if matches!(reg, Register::D(_)) { 8 } else { 4 };
+            assert_eq!(size, NonZeroUsize::new(bytes), "register {}", id);
+        }
+        assert_eq!(
+            Register::from_raw_id(39).map(|(reg, _)| reg.to_string()),
+            Some("psp_s".to_string())
+        );
+        assert_eq!(Register::from_raw_id(40), None);
+    }
+
+    #[test]
+    fn xpsr_fields_decode_known_values() {
+        // N, C and Q set, GE=0b1010, Thumb, IT=0xad and exception 3
+        let it = 0xadu32;
+        let value = 0xa800_0000 | 0xa << 16 | 1 << 24 | (it & 3) << 25 | (it >> 2) << 10 | 3;
+        let xpsr = Xpsr(value);
+        assert!(xpsr.n() && !xpsr.z() && xpsr.c() && !xpsr.v() && xpsr.q());
+        assert_eq!(xpsr.ge(), 0xa);
+        assert!(xpsr.thumb());
+        assert_eq!(xpsr.it(), 0xad);
+        assert_eq!(xpsr.exception(), 3);
+        assert_eq!(xpsr.to_string(), "NzCvQ T GE=0xa IT=0xad exception 3");
+        // Each field from its own bits only
+        let all = Xpsr(u32::MAX);
+        assert_eq!((all.ge(), all.it(), all.exception()), (0xf, 0xff, 0x1ff));
+        assert!(!Xpsr(!(1 << 24)).thumb());
+        assert_eq!(Xpsr(!0x1ff).exception(), 0);
+        let mut regs = GuestState::default();
+        regs.set(Register::XPSR, value);
+        assert_eq!(regs.xpsr_decoded(), xpsr);
+        assert_eq!(Xpsr(0).to_string(), "nzcvq t GE=0x0 IT=0x00 exception 0");
+    }
+}
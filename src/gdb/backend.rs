@@ -0,0 +1,461 @@
+//! The Iris requests a gdb stub makes for registers, memory and
+//! breakpoints, behind a trait so that the stub can be driven by something
+//! other than a model, such as the `MockIris` of the unit tests.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::memory::{self, Endianness, MemRegion, ReadRes, Space};
+use crate::resource::{self, ResourceInfo};
+use crate::{breakpoint, instance_registry, FastModelIris, IrisError};
+
+/// The resource, memory and breakpoint requests of an
+/// `t32::IrisGdbStub`. `FastModelIris` makes them of the model through the
+/// functions of the `resource`, `memory` and `breakpoint` modules. What
+/// else the stub does, such as running and stepping the core, goes to the
+/// connection from `fast_model`.
+pub trait IrisBackend {
+    /// The resources of instance `id`, fetched once; see
+    /// `resource::cached_list`.
+    fn resource_list(&mut self, id: u32) -> Result<Arc<Vec<ResourceInfo>>, IrisError>;
+
+    /// Read `resources` in one request, as their words by resource id,
+    /// leaving out those that could not be read; see `resource::read_words`.
+    fn read_resources(
+        &mut self,
+        id: u32,
+        resources: &[&ResourceInfo],
+    ) -> Result<HashMap<u64, Vec<u64>>, IrisError>;
+
+    /// Write `values[n]` to the resource `ids[n]`; see `resource::write`.
+    fn write_resources(
+        &mut self,
+        id: u32,
+        ids: Vec<u64>,
+        values: Vec<u64>,
+    ) -> Result<(), IrisError>;
+
+    /// The memory spaces of instance `id`
+    fn memory_spaces(&mut self, id: u32) -> Result<Vec<Space>, IrisError>;
+
+    /// The regions of memory space `space`; see `memory::regions`.
+    fn memory_regions(&mut self, id: u32, space: u64) -> Result<Vec<MemRegion>, IrisError>;
+
+    /// Read `count` units of `width` bytes, packed little endian into
+    /// words, in one request; see `memory::read`.
+    fn read_memory(
+        &mut self,
+        id: u32,
+        space: u64,
+        address: u64,
+        width: u64,
+        count: u64,
+    ) -> Result<ReadRes, IrisError>;
+
+    /// Fill `data` from `address`, returning how many bytes were read
+    /// before the first fault; see `memory::read_block_ordered`.
+    fn read_block(
+        &mut self,
+        id: u32,
+        space: u64,
+        address: u64,
+        data: &mut [u8],
+        order: Endianness,
+    ) -> Result<usize, IrisError>;
+
+    /// Like `read_block`, using only accesses of `width` bytes; see
+    /// `memory::read_units`.
+    fn read_units(
+        &mut self,
+        id: u32,
+        space: u64,
+        address: u64,
+        data: &mut [u8],
+        width: u64,
+        order: Endianness,
+    ) -> Result<usize, IrisError>;
+
+    /// Write `data` as units of `width` bytes, returning how many units
+    /// were written; see `memory::write`.
+    fn write_memory(
+        &mut self,
+        id: u32,
+        space: u64,
+        address: u64,
+        width: u64,
+        data: &[u8],
+    ) -> Result<u64, IrisError>;
+
+    /// Set a breakpoint on the code at `address`, returning its id; see
+    /// `breakpoint::code`.
+    fn code_breakpoint(
+        &mut self,
+        id: u32,
+        address: u64,
+        size: Option<u64>,
+        space: u64,
+        sync: bool,
+        dont_stop: bool,
+    ) -> Result<u64, IrisError>;
+
+    /// Watch `size` bytes at `address` for the accesses of `rw_mode`,
+    /// returning the breakpoint's id; see `breakpoint::data`.
+    fn data_breakpoint(
+        &mut self,
+        id: u32,
+        address: u64,
+        size: u64,
+        space: u64,
+        rw_mode: &str,
+        sync: bool,
+    ) -> Result<u64, IrisError>;
+
+    /// Delete a breakpoint set by `code_breakpoint` or `data_breakpoint`
+    fn delete_breakpoint(&mut self, id: u32, breakpoint: u64) -> Result<(), IrisError>;
+
+    /// The instance id of the simulation engine, which runs and resets the
+    /// model
+    fn simulation_id(&mut self) -> Result<u32, IrisError>;
+
+    /// The connection to the model, for the requests beyond these. Fails
+    /// with `IrisError::Unsupported` when there is no model.
+    fn fast_model(&mut self) -> Result<&mut FastModelIris, IrisError>;
+}
+
+impl IrisBackend for FastModelIris {
+    fn resource_list(&mut self, id: u32) -> Result<Arc<Vec<ResourceInfo>>, IrisError> {
+        resource::cached_list(self, id)
+    }
+
+    fn read_resources(
+        &mut self,
+        id: u32,
+        resources: &[&ResourceInfo],
+    ) -> Result<HashMap<u64, Vec<u64>>, IrisError> {
+        resource::read_words(self, id, resources)
+    }
+
+    fn write_resources(
+        &mut self,
+        id: u32,
+        ids: Vec<u64>,
+        values: Vec<u64>,
+    ) -> Result<(), IrisError> {
+        resource::write(self, id, ids, values)
+    }
+
+    fn memory_spaces(&mut self, id: u32) -> Result<Vec<Space>, IrisError> {
+        memory::spaces(self, id)
+    }
+
+    fn memory_regions(&mut self, id: u32, space: u64) -> Result<Vec<MemRegion>, IrisError> {
+        memory::regions(self, id, space)
+    }
+
+    fn read_memory(
+        &mut self,
+        id: u32,
+        space: u64,
+        address: u64,
+        width: u64,
+        count: u64,
+    ) -> Result<ReadRes, IrisError> {
+        memory::read(self, id, space, address, width, count)
+    }
+
+    fn read_block(
+        &mut self,
+        id: u32,
+        space: u64,
+        address: u64,
+        data: &mut [u8],
+        order: Endianness,
+    ) -> Result<usize, IrisError> {
+        memory::read_block_ordered(self, id, space, address, data, order)
+    }
+
+    fn read_units(
+        &mut self,
+        id: u32,
+        space: u64,
+        address: u64,
+        data: &mut [u8],
+        width: u64,
+        order: Endianness,
+    ) -> Result<usize, IrisError> {
+        memory::read_units(self, id, space, address, data, width, order)
+    }
+
+    fn write_memory(
+        &mut self,
+        id: u32,
+        space: u64,
+        address: u64,
+        width: u64,
+        data: &[u8],
+    ) -> Result<u64, IrisError> {
+        memory::write(self, id, space, address, width, data)
+    }
+
+    fn code_breakpoint(
+        &mut self,
+        id: u32,
+        address: u64,
+        size: Option<u64>,
+        space: u64,
+        sync: bool,
+        dont_stop: bool,
+    ) -> Result<u64, IrisError> {
+        breakpoint::code(self, id, address, size, space, sync, dont_stop)
+    }
+
+    fn data_breakpoint(
+        &mut self,
+        id: u32,
+        address: u64,
+        size: u64,
+        space: u64,
+        rw_mode: &str,
+        sync: bool,
+    ) -> Result<u64, IrisError> {
+        breakpoint::data(self, id, address, size, space, rw_mode, sync)
+    }
+
+    fn delete_breakpoint(&mut self, id: u32, breakpoint: u64) -> Result<(), IrisError> {
+        breakpoint::delete(self, id, breakpoint)
+    }
+
+    fn simulation_id(&mut self) -> Result<u32, IrisError> {
+        let sim = instance_registry::get_instance_by_name(
+            self,
+            "framework.SimulationEngine".to_string(),
+        )?;
+        Ok(sim.id)
+    }
+
+    fn fast_model(&mut self) -> Result<&mut FastModelIris, IrisError> {
+        Ok(self)
+    }
+}
+
+/// A backend with no model behind it, serving the resources, memory and
+/// breakpoints it was set up with. Requests beyond those of `IrisBackend`
+/// fail, as there is no connection for `fast_model`.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MockIris {
+    /// The resource lists, by instance id
+    pub resources: HashMap<u32, Arc<Vec<ResourceInfo>>>,
+    /// The words of each resource, by instance and resource id. Resources
+    /// without a value can't be read.
+    pub values: HashMap<(u32, u64), Vec<u64>>,
+    /// The bytes of memory by address, the same in every space. Accesses
+    /// to any other address fault.
+    pub memory: HashMap<u64, u8>,
+    /// The breakpoints set, by id, as their instance id and address
+    pub breakpoints: HashMap<u64, (u32, u64)>,
+    next_breakpoint: u64,
+}
+
+#[cfg(test)]
+impl MockIris {
+    /// Give instance `id` the resources of the `resource_getList` result
+    /// `list`, each holding `value` of its resource id, for lists recorded
+    /// from a model.
+    pub(crate) fn with_resources(
+        mut self,
+        id: u32,
+        list: serde_json::Value,
+        value: impl Fn(u64) -> u64,
+    ) -> Self {
+        let list: Vec<ResourceInfo> = serde_json::from_value(list).unwrap();
+        for res in &list {
+            let words = res.bit_width.div_ceil(64).max(1);
+            let mut value = vec![value(res.id)];
+            value.resize(words as usize, 0);
+            self.values.insert((id, res.id), value);
+        }
+        self.resources.insert(id, Arc::new(list));
+        self
+    }
+
+    /// Map `data` into memory at `address`
+    pub(crate) fn with_memory(mut self, address: u64, data: &[u8]) -> Self {
+        for (addr, &byte) in (address..).zip(data) {
+            self.memory.insert(addr, byte);
+        }
+        self
+    }
+
+    /// The bytes at `address` up to the first that isn't mapped
+    fn read_mapped(&self, address: u64, data: &mut [u8]) -> usize {
+        let mut valid = 0;
+        for (dst, addr) in data.iter_mut().zip(address..) {
+            match self.memory.get(&addr) {
+                Some(&byte) => *dst = byte,
+                None => break,
+            }
+            valid += 1;
+        }
+        valid
+    }
+}
+
+#[cfg(test)]
+impl IrisBackend for MockIris {
+    fn resource_list(&mut self, id: u32) -> Result<Arc<Vec<ResourceInfo>>, IrisError> {
+        self.resources
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| IrisError::Protocol(format!("No instance {}", id)))
+    }
+
+    fn read_resources(
+        &mut self,
+        id: u32,
+        resources: &[&ResourceInfo],
+    ) -> Result<HashMap<u64, Vec<u64>>, IrisError> {
+        Ok(resources
+            .iter()
+            .filter_map(|res| Some((res.id, self.values.get(&(id, res.id))?.clone())))
+            .collect())
+    }
+
+    fn write_resources(
+        &mut self,
+        id: u32,
+        ids: Vec<u64>,
+        values: Vec<u64>,
+    ) -> Result<(), IrisError> {
+        for (rsc_id, value) in ids.into_iter().zip(values) {
+            match self.values.get_mut(&(id, rsc_id)) {
+                Some(words) => words[0] = value,
+                None => {
+                    return Err(IrisError::Protocol(format!(
+                        "Could not write resource {}",
+                        rsc_id
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn memory_spaces(&mut self, _id: u32) -> Result<Vec<Space>, IrisError> {
+        Ok(serde_json::from_value(serde_json::json!([{"spaceId": 0, "name": "Memory"}])).unwrap())
+    }
+
+    fn memory_regions(&mut self, _id: u32, _space: u64) -> Result<Vec<MemRegion>, IrisError> {
+        Ok(Vec::new())
+    }
+
+    fn read_memory(
+        &mut self,
+        _id: u32,
+        _space: u64,
+        address: u64,
+        width: u64,
+        count: u64,
+    ) -> Result<ReadRes, IrisError> {
+        let mut bytes = vec![0; (width * count) as usize];
+        let valid = self.read_mapped(address, &mut bytes);
+        let units = valid - valid % width as usize;
+        let data = bytes[..units]
+            .chunks(8)
+            .map(|chunk| {
+                let mut word = [0u8; 8];
+                word[..chunk.len()].copy_from_slice(chunk);
+                u64::from_le_bytes(word)
+            })
+            .collect();
+        let error = (units < bytes.len()).then(|| serde_json::json!("Memory fault"));
+        Ok(ReadRes { data, error })
+    }
+
+    fn read_block(
+        &mut self,
+        _id: u32,
+        _space: u64,
+        address: u64,
+        data: &mut [u8],
+        _order: Endianness,
+    ) -> Result<usize, IrisError> {
+        Ok(self.read_mapped(address, data))
+    }
+
+    fn read_units(
+        &mut self,
+        _id: u32,
+        _space: u64,
+        address: u64,
+        data: &mut [u8],
+        _width: u64,
+        _order: Endianness,
+    ) -> Result<usize, IrisError> {
+        Ok(self.read_mapped(address, data))
+    }
+
+    fn write_memory(
+        &mut self,
+        _id: u32,
+        _space: u64,
+        address: u64,
+        width: u64,
+        data: &[u8],
+    ) -> Result<u64, IrisError> {
+        let mapped = (address..address + data.len() as u64)
+            .take_while(|addr| self.memory.contains_key(addr))
+            .count();
+        let units = mapped as u64 / width;
+        if units == 0 {
+            return Err(IrisError::MemoryFault(address));
+        }
+        for (addr, &byte) in (address..).zip(&data[..(units * width) as usize]) {
+            self.memory.insert(addr, byte);
+        }
+        Ok(units)
+    }
+
+    fn code_breakpoint(
+        &mut self,
+        id: u32,
+        address: u64,
+        _size: Option<u64>,
+        _space: u64,
+        _sync: bool,
+        _dont_stop: bool,
+    ) -> Result<u64, IrisError> {
+        self.next_breakpoint += 1;
+        self.breakpoints.insert(self.next_breakpoint, (id, address));
+        Ok(self.next_breakpoint)
+    }
+
+    fn data_breakpoint(
+        &mut self,
+        id: u32,
+        address: u64,
+        size: u64,
+        space: u64,
+        _rw_mode: &str,
+        sync: bool,
+    ) -> Result<u64, IrisError> {
+        self.code_breakpoint(id, address, Some(size), space, sync, false)
+    }
+
+    fn delete_breakpoint(&mut self, _id: u32, breakpoint: u64) -> Result<(), IrisError> {
+        self.breakpoints
+            .remove(&breakpoint)
+            .map(|_| ())
+            .ok_or_else(|| IrisError::Protocol(format!("No breakpoint {}", breakpoint)))
+    }
+
+    fn simulation_id(&mut self) -> Result<u32, IrisError> {
+        Ok(0)
+    }
+
+    fn fast_model(&mut self) -> Result<&mut FastModelIris, IrisError> {
+        Err(IrisError::Unsupported(
+            "A mock has no model behind it".to_string(),
+        ))
+    }
+}
@@ -0,0 +1,160 @@
+//! The fault status registers of an Armv7-M core, decoded so that a stop in
+//! a fault handler can say what went wrong.
+
+use std::fmt;
+
+use crate::gdb::backend::IrisBackend;
+use crate::gdb::read_resource;
+use crate::IrisError;
+
+/// The causes recorded in CFSR, as the bit and its description. MemManage
+/// faults use bits 0-7, bus faults bits 8-15 and usage faults bits 16-31.
+const CFSR_CAUSES: [(u32, &str); 17] = [
+    (0, "instruction access violation"),
+    (1, "data access violation"),
+    (3, "access violation on exception return unstacking"),
+    (4, "access violation on exception entry stacking"),
+    (5, "access violation during lazy FP state preservation"),
+    (8, "instruction bus error"),
+    (9, "precise data bus error"),
+    (10, "imprecise data bus error"),
+    (11, "bus error on exception return unstacking"),
+    (12, "bus error on exception entry stacking"),
+    (13, "bus error during lazy FP state preservation"),
+    (16, "undefined instruction"),
+    (17, "invalid state, such as a cleared T bit"),
+    (18, "invalid EXC_RETURN value"),
+    (19, "no coprocessor"),
+    (24, "unaligned access"),
+    (25, "divide by zero"),
+];
+
+/// CFSR.MMARVALID: MMFAR holds the faulting address
+const MMARVALID: u32 = 1 << 7;
+/// CFSR.BFARVALID: BFAR holds the faulting address
+const BFARVALID: u32 = 1 << 15;
+/// HFSR.VECTTBL: a bus fault on a vector table read
+const VECTTBL: u32 = 1 << 1;
+/// HFSR.FORCED: a configurable fault escalated to a HardFault
+const FORCED: u32 = 1 << 30;
+/// CFSR.UNALIGNED: a UsageFault on an unaligned access
+const UNALIGNED: u32 = 1 << 24;
+/// CFSR.DIVBYZERO: a UsageFault on a division by zero
+const DIVBYZERO: u32 = 1 << 25;
+
+/// The fault status registers of the System Control Block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultInfo {
+    pub cfsr: u32,
+    pub hfsr: u32,
+    /// The address of a MemManage fault, when MMFAR holds one
+    pub mmfar: Option<u32>,
+    /// The address of a bus fault, when BFAR holds one
+    pub bfar: Option<u32>,
+}
+
+impl FaultInfo {
+    /// Read the fault status registers of a core. Returns `None` when the
+    /// model doesn't expose CFSR and HFSR as resources.
+    pub fn read<B: IrisBackend + ?Sized>(
+        iris: &mut B,
+        instance_id: u32,
+    ) -> Result<Option<Self>, IrisError> {
+        let (cfsr, hfsr) = match (
+            read_resource(iris, instance_id, "CFSR")?,
+            read_resource(iris, instance_id, "HFSR")?,
+        ) {
+            (Some(cfsr), Some(hfsr)) => (cfsr as u32, hfsr as u32),
+            _ => return Ok(None),
+        };
+        let mut address = |valid, name| -> Result<Option<u32>, IrisError> {
+            if cfsr & valid == 0 {
+                return Ok(None);
+            }
+            Ok(read_resource(iris, instance_id, name)?.map(|addr| addr as u32))
+        };
+        let mmfar = address(MMARVALID, "MMFAR")?;
+        let bfar = address(BFARVALID, "BFAR")?;
+        Ok(Some(Self {
+            cfsr,
+            hfsr,
+            mmfar,
+            bfar,
+        }))
+    }
+
+    /// Whether any fault is recorded
+    pub fn is_fault(&self) -> bool {
+        self.cfsr != 0 || self.hfsr & (VECTTBL | FORCED) != 0
+    }
+
+    /// The exception number of the fault a HardFault stands in for: the
+    /// configurable fault whose cause is recorded in CFSR if it escalated,
+    /// MemManage (4), BusFault (5) or UsageFault (6), or BusFault for a
+    /// vector table read.
+    pub fn escalated_from(&self) -> Option<u32> {
+        if self.hfsr & VECTTBL != 0 {
+            return Some(5);
+        }
+        if self.hfsr & FORCED == 0 {
+            return None;
+        }
+        match self.cfsr.trailing_zeros() {
+            0..=7 => Some(4),
+            8..=15 => Some(5),
+            16..=31 => Some(6),
+            _ => None,
+        }
+    }
+
+    /// Whether the UsageFault recorded is a division by zero
+    pub fn is_divide_by_zero(&self) -> bool {
+        self.cfsr & DIVBYZERO != 0
+    }
+
+    /// Whether the UsageFault recorded is an unaligned access
+    pub fn is_unaligned(&self) -> bool {
+        self.cfsr & UNALIGNED != 0
+    }
+}
+
+impl fmt::Display for FaultInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.is_fault() {
+            return write!(f, "No fault recorded");
+        }
+        let mut parts = Vec::new();
+        if self.hfsr & VECTTBL != 0 {
+            parts.push("HardFault: bus fault on a vector table read".to_string());
+        }
+        for (bit, cause) in CFSR_CAUSES.iter() {
+            if self.cfsr & (1 << bit) == 0 {
+                continue;
+            }
+            let (kind, address) = match bit {
+                0..=7 => ("MemManage", self.mmfar.map(|addr| ("MMFAR", addr))),
+                8..=15 => ("BusFault", self.bfar.map(|addr| ("BFAR", addr))),
+                _ => ("UsageFault", None),
+            };
+            let mut part = format!("{}: {}", kind, cause);
+            // Only the data access causes leave the address behind
+            if let (1 | 9, Some((name, addr))) = (bit, address) {
+                part.push_str(&format!(" at {}={:#010x}", name, addr));
+            }
+            parts.push(part);
+        }
+        if self.hfsr & FORCED != 0 {
+            if parts.is_empty() {
+                parts.push("a fault that CFSR no longer records".to_string());
+            }
+            write!(f, "HardFault escalated from ")?;
+        }
+        write!(
+            f,
+            "{} (CFSR={:#010x}, HFSR={:#010x})",
+            parts.join("; "),
+            self.cfsr,
+            self.hfsr
+        )
+    }
+}
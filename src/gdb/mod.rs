@@ -1,2 +1,603 @@
-pub mod t32;
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use gdbstub::conn::{Connection, ConnectionExt};
+use gdbstub::outputln;
+use gdbstub::stub::run_blocking::{BlockingEventLoop, Event, WaitForStopReasonError};
+use gdbstub::stub::IntoStopReason;
+use gdbstub::target::ext::monitor_cmd::ConsoleOutput;
+use gdbstub::target::Target;
+
+use crate::gdb::backend::IrisBackend;
+use crate::gdb::fault::FaultInfo;
+use crate::instance_registry::ArchKind;
+use crate::iris_client::Backoff;
+use crate::memory::Endianness;
+use crate::{disasm, resource, simulation_time, FastModelIris, IrisError};
+
 pub mod a64;
+pub mod armv7m;
+pub mod backend;
+pub mod fault;
+pub mod multi;
+pub mod semihosting;
+pub mod t32;
+#[cfg(feature = "std")]
+pub mod transport;
+
+/// A gdb stub for whichever kind of core an instance is; see
+/// `FastModelIris::detect_arch`.
+// Only one is ever made per session, so its size doesn't matter
+#[allow(clippy::large_enum_variant)]
+pub enum AnyStub<'i> {
+    Armv7m(t32::IrisGdbStub<'i>),
+    AArch64(a64::IrisGdbStub<'i>),
+}
+
+impl<'i> AnyStub<'i> {
+    /// Create the stub matching the core, with its default options. Fails
+    /// for cores neither stub supports.
+    pub fn for_instance(iris: &'i mut FastModelIris, instance_id: u32) -> Result<Self, IrisError> {
+        Ok(match iris.detect_arch(instance_id)? {
+            ArchKind::Armv7m => Self::Armv7m(t32::IrisGdbStub::builder(iris, instance_id).build()?),
+            ArchKind::AArch64 => Self::AArch64(a64::IrisGdbStub::from_instance(iris, instance_id)?),
+        })
+    }
+}
+
+/// A stub whose target runs once gdb resumes it, until it halts. gdbstub's
+/// resume calls only say what gdb asked for; the run itself happens in
+/// `wait_for_stop`, called by `EventLoop`.
+pub trait RunTarget: Target + Sized {
+    type StopReason: IntoStopReason<Self>;
+
+    /// Carry out the resume gdb last asked for and wait for the target to
+    /// halt, returning why it did. Returns `None` once `interrupted` says
+    /// gdb sent something, with the target halted but the resume kept, so
+    /// that calling this again carries on with it.
+    fn wait_for_stop(
+        &mut self,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> Result<Option<Self::StopReason>, Self::Error>;
+
+    /// Halt the target for gdb's interrupt and give the stop to report.
+    fn interrupt(&mut self) -> Result<Self::StopReason, Self::Error>;
+}
+
+/// Drives a `RunTarget` for `GdbStub::run_blocking`, over connection `C`.
+pub struct EventLoop<T, C>(PhantomData<fn() -> (T, C)>);
+
+impl<T, C> BlockingEventLoop for EventLoop<T, C>
+where
+    T: RunTarget,
+    C: ConnectionExt,
+{
+    type Target = T;
+    type Connection = C;
+    type StopReason = T::StopReason;
+
+    /// A connection error halts the target like gdb's data does, and is
+    /// then returned by the read of that data
+    fn wait_for_stop_reason(
+        target: &mut T,
+        conn: &mut C,
+    ) -> Result<Event<T::StopReason>, WaitForStopReasonError<T::Error, <C as Connection>::Error>>
+    {
+        let mut interrupted = || !matches!(conn.peek(), Ok(None));
+        match target.wait_for_stop(&mut interrupted) {
+            Ok(Some(reason)) => Ok(Event::TargetStopped(reason)),
+            Ok(None) => match conn.read() {
+                Ok(byte) => Ok(Event::IncomingData(byte)),
+                Err(e) => Err(WaitForStopReasonError::Connection(e)),
+            },
+            Err(e) => Err(WaitForStopReasonError::Target(e)),
+        }
+    }
+
+    fn on_interrupt(target: &mut T) -> Result<Option<T::StopReason>, T::Error> {
+        target.interrupt().map(Some)
+    }
+}
+
+/// Copy the part of `data` starting at `offset` into `buf`, taking at most
+/// `length` bytes, for the qXfer reads gdb makes in pieces. Returns the
+/// number of bytes copied, 0 past the end.
+pub(crate) fn copy_range(data: &[u8], offset: u64, length: usize, buf: &mut [u8]) -> usize {
+    let start = usize::try_from(offset).map_or(data.len(), |offset| offset.min(data.len()));
+    let len = (data.len() - start).min(length).min(buf.len());
+    buf[..len].copy_from_slice(&data[start..start + len]);
+    len
+}
+
+/// Width of the units used when writing aligned memory from gdb.
+const WRITE_WIDTH: u64 = 4;
+
+/// Signal reported to gdb when the user interrupts a running target.
+pub const SIGINT: u8 = 2;
+/// Signal reported to gdb when the target halts for any other reason.
+pub const SIGTRAP: u8 = 5;
+/// Signal reported to gdb when the target is stopped because it ran for
+/// longer than the resume timeout.
+pub const SIGALRM: u8 = 14;
+/// Signal reported to gdb when the target is stopped because the watchdog
+/// saw it make no progress.
+pub const SIGXCPU: u8 = 24;
+/// Signal reported to gdb when the target halts in a usage fault handler.
+pub const SIGILL: u8 = 4;
+/// Signal reported to gdb when the target halts in a bus fault handler, or
+/// a usage fault handler for an unaligned access.
+pub const SIGBUS: u8 = 7;
+/// Signal reported to gdb when the target halts in a usage fault handler
+/// for a division by zero.
+pub const SIGFPE: u8 = 8;
+/// Signal reported to gdb when the target halts in a hard fault or memory
+/// management fault handler.
+pub const SIGSEGV: u8 = 11;
+
+/// The signals the Armv7-M stub reports to gdb for the stops that aren't a
+/// breakpoint, a watchpoint or the end of a step, with the defaults below.
+/// gdb reacts to each signal as set with `handle`, and IDEs tend to follow
+/// it, so this can make a fault stop like a breakpoint would, or tell
+/// faults apart. Set with `IrisGdbStubBuilder::signal_map`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignalMap {
+    /// gdb interrupted the target; `SIGINT`
+    pub interrupt: u8,
+    /// The target ran for longer than the resume timeout; `SIGALRM`
+    pub timeout: u8,
+    /// The watchdog saw the target make no progress; `SIGXCPU`
+    pub stalled: u8,
+    /// Halted in the HardFault handler; `SIGSEGV`
+    pub hard_fault: u8,
+    /// Halted in the MemManage handler; `SIGSEGV`
+    pub mem_manage: u8,
+    /// Halted in the BusFault handler; `SIGBUS`
+    pub bus_fault: u8,
+    /// Halted in the UsageFault handler; `SIGILL`
+    pub usage_fault: u8,
+    /// Halted in the UsageFault handler for a division by zero; `SIGFPE`
+    pub divide_by_zero: u8,
+    /// Halted in the UsageFault handler for an unaligned access; `SIGBUS`
+    pub unaligned: u8,
+    /// Halted for any other reason; `SIGTRAP`
+    pub other: u8,
+}
+
+impl SignalMap {
+    /// Report every stop as `SIGTRAP` but for gdb's own interrupts, which
+    /// gdb only recognizes as `SIGINT`.
+    pub fn sigtrap_only() -> Self {
+        Self {
+            interrupt: SIGINT,
+            timeout: SIGTRAP,
+            stalled: SIGTRAP,
+            hard_fault: SIGTRAP,
+            mem_manage: SIGTRAP,
+            bus_fault: SIGTRAP,
+            usage_fault: SIGTRAP,
+            divide_by_zero: SIGTRAP,
+            unaligned: SIGTRAP,
+            other: SIGTRAP,
+        }
+    }
+
+    /// The signal for a halt in the handler of exception `exception`
+    pub(crate) fn for_exception(&self, exception: u32) -> u8 {
+        match exception {
+            3 => self.hard_fault,
+            4 => self.mem_manage,
+            5 => self.bus_fault,
+            6 => self.usage_fault,
+            _ => self.other,
+        }
+    }
+
+    /// The signal for a halt in the handler of exception `exception`, going
+    /// by the cause the fault status registers record, if the model has
+    /// them. gdb shows nothing but the signal at a stop, so this is how a
+    /// fault's cause reaches it: a HardFault escalated from another fault
+    /// is reported as that fault, and a division by zero or an unaligned
+    /// access as such rather than as a UsageFault.
+    pub(crate) fn for_fault(&self, exception: u32, fault: Option<&FaultInfo>) -> u8 {
+        let fault = match fault {
+            Some(fault) => fault,
+            None => return self.for_exception(exception),
+        };
+        let exception = match exception {
+            3 => fault.escalated_from().unwrap_or(3),
+            exception => exception,
+        };
+        match exception {
+            6 if fault.is_divide_by_zero() => self.divide_by_zero,
+            6 if fault.is_unaligned() => self.unaligned,
+            exception => self.for_exception(exception),
+        }
+    }
+}
+
+impl Default for SignalMap {
+    fn default() -> Self {
+        Self {
+            interrupt: SIGINT,
+            timeout: SIGALRM,
+            stalled: SIGXCPU,
+            hard_fault: SIGSEGV,
+            mem_manage: SIGSEGV,
+            bus_fault: SIGBUS,
+            usage_fault: SIGILL,
+            divide_by_zero: SIGFPE,
+            unaligned: SIGBUS,
+            other: SIGTRAP,
+        }
+    }
+}
+
+/// Log why an Iris request failed. gdb only learns that the request failed,
+/// so the cause would otherwise be lost.
+pub(crate) fn report(err: IrisError) {
+    eprintln!("Error: {}", err);
+}
+
+/// A breakpoint hit, as reported by the `IRIS_BREAKPOINT_HIT` event
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct BreakpointHit {
+    /// The Iris id of the breakpoint
+    pub id: u64,
+    /// The address accessed, for data breakpoints
+    pub access_addr: Option<u64>,
+}
+
+/// The last breakpoint hit on an instance, updated whenever Iris delivers an
+/// event while waiting on a response. It's behind a lock as the callback
+/// setting it must be `Send`, like the connection it's registered with.
+#[derive(Clone, Default)]
+pub(crate) struct LastHit(Arc<Mutex<Option<BreakpointHit>>>);
+
+impl LastHit {
+    pub(crate) fn get(&self) -> Option<BreakpointHit> {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn set(&self, hit: Option<BreakpointHit>) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = hit;
+    }
+}
+
+/// Ask Iris to report breakpoint hits on the instance to this client.
+pub(crate) fn track_breakpoint_hits(
+    iris: &mut FastModelIris,
+    instance_id: u32,
+) -> Result<LastHit, IrisError> {
+    const EVENT: &str = "IRIS_BREAKPOINT_HIT";
+    // Each instance needs its own callback, so the instance is in the name
+    let func = format!("ec_{}_{}", EVENT, instance_id);
+    let last_hit = LastHit::default();
+    let hits = last_hit.clone();
+    iris.subscribe_callback(
+        instance_id,
+        EVENT,
+        func,
+        Box::new(move |params| {
+            let fields = &params["fields"];
+            if let Some(id) = fields["BPT_ID"].as_u64() {
+                hits.set(Some(BreakpointHit {
+                    id,
+                    access_addr: fields["ACCESS_ADDR"].as_u64(),
+                }));
+            }
+            Ok(())
+        }),
+    )?;
+    Ok(last_hit)
+}
+
+/// Stops a run on which a core executes no instructions for `timeout`,
+/// checking its instruction count every `interval`. This tells a model
+/// that has locked up, or a core waiting for an interrupt that never comes,
+/// apart from one that is merely slow.
+#[derive(Debug, Clone, Copy)]
+pub struct Watchdog {
+    pub timeout: Duration,
+    pub interval: Duration,
+}
+
+/// Run the simulation until it halts, stopping it early if gdb sends an
+/// interrupt or `timeout` expires. Returns `true` when the halt was caused
+/// by the interrupt, and `IrisError::Timeout` when it was the timeout.
+/// With a watchdog on the core of the given instance id, a run on which the
+/// core makes no progress is stopped with `IrisError::Stalled`.
+pub(crate) fn run_until_halt(
+    iris: &mut FastModelIris,
+    sim: u32,
+    interrupted: &mut dyn FnMut() -> bool,
+    timeout: Option<Duration>,
+    watchdog: Option<(u32, Watchdog)>,
+) -> Result<bool, IrisError> {
+    let (core, watchdog) = match watchdog {
+        Some(watchdog) => watchdog,
+        None => {
+            iris.run(sim)?;
+            let stop = iris.wait_for_stop_or(sim, timeout, &mut *interrupted)?;
+            return Ok(stop.interrupted);
+        }
+    };
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut count = iris.instruction_count(core)?;
+    let mut progressed = Instant::now();
+    let mut next_check = progressed + watchdog.interval;
+    let mut backoff = Backoff::new();
+    iris.run(sim)?;
+    loop {
+        if !simulation_time::get(iris, sim)?.running {
+            return Ok(false);
+        }
+        if interrupted() {
+            iris.stop(sim)?;
+            return Ok(true);
+        }
+        let now = Instant::now();
+        if deadline.is_some_and(|deadline| now >= deadline) {
+            iris.stop(sim)?;
+            return Err(IrisError::Timeout);
+        }
+        if now < next_check {
+            backoff.wait();
+            continue;
+        }
+        // The step counter is read while the core runs
+        let current = iris.instruction_count(core)?;
+        if current != count {
+            count = current;
+            progressed = now;
+        } else if now - progressed >= watchdog.timeout {
+            iris.stop(sim)?;
+            return Err(IrisError::Stalled(now - progressed));
+        }
+        next_check = now + watchdog.interval;
+    }
+}
+
+/// Check that the instance has a memory space with this id.
+pub(crate) fn check_space<B: IrisBackend + ?Sized>(
+    iris: &mut B,
+    instance_id: u32,
+    space_id: u64,
+) -> Result<(), IrisError> {
+    if iris
+        .memory_spaces(instance_id)?
+        .iter()
+        .any(|space| space.id == space_id)
+    {
+        Ok(())
+    } else {
+        Err(IrisError::InvalidArgument(format!(
+            "Instance {} has no memory space {}",
+            instance_id, space_id
+        )))
+    }
+}
+
+/// Read the resource called `name`, if the instance has one.
+pub(crate) fn read_resource<B: IrisBackend + ?Sized>(
+    iris: &mut B,
+    instance_id: u32,
+    name: &str,
+) -> Result<Option<u64>, IrisError> {
+    let resources = iris.resource_list(instance_id)?;
+    let info = match resources.iter().find(|res| res.name == name) {
+        Some(info) => info,
+        None => return Ok(None),
+    };
+    match iris.read_resources(instance_id, &[info])?.get(&info.id) {
+        Some(words) => Ok(Some(words[0])),
+        None => Err(IrisError::Protocol(format!("Could not read {}", name))),
+    }
+}
+
+/// The memory space that the PC of the instance fetches from. Cores that
+/// don't report one use space 0.
+pub(crate) fn pc_space<B: IrisBackend + ?Sized>(
+    iris: &mut B,
+    instance_id: u32,
+) -> Result<u64, IrisError> {
+    Ok(read_resource(iris, instance_id, "PC_MEMSPACE")?.unwrap_or(0))
+}
+
+/// Read memory on behalf of gdb, filling as much of `data` as Iris returned.
+/// Returns the number of bytes that are valid; fewer than `data.len()` means
+/// the read faulted there.
+pub(crate) fn read_memory<B: IrisBackend + ?Sized>(
+    iris: &mut B,
+    instance_id: u32,
+    space: u64,
+    addr: u64,
+    data: &mut [u8],
+    order: Endianness,
+) -> Result<usize, IrisError> {
+    iris.read_block(instance_id, space, addr, data, order)
+}
+
+/// Write all of `data` in units of `width` bytes, treating a partial write
+/// as a fault at the first unit that was not written.
+fn write_units<B: IrisBackend + ?Sized>(
+    iris: &mut B,
+    instance_id: u32,
+    space: u64,
+    addr: u64,
+    width: u64,
+    data: &[u8],
+) -> Result<(), IrisError> {
+    let written = iris.write_memory(instance_id, space, addr, width, data)?;
+    if written * width < data.len() as u64 {
+        return Err(IrisError::MemoryFault(addr + written * width));
+    }
+    Ok(())
+}
+
+/// Write memory on behalf of gdb. Aligned writes use word sized units, and a
+/// trailing partial word is merged with the current memory contents so the
+/// bytes after the end of `data` are left untouched. Big endian memory is
+/// written a byte at a time, as the units would be packed little endian.
+pub(crate) fn write_memory<B: IrisBackend + ?Sized>(
+    iris: &mut B,
+    instance_id: u32,
+    space: u64,
+    addr: u64,
+    data: &[u8],
+    order: Endianness,
+) -> Result<(), IrisError> {
+    if order == Endianness::Big || !addr.is_multiple_of(WRITE_WIDTH) {
+        return write_units(iris, instance_id, space, addr, 1, data);
+    }
+    let whole = data.len() - data.len() % WRITE_WIDTH as usize;
+    let (body, tail) = data.split_at(whole);
+    if !body.is_empty() {
+        write_units(iris, instance_id, space, addr, WRITE_WIDTH, body)?;
+    }
+    if !tail.is_empty() {
+        let tail_addr = addr + whole as u64;
+        let current = iris.read_memory(instance_id, space, tail_addr, WRITE_WIDTH, 1)?;
+        let mut unit = current
+            .data
+            .first()
+            .map(|u| u.to_le_bytes())
+            .ok_or(IrisError::MemoryFault(tail_addr))?;
+        unit[..tail.len()].copy_from_slice(tail);
+        write_units(
+            iris,
+            instance_id,
+            space,
+            tail_addr,
+            WRITE_WIDTH,
+            &unit[..WRITE_WIDTH as usize],
+        )?;
+    }
+    Ok(())
+}
+
+/// Parse a number given to a monitor command, either in hex with a `0x`
+/// prefix or in decimal.
+fn parse_value(value: &str) -> Option<u64> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Handle `disassemble [address] [count]`, which disassembles `count`
+/// instructions, 8 by default, from `address` or else from `pc`. Returns
+/// whether `cmd` was this command.
+pub(crate) fn disasm_monitor_cmd(
+    iris: &mut FastModelIris,
+    instance_id: u32,
+    cmd: &str,
+    pc: u64,
+    mode: Option<&str>,
+    out: &mut ConsoleOutput<'_>,
+) -> Result<bool, IrisError> {
+    let mut words = cmd.split_whitespace();
+    if words.next() != Some("disassemble") {
+        return Ok(false);
+    }
+    let address = match words.next().map(parse_value) {
+        Some(Some(address)) => address,
+        Some(None) => {
+            outputln!(out, "Could not parse the address");
+            return Ok(true);
+        }
+        None => pc,
+    };
+    let count = match words.next().map(parse_value) {
+        Some(Some(count)) => count,
+        Some(None) => {
+            outputln!(out, "Could not parse the count");
+            return Ok(true);
+        }
+        None => 8,
+    };
+    for line in disasm::at(iris, instance_id, address, count, mode)? {
+        let marker = if line.address == pc { "=>" } else { "  " };
+        outputln!(
+            out,
+            "{} {:#010x}  {:<12} {}",
+            marker,
+            line.address,
+            line.opcode,
+            line.text
+        );
+    }
+    Ok(true)
+}
+
+/// Handle the monitor commands that give access to the Iris resources of an
+/// instance: `list`, `read <name>` and `write <name> <value>`, along with
+/// `icount` for the number of instructions executed. Returns whether `cmd`
+/// was one of them.
+pub(crate) fn resource_monitor_cmd(
+    iris: &mut FastModelIris,
+    instance_id: u32,
+    cmd: &str,
+    out: &mut ConsoleOutput<'_>,
+) -> Result<bool, IrisError> {
+    let mut words = cmd.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("icount"), None, None) => {
+            let count = iris.instruction_count(instance_id)?;
+            outputln!(out, "{} instructions", count);
+        }
+        (Some("list"), None, None) => {
+            for res in resource::cached_list(iris, instance_id)?.iter() {
+                outputln!(out, "{:>6} {}", res.id, res.name);
+            }
+        }
+        (Some("read"), Some(name), None) => match read_resource(iris, instance_id, name)? {
+            Some(value) => outputln!(out, "{} = {:#x}", name, value),
+            None => outputln!(out, "No resource named {}", name),
+        },
+        (Some("write"), Some(name), Some(value)) => {
+            let value = match parse_value(value) {
+                Some(value) => value,
+                None => {
+                    outputln!(out, "Could not parse {} as a number", value);
+                    return Ok(true);
+                }
+            };
+            match resource::write_by_name(iris, instance_id, name, value) {
+                Err(IrisError::ResourceNotFound(_)) => outputln!(out, "No resource named {}", name),
+                res => res?,
+            }
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fault(cfsr: u32, hfsr: u32) -> FaultInfo {
+        FaultInfo {
+            cfsr,
+            hfsr,
+            mmfar: None,
+            bfar: None,
+        }
+    }
+
+    #[test]
+    fn fault_signals_follow_the_cause() {
+        let signals = SignalMap::default();
+        // A precise data bus error escalated to a HardFault
+        assert_eq!(signals.for_fault(3, Some(&fault(1 << 9, 1 << 30))), SIGBUS);
+        // A HardFault that wasn't escalated keeps its own signal
+        assert_eq!(signals.for_fault(3, Some(&fault(1 << 9, 0))), SIGSEGV);
+        assert_eq!(signals.for_fault(6, Some(&fault(1 << 25, 0))), SIGFPE);
+        assert_eq!(signals.for_fault(6, Some(&fault(1 << 24, 0))), SIGBUS);
+        assert_eq!(signals.for_fault(6, Some(&fault(1 << 16, 0))), SIGILL);
+        // Without the fault status registers only the handler is known
+        assert_eq!(signals.for_fault(5, None), SIGBUS);
+    }
+}
@@ -0,0 +1,655 @@
+//! A gdb stub for several Armv7-M cores at once, each shown to gdb as a
+//! thread.
+//!
+//! The cores share one simulation, so they can't be resumed on their own:
+//! while gdb steps one thread or continues some of them, the other cores run
+//! as well. Breakpoints are set on every core.
+//!
+//! gdb can still ask for different actions on different threads, such as
+//! stepping one core while continuing another. The cores being stepped are
+//! then set up to stop after one instruction, and the simulation runs until
+//! the first of them does or another core hits a breakpoint. A core stepped
+//! without a thread continuing runs with no timeout, as its step should end
+//! at once; one in `WFI` can still be interrupted from gdb.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use gdbstub::arch::Arch;
+use gdbstub::common::{Signal, Tid};
+use gdbstub::stub::MultiThreadStopReason as ThreadStopReason;
+use gdbstub::target::ext::base::multithread::{
+    MultiThreadBase, MultiThreadResume, MultiThreadResumeOps, MultiThreadSchedulerLocking,
+    MultiThreadSchedulerLockingOps, MultiThreadSingleStep, MultiThreadSingleStepOps,
+};
+use gdbstub::target::ext::base::BaseOps;
+use gdbstub::target::ext::breakpoints::{
+    Breakpoints, BreakpointsOps, HwBreakpoint, HwBreakpointOps, SwBreakpoint, SwBreakpointOps,
+};
+use gdbstub::target::ext::target_description_xml_override::{
+    TargetDescriptionXmlOverride, TargetDescriptionXmlOverrideOps,
+};
+use gdbstub::target::{Target, TargetError, TargetResult};
+
+use crate::gdb::t32::{
+    has_vfp, read_core_registers, target_xml, write_core_registers, Armv7mArch, GuestState,
+};
+use crate::gdb::{
+    copy_range, pc_space, read_memory, read_resource, report, run_until_halt,
+    track_breakpoint_hits, write_memory, LastHit, RunTarget, SIGALRM, SIGINT, SIGTRAP,
+};
+use crate::memory::Endianness;
+use crate::step::{self, Unit};
+use crate::{breakpoint, instance_registry, FastModelIris, IrisError};
+
+/// What gdb asked a thread to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResumeAction {
+    Continue,
+    Step,
+}
+
+/// A core, as seen through one gdb thread
+struct Core {
+    instance_id: u32,
+    /// The memory space used by `read_addrs` and `write_addrs`
+    space_id: u64,
+    last_hit: Option<LastHit>,
+}
+
+pub struct IrisGdbStubMulti<'i> {
+    pub iris: &'i mut FastModelIris,
+    sim: u32,
+    /// The cores, with thread id `n` being `cores[n - 1]`
+    cores: Vec<Core>,
+    /// The Iris breakpoints set for each of gdb's, keyed by address, as
+    /// the instance id of the core and the breakpoint id on it
+    breakpoints: HashMap<u32, Vec<(u32, u64)>>,
+    /// The actions gdb asked for on particular threads
+    resume_actions: HashMap<Tid, ResumeAction>,
+    /// Whether gdb asked for the threads it gave no action to to stay
+    /// stopped, rather than continue. They run anyway, but without a
+    /// timeout unless another thread continues.
+    scheduler_locked: bool,
+    /// How long a continue may run before the target is stopped
+    timeout: Option<Duration>,
+    /// Whether every core has the VFP registers, and so gdb is shown them
+    has_fpu: bool,
+    target_xml: String,
+}
+
+impl<'i> IrisGdbStubMulti<'i> {
+    /// Create a stub with one thread for each instance, in order.
+    pub fn from_instances(
+        iris: &'i mut FastModelIris,
+        instance_ids: &[u32],
+    ) -> Result<Self, IrisError> {
+        if instance_ids.is_empty() {
+            return Err(IrisError::InvalidArgument(
+                "At least one core is needed".to_string(),
+            ));
+        }
+        let sim = instance_registry::get_instance_by_name(
+            iris,
+            "framework.SimulationEngine".to_string(),
+        )?;
+        let mut cores = Vec::with_capacity(instance_ids.len());
+        let mut has_fpu = true;
+        for &instance_id in instance_ids {
+            has_fpu &= has_vfp(iris, instance_id)?;
+            cores.push(Core {
+                instance_id,
+                space_id: pc_space(iris, instance_id)?,
+                last_hit: track_breakpoint_hits(iris, instance_id).ok(),
+            });
+        }
+        Ok(Self {
+            iris,
+            sim: sim.id,
+            cores,
+            breakpoints: HashMap::new(),
+            resume_actions: HashMap::new(),
+            scheduler_locked: false,
+            timeout: None,
+            has_fpu,
+            // Without single register access gdb can't read the stack
+            // pointers beyond the `g` packet, so they're left out
+            target_xml: target_xml(has_fpu, &[]),
+        })
+    }
+
+    /// Stop the target and report `SIGALRM` to gdb when it runs for
+    /// longer than `timeout` after a continue.
+    pub fn with_resume_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    fn core(&self, tid: Tid) -> TargetResult<&Core, Self> {
+        self.cores.get(tid.get() - 1).ok_or(TargetError::NonFatal)
+    }
+
+    fn tid(index: usize) -> Tid {
+        Tid::new(index + 1).unwrap()
+    }
+
+    /// The threads gdb asked to step, in order
+    fn stepping_threads(&self) -> Vec<Tid> {
+        (0..self.cores.len())
+            .map(Self::tid)
+            .filter(|tid| self.resume_actions.get(tid) == Some(&ResumeAction::Step))
+            .collect()
+    }
+
+    /// Whether gdb asked for any thread to continue, either by giving it
+    /// that action or by not locking the threads it gave none
+    fn any_continuing(&self) -> bool {
+        !self.scheduler_locked
+            || self
+                .resume_actions
+                .values()
+                .any(|&action| action == ResumeAction::Continue)
+    }
+
+    /// Work out which core halted and why, once the simulation has stopped
+    /// after a step or a continue that gdb did not interrupt. `stepped` holds
+    /// the instance ids of the cores that were stepped.
+    fn stop_reason(&mut self, stepped: &[u32]) -> Result<ThreadStopReason<u32>, IrisError> {
+        let hit = self.cores.iter().enumerate().find_map(|(index, core)| {
+            let hit = core.last_hit.as_ref().and_then(|hit| hit.get())?;
+            let ours = self
+                .breakpoints
+                .values()
+                .flatten()
+                .any(|&(inst, id)| inst == core.instance_id && id == hit.id);
+            ours.then(|| Self::tid(index))
+        });
+        if let Some(tid) = hit {
+            return Ok(ThreadStopReason::HwBreak(tid));
+        }
+        if let Some(index) = self.step_done(stepped)? {
+            return Ok(ThreadStopReason::SignalWithThread {
+                tid: Self::tid(index),
+                signal: Signal(SIGTRAP),
+            });
+        }
+        for index in 0..self.cores.len() {
+            let instance_id = self.cores[index].instance_id;
+            if let Some(pc) = read_resource(self.iris, instance_id, "R15")? {
+                if self.breakpoints.contains_key(&((pc as u32) & !1)) {
+                    return Ok(ThreadStopReason::HwBreak(Self::tid(index)));
+                }
+            }
+        }
+        Ok(ThreadStopReason::Signal(Signal(SIGTRAP)))
+    }
+
+    /// The index of the first stepped core that finished its step, if any.
+    /// gdb is told which thread that is, as it may not be the one it last
+    /// selected.
+    fn step_done(&mut self, stepped: &[u32]) -> Result<Option<usize>, IrisError> {
+        for &instance_id in stepped {
+            if step::remaining(self.iris, instance_id, Unit::Instruction)? == 0 {
+                return Ok(self
+                    .cores
+                    .iter()
+                    .position(|core| core.instance_id == instance_id));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Drop the steps left over on cores that didn't finish theirs, so that
+    /// they don't stop the next continue.
+    fn cancel_steps(&mut self, stepped: &[u32]) -> Result<(), IrisError> {
+        for &instance_id in stepped {
+            if step::remaining(self.iris, instance_id, Unit::Instruction)? != 0 {
+                step::setup(self.iris, instance_id, 0, Unit::Instruction)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Take out the breakpoints gdb left behind, so the next session starts
+/// clean. Failures are ignored, as the model may be gone.
+impl Drop for IrisGdbStubMulti<'_> {
+    fn drop(&mut self) {
+        for (instance_id, id) in self.breakpoints.drain().flat_map(|(_, set)| set) {
+            let _ = breakpoint::delete(self.iris, instance_id, id);
+        }
+    }
+}
+
+impl<'i> Target for IrisGdbStubMulti<'i> {
+    type Arch = Armv7mArch;
+    type Error = ();
+    fn base_ops(&mut self) -> BaseOps<'_, Self::Arch, Self::Error> {
+        BaseOps::MultiThread(self)
+    }
+
+    fn support_breakpoints(&mut self) -> Option<BreakpointsOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_target_description_xml_override(
+        &mut self,
+    ) -> Option<TargetDescriptionXmlOverrideOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl TargetDescriptionXmlOverride for IrisGdbStubMulti<'_> {
+    fn target_description_xml(
+        &self,
+        _annex: &[u8],
+        offset: u64,
+        length: usize,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        Ok(copy_range(self.target_xml.as_bytes(), offset, length, buf))
+    }
+}
+
+/// There's no process to deliver a signal to, so signals are dropped
+impl MultiThreadResume for IrisGdbStubMulti<'_> {
+    fn resume(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn clear_resume_actions(&mut self) -> Result<(), ()> {
+        self.resume_actions.clear();
+        self.scheduler_locked = false;
+        Ok(())
+    }
+
+    fn set_resume_action_continue(&mut self, tid: Tid, _signal: Option<Signal>) -> Result<(), ()> {
+        self.resume_actions.insert(tid, ResumeAction::Continue);
+        Ok(())
+    }
+
+    fn support_single_step(&mut self) -> Option<MultiThreadSingleStepOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_scheduler_locking(&mut self) -> Option<MultiThreadSchedulerLockingOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl MultiThreadSingleStep for IrisGdbStubMulti<'_> {
+    fn set_resume_action_step(&mut self, tid: Tid, _signal: Option<Signal>) -> Result<(), ()> {
+        self.resume_actions.insert(tid, ResumeAction::Step);
+        Ok(())
+    }
+}
+
+/// The cores share the simulation, so no thread can be held back; see the
+/// module documentation
+impl MultiThreadSchedulerLocking for IrisGdbStubMulti<'_> {
+    fn set_resume_action_scheduler_lock(&mut self) -> Result<(), ()> {
+        self.scheduler_locked = true;
+        Ok(())
+    }
+}
+
+impl RunTarget for IrisGdbStubMulti<'_> {
+    type StopReason = ThreadStopReason<u32>;
+
+    fn wait_for_stop(
+        &mut self,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> Result<Option<ThreadStopReason<u32>>, ()> {
+        for core in &self.cores {
+            if let Some(last_hit) = &core.last_hit {
+                last_hit.set(None);
+            }
+        }
+        let mut stepped = Vec::new();
+        for tid in self.stepping_threads() {
+            let instance_id = self.core(tid).map_err(|_| ())?.instance_id;
+            step::setup(self.iris, instance_id, 1, Unit::Instruction).map_err(report)?;
+            stepped.push(instance_id);
+        }
+        let timeout = if stepped.is_empty() || self.any_continuing() {
+            self.timeout
+        } else {
+            None
+        };
+        let res = run_until_halt(self.iris, self.sim, interrupted, timeout, None);
+        let reason = match res {
+            Ok(true) => Ok(None),
+            Ok(false) => self.stop_reason(&stepped).map(Some),
+            Err(IrisError::Timeout) => Ok(Some(ThreadStopReason::Signal(Signal(SIGALRM)))),
+            Err(e) => Err(e),
+        };
+        let cancelled = self.cancel_steps(&stepped);
+        let reason = reason.map_err(report)?;
+        cancelled.map_err(report)?;
+        Ok(reason)
+    }
+
+    fn interrupt(&mut self) -> Result<ThreadStopReason<u32>, ()> {
+        self.iris.stop(self.sim).map_err(report)?;
+        Ok(ThreadStopReason::Signal(Signal(SIGINT)))
+    }
+}
+
+impl MultiThreadBase for IrisGdbStubMulti<'_> {
+    fn support_resume(&mut self) -> Option<MultiThreadResumeOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn read_registers(&mut self, regs: &mut GuestState, tid: Tid) -> TargetResult<(), Self> {
+        let instance_id = self.core(tid)?.instance_id;
+        read_core_registers(self.iris, instance_id, self.has_fpu, regs).map_err(report)?;
+        Ok(())
+    }
+
+    fn write_registers(&mut self, regs: &GuestState, tid: Tid) -> TargetResult<(), Self> {
+        let instance_id = self.core(tid)?.instance_id;
+        write_core_registers(self.iris, instance_id, regs).map_err(report)?;
+        Ok(())
+    }
+
+    /// Reads up to the first address that faults, failing if that is the
+    /// first one.
+    fn read_addrs(
+        &mut self,
+        start_addr: u32,
+        data: &mut [u8],
+        tid: Tid,
+    ) -> TargetResult<usize, Self> {
+        let core = self.core(tid)?;
+        let (instance_id, space_id) = (core.instance_id, core.space_id);
+        let valid = read_memory(
+            self.iris,
+            instance_id,
+            space_id,
+            u64::from(start_addr),
+            data,
+            Endianness::Little,
+        )
+        .map_err(report)?;
+        if valid == 0 && !data.is_empty() {
+            return Err(TargetError::NonFatal);
+        }
+        Ok(valid)
+    }
+
+    fn write_addrs(&mut self, start_addr: u32, data: &[u8], tid: Tid) -> TargetResult<(), Self> {
+        let core = self.core(tid)?;
+        let (instance_id, space_id) = (core.instance_id, core.space_id);
+        write_memory(
+            self.iris,
+            instance_id,
+            space_id,
+            u64::from(start_addr),
+            data,
+            Endianness::Little,
+        )
+        .map_err(report)?;
+        Ok(())
+    }
+
+    fn list_active_threads(&mut self, thread_is_active: &mut dyn FnMut(Tid)) -> Result<(), ()> {
+        for index in 0..self.cores.len() {
+            thread_is_active(Self::tid(index));
+        }
+        Ok(())
+    }
+}
+
+impl<'i> Breakpoints for IrisGdbStubMulti<'i> {
+    fn support_hw_breakpoint(&mut self) -> Option<HwBreakpointOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_sw_breakpoint(&mut self) -> Option<SwBreakpointOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+/// Patching memory would stop every core sharing it, so gdb's `break` sets
+/// hardware breakpoints as well.
+impl<'i> SwBreakpoint for IrisGdbStubMulti<'i> {
+    fn add_sw_breakpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        kind: <Self::Arch as Arch>::BreakpointKind,
+    ) -> TargetResult<bool, Self> {
+        self.add_hw_breakpoint(addr, kind)
+    }
+
+    fn remove_sw_breakpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        kind: <Self::Arch as Arch>::BreakpointKind,
+    ) -> TargetResult<bool, Self> {
+        self.remove_hw_breakpoint(addr, kind)
+    }
+}
+
+impl<'i> HwBreakpoint for IrisGdbStubMulti<'i> {
+    /// Sets the breakpoint on every core, or on none of them if any core
+    /// rejects it.
+    fn add_hw_breakpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        _: <Self::Arch as Arch>::BreakpointKind,
+    ) -> TargetResult<bool, Self> {
+        if self.breakpoints.contains_key(&addr) {
+            return Ok(true);
+        }
+        let mut set = Vec::with_capacity(self.cores.len());
+        for index in 0..self.cores.len() {
+            let instance_id = self.cores[index].instance_id;
+            let space = pc_space(self.iris, instance_id).map_err(report)?;
+            match breakpoint::code(
+                self.iris,
+                instance_id,
+                u64::from(addr),
+                None,
+                space,
+                true,
+                false,
+            ) {
+                Ok(id) => set.push((instance_id, id)),
+                Err(_) => {
+                    for (instance_id, id) in set {
+                        let _ = breakpoint::delete(self.iris, instance_id, id);
+                    }
+                    return Ok(false);
+                }
+            }
+        }
+        self.breakpoints.insert(addr, set);
+        Ok(true)
+    }
+
+    fn remove_hw_breakpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        _: <Self::Arch as Arch>::BreakpointKind,
+    ) -> TargetResult<bool, Self> {
+        let set = match self.breakpoints.remove(&addr) {
+            Some(set) => set,
+            None => return Ok(true),
+        };
+        // The breakpoints that couldn't be deleted are kept, so that a
+        // later remove can try them again
+        let left: Vec<_> = set
+            .into_iter()
+            .filter(|&(instance_id, id)| breakpoint::delete(self.iris, instance_id, id).is_err())
+            .collect();
+        if left.is_empty() {
+            return Ok(true);
+        }
+        self.breakpoints.insert(addr, left);
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gdb::armv7m::CORE_REGISTERS;
+    use crate::iris_client::Answer;
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// The requests a model was sent, as their method and parameters
+    type Log = Arc<Mutex<Vec<(String, Value)>>>;
+
+    /// How a model of Cortex-M cores answers a stub, as a server made by
+    /// `FastModelIris::answered`. The simulation reports that it is
+    /// running for `polls` reads of its time after each run, and then the
+    /// cores in `finishing` have taken their steps.
+    fn model(finishing: &'static [u64], polls: usize, log: Log) -> Arc<Answer> {
+        let remaining = Mutex::new(HashMap::new());
+        let running = AtomicUsize::new(0);
+        Arc::new(move |method: &str, params: &Value| {
+            log.lock()
+                .unwrap()
+                .push((method.to_string(), params.clone()));
+            let inst = params["instId"].as_u64().unwrap_or_default();
+            match method {
+                "instanceRegistry_getInstanceInfoByName" => {
+                    Ok(json!({"instId": 0, "instName": params["instName"]}))
+                }
+                "resource_getList" => Ok(CORE_REGISTERS
+                    .iter()
+                    .enumerate()
+                    .map(|(id, (_, name))| json!({"rscId": id, "name": name, "cname": name, "bitWidth": 32}))
+                    .collect()),
+                "resource_read" => Ok(json!({"data": [0x100]})),
+                "step_setup" => {
+                    remaining
+                        .lock()
+                        .unwrap()
+                        .insert(inst, params["steps"].clone());
+                    Ok(json!(null))
+                }
+                "step_getRemainingSteps" => {
+                    let steps = remaining.lock().unwrap().get(&inst).cloned();
+                    Ok(steps.unwrap_or(json!(0)))
+                }
+                "simulationTime_run" => {
+                    running.store(polls, Ordering::SeqCst);
+                    let mut remaining = remaining.lock().unwrap();
+                    for inst in finishing {
+                        remaining.insert(*inst, json!(0));
+                    }
+                    Ok(json!(null))
+                }
+                "simulationTime_stop" => {
+                    running.store(0, Ordering::SeqCst);
+                    Ok(json!(null))
+                }
+                "simulationTime_get" => {
+                    let polls = running.load(Ordering::SeqCst);
+                    running.store(polls.saturating_sub(1), Ordering::SeqCst);
+                    Ok(json!({"ticks": 0, "tickHz": 1, "running": polls > 0}))
+                }
+                _ => Err(format!("{} is not modelled", method)),
+            }
+        })
+    }
+
+    /// The requests sent from the first run of the simulation on
+    fn from_run(log: &Log) -> Vec<(String, Value)> {
+        let log = log.lock().unwrap();
+        let run = log
+            .iter()
+            .position(|(method, _)| method == "simulationTime_run")
+            .expect("a run");
+        log[run..].to_vec()
+    }
+
+    /// The cores and step counts of the `step_setup` requests among `log`
+    fn setups(log: &[(String, Value)]) -> Vec<(u64, u64)> {
+        log.iter()
+            .filter(|(method, _)| method == "step_setup")
+            .map(|(_, params)| {
+                (
+                    params["instId"].as_u64().unwrap(),
+                    params["steps"].as_u64().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn tid(n: usize) -> Tid {
+        Tid::new(n).unwrap()
+    }
+
+    #[test]
+    fn stepping_one_thread_while_another_continues() {
+        let log = Log::default();
+        let mut iris = FastModelIris::answered(model(&[7], 0, log.clone()));
+        let mut stub = IrisGdbStubMulti::from_instances(&mut iris, &[7, 9]).unwrap();
+        stub.set_resume_action_step(tid(1), None).unwrap();
+        stub.set_resume_action_continue(tid(2), None).unwrap();
+        log.lock().unwrap().clear();
+        let reason = stub.wait_for_stop(&mut || false).unwrap();
+        assert_eq!(
+            reason,
+            Some(ThreadStopReason::SignalWithThread {
+                tid: tid(1),
+                signal: Signal(SIGTRAP),
+            })
+        );
+        // Only the stepped core is set up to stop, and before the run
+        assert_eq!(setups(&log.lock().unwrap()), [(7, 1)]);
+        assert!(setups(&from_run(&log)).is_empty());
+    }
+
+    #[test]
+    fn a_step_alone_runs_without_the_timeout() {
+        let log = Log::default();
+        let mut iris = FastModelIris::answered(model(&[9], 3, log.clone()));
+        let mut stub = IrisGdbStubMulti::from_instances(&mut iris, &[7, 9])
+            .unwrap()
+            .with_resume_timeout(Duration::ZERO);
+        stub.set_resume_action_step(tid(2), None).unwrap();
+        stub.set_resume_action_scheduler_lock().unwrap();
+        let reason = stub.wait_for_stop(&mut || false).unwrap();
+        assert_eq!(
+            reason,
+            Some(ThreadStopReason::SignalWithThread {
+                tid: tid(2),
+                signal: Signal(SIGTRAP),
+            })
+        );
+        let after = from_run(&log);
+        assert!(after
+            .iter()
+            .all(|(method, _)| method != "simulationTime_stop"));
+
+        // Once another thread continues, the timeout applies again
+        stub.clear_resume_actions().unwrap();
+        stub.set_resume_action_step(tid(2), None).unwrap();
+        let reason = stub.wait_for_stop(&mut || false).unwrap();
+        assert_eq!(reason, Some(ThreadStopReason::Signal(Signal(SIGALRM))));
+    }
+
+    #[test]
+    fn steps_left_over_are_cancelled() {
+        let log = Log::default();
+        let mut iris = FastModelIris::answered(model(&[9], 0, log.clone()));
+        let mut stub = IrisGdbStubMulti::from_instances(&mut iris, &[7, 9]).unwrap();
+        stub.set_resume_action_step(tid(1), None).unwrap();
+        stub.set_resume_action_step(tid(2), None).unwrap();
+        let reason = stub.wait_for_stop(&mut || false).unwrap();
+        assert_eq!(
+            reason,
+            Some(ThreadStopReason::SignalWithThread {
+                tid: tid(2),
+                signal: Signal(SIGTRAP),
+            })
+        );
+        assert_eq!(setups(&log.lock().unwrap()), [(7, 1), (9, 1), (7, 0)]);
+        assert_eq!(setups(&from_run(&log)), [(7, 0)]);
+    }
+}
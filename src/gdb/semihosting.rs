@@ -0,0 +1,395 @@
+//! Semihosting for Armv7-M firmware, serviced by the gdb stub.
+//!
+//! The model's own semihosting must be disabled, so that the `BKPT 0xAB`
+//! of a semihosting call halts the core instead. The stub then performs the
+//! call and resumes the core, so firmware output appears while gdb waits on
+//! a continue.
+//!
+//! Firmware can also read host files, from a directory given with
+//! `Semihosting::files`. gdbstub doesn't support gdb's File-I/O protocol,
+//! which would have gdb perform these calls, so the stub opens the files
+//! itself. Files are only opened for reading, and only below that
+//! directory.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::gdb::{read_memory, read_resource, write_memory};
+use crate::memory::Endianness;
+use crate::{resource, FastModelIris, IrisError};
+
+/// The 16 bit Thumb encoding of `BKPT #0xab`
+const BKPT_SEMIHOSTING: [u8; 2] = 0xbeabu16.to_le_bytes();
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_READC: u32 = 0x07;
+const SYS_SEEK: u32 = 0x0a;
+const SYS_FLEN: u32 = 0x0c;
+
+/// What semihosting calls return on failure
+const FAILED: u32 = u32::MAX;
+
+/// The longest file name `SYS_OPEN` accepts, that of Linux
+const PATH_MAX: u32 = 4096;
+
+/// The most bytes `SYS_READ` and `SYS_WRITE` move at a time, so that the
+/// length the firmware passes doesn't decide how much the stub allocates
+const CHUNK: usize = 4096;
+
+/// A handle returned by `SYS_OPEN`
+enum Handle {
+    /// `:tt`, the console
+    Console,
+    File(File),
+}
+
+/// Where semihosting output goes and input comes from.
+pub struct Semihosting {
+    out: Box<dyn Write>,
+    input: Box<dyn Read>,
+    /// The directory `SYS_OPEN` opens host files in, if it opens them at
+    /// all
+    root: Option<PathBuf>,
+    handles: HashMap<u32, Handle>,
+    next_handle: u32,
+}
+
+impl Semihosting {
+    pub fn new(out: impl Write + 'static, input: impl Read + 'static) -> Self {
+        Self {
+            out: Box::new(out),
+            input: Box::new(input),
+            root: None,
+            handles: HashMap::new(),
+            next_handle: 1,
+        }
+    }
+
+    /// Service `SYS_OPEN`, `SYS_READ`, `SYS_SEEK`, `SYS_FLEN` and
+    /// `SYS_CLOSE`, opening the files the firmware names relative to
+    /// `root`. Names that are absolute, that go up with `..` or that lead
+    /// outside `root` through a link fail to open, as do the modes that
+    /// write and names longer than `PATH_MAX`. `:tt` opens the console,
+    /// on which `SYS_SEEK` and `SYS_FLEN` fail, with or without this;
+    /// without it, calls on any other file are left for gdb to see.
+    pub fn files(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = Some(root.into());
+        self
+    }
+
+    /// Perform the semihosting call the core is halted on, if it is halted
+    /// on one, and step the core past it. Returns whether the call was
+    /// handled; unsupported calls are left for gdb to see.
+    pub(crate) fn handle(
+        &mut self,
+        iris: &mut FastModelIris,
+        instance_id: u32,
+        space: u64,
+    ) -> Result<bool, IrisError> {
+        let pc = match read_resource(iris, instance_id, "R15")? {
+            Some(pc) => pc,
+            None => return Ok(false),
+        };
+        let mut insn = [0u8; 2];
+        let valid = read_memory(iris, instance_id, space, pc, &mut insn, Endianness::Little)?;
+        if valid < insn.len() || insn != BKPT_SEMIHOSTING {
+            return Ok(false);
+        }
+        let op = read_resource(iris, instance_id, "R0")?.unwrap_or(0) as u32;
+        let param = read_resource(iris, instance_id, "R1")?.unwrap_or(0);
+        let ret = match op {
+            SYS_WRITEC => {
+                let mut c = [0u8];
+                read_exact(iris, instance_id, space, param, &mut c)?;
+                self.write(&c);
+                None
+            }
+            SYS_WRITE0 => {
+                let s = read_c_string(iris, instance_id, space, param)?;
+                self.write(&s);
+                None
+            }
+            SYS_WRITE => {
+                let mut block = [0u8; 12];
+                read_exact(iris, instance_id, space, param, &mut block)?;
+                let word = |n: usize| u32::from_le_bytes(block[n * 4..][..4].try_into().unwrap());
+                // The files are opened for reading only
+                if let Some(Handle::File(_)) = self.handles.get(&word(0)) {
+                    return self.finish(iris, instance_id, pc, Some(word(2)));
+                }
+                // The result is the number of bytes that were not written
+                let mut left = word(2);
+                let mut addr = u64::from(word(1));
+                let mut data = [0u8; CHUNK];
+                while left > 0 {
+                    let chunk = &mut data[..CHUNK.min(left as usize)];
+                    read_exact(iris, instance_id, space, addr, chunk)?;
+                    if !self.write(chunk) {
+                        break;
+                    }
+                    left -= chunk.len() as u32;
+                    addr += chunk.len() as u64;
+                }
+                Some(left)
+            }
+            SYS_READC => {
+                let mut c = [0u8];
+                Some(match self.input.read(&mut c) {
+                    Ok(1) => u32::from(c[0]),
+                    _ => FAILED,
+                })
+            }
+            SYS_OPEN | SYS_CLOSE | SYS_READ | SYS_SEEK | SYS_FLEN => {
+                match self.file_call(iris, instance_id, space, op, param)? {
+                    Some(ret) => Some(ret),
+                    None => return Ok(false),
+                }
+            }
+            _ => return Ok(false),
+        };
+        self.finish(iris, instance_id, pc, ret)
+    }
+
+    /// Return `ret` to the firmware, if the call has a result, and step the
+    /// core past the `BKPT` at `pc`
+    fn finish(
+        &mut self,
+        iris: &mut FastModelIris,
+        instance_id: u32,
+        pc: u64,
+        ret: Option<u32>,
+    ) -> Result<bool, IrisError> {
+        let (ids, values) = resource::cached_list(iris, instance_id)?
+            .iter()
+            .filter_map(|res| match (res.name.as_str(), ret) {
+                ("R0", Some(ret)) => Some((res.id, u64::from(ret))),
+                ("R15", _) => Some((res.id, pc + 2)),
+                _ => None,
+            })
+            .unzip();
+        resource::write(iris, instance_id, ids, values)?;
+        Ok(true)
+    }
+
+    /// Returns whether all of `data` was written
+    fn write(&mut self, data: &[u8]) -> bool {
+        self.out.write_all(data).is_ok() && self.out.flush().is_ok()
+    }
+
+    /// Perform a call on a file handle, or `SYS_OPEN`, returning its
+    /// result, or `None` when it needs host files and `Semihosting::files`
+    /// wasn't given. Host errors are reported to the firmware as failures.
+    fn file_call(
+        &mut self,
+        iris: &mut FastModelIris,
+        instance_id: u32,
+        space: u64,
+        op: u32,
+        param: u64,
+    ) -> Result<Option<u32>, IrisError> {
+        let mut block = [0u8; 12];
+        let len = match op {
+            SYS_CLOSE | SYS_FLEN => 4,
+            SYS_SEEK => 8,
+            _ => 12,
+        };
+        read_exact(iris, instance_id, space, param, &mut block[..len])?;
+        let word = |n: usize| u32::from_le_bytes(block[n * 4..][..4].try_into().unwrap());
+        if op == SYS_OPEN {
+            if word(2) > PATH_MAX {
+                return Ok(self.root.is_some().then_some(FAILED));
+            }
+            let mut name = vec![0u8; word(2) as usize];
+            read_exact(iris, instance_id, space, u64::from(word(0)), &mut name)?;
+            let name = String::from_utf8_lossy(&name);
+            if name != ":tt" && self.root.is_none() {
+                return Ok(None);
+            }
+            return Ok(Some(self.open(&name, word(1))));
+        }
+        // Without a root the only handles are the console's
+        let handle = match self.handles.get_mut(&word(0)) {
+            Some(handle) => handle,
+            None if self.root.is_none() => return Ok(None),
+            None => return Ok(Some(FAILED)),
+        };
+        Ok(Some(match (op, handle) {
+            (SYS_CLOSE, _) => {
+                self.handles.remove(&word(0));
+                0
+            }
+            (SYS_READ, handle) => {
+                // The result is the number of bytes that were not read
+                let mut left = word(2);
+                let mut addr = u64::from(word(1));
+                let mut data = [0u8; CHUNK];
+                while left > 0 {
+                    let chunk = &mut data[..CHUNK.min(left as usize)];
+                    let read = match handle {
+                        Handle::Console => self.input.read(chunk),
+                        Handle::File(file) => read_up_to(file, chunk),
+                    };
+                    let read = match read {
+                        Ok(read) => read,
+                        Err(_) => return Ok(Some(FAILED)),
+                    };
+                    write_memory(
+                        iris,
+                        instance_id,
+                        space,
+                        addr,
+                        &chunk[..read],
+                        Endianness::Little,
+                    )?;
+                    left -= read as u32;
+                    addr += read as u64;
+                    // A read of the console is done once some input has
+                    // come in, rather than wait for the rest
+                    if read < chunk.len() || matches!(handle, Handle::Console) {
+                        break;
+                    }
+                }
+                left
+            }
+            (SYS_SEEK, Handle::File(file)) => match file.seek(SeekFrom::Start(word(1).into())) {
+                Ok(_) => 0,
+                Err(_) => FAILED,
+            },
+            (SYS_FLEN, Handle::File(file)) => match file.metadata() {
+                Ok(meta) => meta.len().try_into().unwrap_or(FAILED),
+                Err(_) => FAILED,
+            },
+            _ => FAILED,
+        }))
+    }
+
+    /// Open `name` in `mode`, returning the new handle
+    fn open(&mut self, name: &str, mode: u32) -> u32 {
+        // Modes 0 and 1 are "r" and "rb"
+        let handle = if name == ":tt" {
+            Handle::Console
+        } else if mode > 1 {
+            return FAILED;
+        } else {
+            match self.root.as_deref().and_then(|root| sandboxed(root, name)) {
+                Some(path) => match File::open(path) {
+                    Ok(file) => Handle::File(file),
+                    Err(_) => return FAILED,
+                },
+                None => return FAILED,
+            }
+        };
+        let n = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(n, handle);
+        n
+    }
+}
+
+/// The path of `name` below `root`, unless it leads elsewhere
+fn sandboxed(root: &Path, name: &str) -> Option<PathBuf> {
+    let name = Path::new(name);
+    let plain = name
+        .components()
+        .all(|part| matches!(part, Component::Normal(_) | Component::CurDir));
+    if !plain {
+        return None;
+    }
+    let root = root.canonicalize().ok()?;
+    let path = root.join(name).canonicalize().ok()?;
+    path.starts_with(&root).then_some(path)
+}
+
+/// Read as much of `data` as the file has left
+fn read_up_to(file: &mut File, data: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < data.len() {
+        match file.read(&mut data[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+fn read_exact(
+    iris: &mut FastModelIris,
+    instance_id: u32,
+    space: u64,
+    addr: u64,
+    data: &mut [u8],
+) -> Result<(), IrisError> {
+    let valid = read_memory(iris, instance_id, space, addr, data, Endianness::Little)?;
+    if valid < data.len() {
+        return Err(IrisError::MemoryFault(addr + valid as u64));
+    }
+    Ok(())
+}
+
+/// Read the NUL terminated string at `addr`, without the NUL
+fn read_c_string(
+    iris: &mut FastModelIris,
+    instance_id: u32,
+    space: u64,
+    mut addr: u64,
+) -> Result<Vec<u8>, IrisError> {
+    const CHUNK: usize = 64;
+    let mut s = Vec::new();
+    loop {
+        let mut chunk = [0u8; CHUNK];
+        let valid = read_memory(
+            iris,
+            instance_id,
+            space,
+            addr,
+            &mut chunk,
+            Endianness::Little,
+        )?;
+        if valid == 0 {
+            return Err(IrisError::MemoryFault(addr));
+        }
+        match chunk[..valid].iter().position(|&b| b == 0) {
+            Some(end) => {
+                s.extend_from_slice(&chunk[..end]);
+                return Ok(s);
+            }
+            None => s.extend_from_slice(&chunk[..valid]),
+        }
+        addr += valid as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_opened_only_below_the_root() {
+        let root = std::env::temp_dir().join(format!("cornea-sandbox-{}", std::process::id()));
+        // Left behind by a run that failed
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("data")).unwrap();
+        std::fs::write(root.join("data/input.bin"), b"").unwrap();
+        let inside = root.canonicalize().unwrap().join("data/input.bin");
+        assert_eq!(sandboxed(&root, "data/input.bin"), Some(inside.clone()));
+        assert_eq!(sandboxed(&root, "./data/input.bin"), Some(inside));
+        assert_eq!(sandboxed(&root, "data/missing.bin"), None);
+        assert_eq!(sandboxed(&root, "data/../data/input.bin"), None);
+        assert_eq!(sandboxed(&root, "/etc/passwd"), None);
+        #[cfg(unix)]
+        {
+            // A link out of the root is followed, and so refused
+            std::os::unix::fs::symlink("/etc", root.join("etc")).unwrap();
+            assert_eq!(sandboxed(&root, "etc/passwd"), None);
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,274 +1,1852 @@
 use std::borrow::Borrow;
 use std::collections::hash_map::{Entry, HashMap};
-use std::convert::TryInto;
-use std::io::{Error as IOError, Read, Stdin, Stdout, Write};
-use std::sync::mpsc::{channel, Receiver};
-use std::thread::spawn;
-
-use gdbstub::arch::{Arch, RegId, Registers};
-use gdbstub::target::ext::base::singlethread::{SingleThreadOps, StopReason};
-use gdbstub::target::ext::base::{BaseOps, ResumeAction};
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+use std::time::Duration;
+
+use gdbstub::arch::Arch;
+use gdbstub::common::{Pid, Signal};
+use gdbstub::outputln;
+use gdbstub::stub::{DisconnectReason, SingleThreadStopReason as StopReason};
+use gdbstub::target::ext::base::single_register_access::{
+    SingleRegisterAccess, SingleRegisterAccessOps,
+};
+use gdbstub::target::ext::base::singlethread::{
+    SingleThreadBase, SingleThreadRangeStepping, SingleThreadRangeSteppingOps, SingleThreadResume,
+    SingleThreadResumeOps, SingleThreadSingleStep, SingleThreadSingleStepOps,
+};
+use gdbstub::target::ext::base::BaseOps;
 #[allow(unused)]
 use gdbstub::target::ext::breakpoints::{
-    Breakpoints, BreakpointsOps, HwBreakpoint, HwBreakpointOps, SwBreakpoint, SwBreakpointOps,
+    Breakpoints, BreakpointsOps, HwBreakpoint, HwBreakpointOps, HwWatchpoint, HwWatchpointOps,
+    SwBreakpoint, SwBreakpointOps, WatchKind,
+};
+use gdbstub::target::ext::extended_mode::{
+    Args, AttachKind, ExtendedMode, ExtendedModeOps, ShouldTerminate,
 };
+use gdbstub::target::ext::flash::{Flash, FlashOps};
+use gdbstub::target::ext::memory_map::{MemoryMap, MemoryMapOps};
 use gdbstub::target::ext::monitor_cmd::{ConsoleOutput, MonitorCmd, MonitorCmdOps};
-use gdbstub::target::{Target, TargetResult};
-use gdbstub::{outputln, Connection};
+use gdbstub::target::ext::section_offsets::{Offsets, SectionOffsets, SectionOffsetsOps};
+use gdbstub::target::ext::target_description_xml_override::{
+    TargetDescriptionXmlOverride, TargetDescriptionXmlOverrideOps,
+};
+use gdbstub::target::{Target, TargetError, TargetResult};
+
+#[cfg(feature = "std")]
+pub use crate::gdb::transport::{GdbOverPipe, GdbOverTcp};
 
-use crate::{
-    breakpoint, instance_registry, memory, resource, simulation, simulation_time, step,
-    FastModelIris,
+pub use crate::gdb::armv7m::{Armv7mArch, GuestState, Register, Xpsr};
+use crate::gdb::armv7m::{BANKED_REGISTERS, BANKED_REGNUM, CORE_REGISTERS};
+use crate::gdb::backend::IrisBackend;
+use crate::gdb::fault::FaultInfo;
+use crate::gdb::semihosting::Semihosting;
+use crate::gdb::{
+    check_space, copy_range, disasm_monitor_cmd, parse_value, pc_space, read_memory, read_resource,
+    report, resource_monitor_cmd, run_until_halt, track_breakpoint_hits, write_memory, LastHit,
+    RunTarget, SignalMap, Watchdog,
 };
+use crate::instance_registry::SecurityState;
+use crate::memory::{Endianness, MemKind, MemRegion};
+use crate::simulation::ResetKind;
+use crate::step::Unit;
+use crate::{disasm, memory, step, FastModelIris, IrisError};
 
-pub struct IrisGdbStub<'i> {
-    pub iris: &'i mut FastModelIris,
+/// A gdb stub for an Armv7-M core, reached through the backend `B`, by
+/// default a connection to the model.
+pub struct IrisGdbStub<'i, B: IrisBackend + ?Sized = FastModelIris> {
+    pub iris: &'i mut B,
     pub instance_id: u32,
     sim: u32,
     breakpoints: HashMap<u32, u64>,
+    /// Conditions on the hardware breakpoints, by address; see
+    /// `skip_hit`. Kept while gdb takes its breakpoints out around a stop,
+    /// and so only dropped by `monitor condition <addr>`.
+    conditions: HashMap<u32, Condition>,
+    /// The instructions replaced by software breakpoints, with the memory
+    /// space they were patched in, keyed by address
+    sw_breakpoints: HashMap<u32, (u64, [u8; 2])>,
+    /// Watched addresses and access kinds, keyed by Iris breakpoint id
+    watchpoints: HashMap<u64, (u32, WatchKind)>,
+    last_hit: Option<LastHit>,
+    /// The memory space used by `read_addrs` and `write_addrs`
+    space_id: u64,
+    /// Whether `space_id` follows the PC's space at each stop; see
+    /// `IrisGdbStubBuilder::follow_security_state`
+    follow_space: bool,
+    /// How long a continue may run before the target is stopped
+    timeout: Option<Duration>,
+    watchdog: Option<Watchdog>,
+    semihosting: Option<Semihosting>,
+    breakpoint_kind: BreakpointKind,
+    signals: SignalMap,
+    /// Whether gdb is shown the VFP registers S0-S31 and FPSCR
+    has_fpu: bool,
+    /// What gdb last asked the core to do, carried out by `wait_for_stop`
+    resume: Resume,
+    /// The byte order of registers and memory as gdb sees them
+    endianness: Endianness,
+    /// Address ranges read with units of the given width; see
+    /// `IrisGdbStubBuilder::device_region`
+    device_regions: Vec<(Range<u64>, u64)>,
+    /// How far the image was moved from its link addresses, reported to gdb
+    /// as the offset of every section
+    load_offset: u32,
+    /// The registers as gdb last read or wrote them, when caching is on;
+    /// see `IrisGdbStubBuilder::cache_registers`
+    register_cache: Option<RegisterCache>,
+    /// Called with what gdb does to the target; see `set_observer`
+    observer: Option<Box<dyn FnMut(TraceEvent)>>,
+    on_detach: SessionEnd,
+    on_kill: SessionEnd,
+    target_xml: String,
+    /// The memory map of the space the stub was built with; see
+    /// `memory_map_xml`
+    memory_map: String,
+    /// The flash regions of the memory map, which gdb's `load` erases and
+    /// writes with the flash packets
+    flash: Vec<Range<u64>>,
+    flash_block_size: u64,
 }
 
-#[derive(Debug, Default, Clone, PartialEq)]
-pub struct GuestState {
-    pub regs: [u32; 26],
+/// How gdb asked for the core to be resumed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resume {
+    Continue,
+    Step,
+    /// Step until the PC leaves `start..end`
+    RangeStep(u32, u32),
 }
 
-impl<'i> IrisGdbStub<'i> {
-    pub fn from_instance(iris: &'i mut FastModelIris, instance_id: u32) -> std::io::Result<Self> {
-        let sim = instance_registry::get_instance_by_name(
-            iris,
-            "framework.SimulationEngine".to_string(),
-        )?;
-        Ok(Self {
+/// Something gdb did to the target, as passed to the observer set with
+/// `IrisGdbStub::set_observer`. Register values are given as their words,
+/// least significant first, in the order the model holds them.
+#[derive(Debug, Clone)]
+pub enum TraceEvent {
+    /// A step of one `unit` ended with the core at `pc`
+    Step {
+        pc: u32,
+        unit: Unit,
+    },
+    /// The core halted on a breakpoint at `pc`
+    Breakpoint {
+        pc: u32,
+    },
+    /// The core halted on a watchpoint on `addr`
+    Watchpoint {
+        addr: u32,
+    },
+    /// gdb read all of the registers
+    RegistersRead(GuestState),
+    /// gdb wrote all of the registers
+    RegistersWritten(GuestState),
+    RegisterRead {
+        register: Register,
+        value: Vec<u32>,
+    },
+    RegisterWritten {
+        register: Register,
+        value: Vec<u32>,
+    },
+    /// gdb read memory, starting at `addr`; only the bytes read before a
+    /// fault are given
+    MemoryRead {
+        addr: u32,
+        data: Vec<u8>,
+    },
+    MemoryWritten {
+        addr: u32,
+        data: Vec<u8>,
+    },
+}
+
+/// The registers of the core while it is halted, with whether gdb wrote any
+/// of them since they were last written to the model
+#[derive(Debug, Default)]
+struct RegisterCache {
+    state: Option<GuestState>,
+    dirty: bool,
+}
+
+/// The Peripheral, Device and System regions of the Armv7-M memory map.
+/// Their registers may not support byte accesses, or have side effects on
+/// read, so gdb's reads there use whole words.
+const DEVICE_REGIONS: [(Range<u64>, u64); 2] = [
+    (0x4000_0000..0x6000_0000, 4),
+    (0xa000_0000..0x1_0000_0000, 4),
+];
+
+/// The block size flash regions are given in the memory map unless
+/// `IrisGdbStubBuilder::flash_block_size` says otherwise, as Iris doesn't
+/// report one
+const FLASH_BLOCK_SIZE: u64 = 0x1000;
+
+/// The 16 bit Thumb encoding of `BKPT #0`
+const BKPT: [u8; 2] = 0xbe00u16.to_le_bytes();
+
+/// What gdb's `break` sets on the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointKind {
+    /// Patch a `BKPT` instruction into memory
+    Software,
+    /// Set an Iris code breakpoint, as `hbreak` does. Needed where memory
+    /// can't be written, such as in flash
+    Hardware,
+}
+
+/// What `IrisGdbStub::end_session` leaves the model doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    /// Leave the core halted where gdb left it
+    Halt,
+    /// Run the simulation, as if gdb had never attached
+    Resume,
+    /// Reset the model, leaving the core halted at its reset handler
+    Reset,
+}
+
+/// A condition on a hardware breakpoint, checked by the stub when the
+/// breakpoint is hit, so that gdb isn't woken for the hits it would resume
+/// from anyway
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Condition {
+    pub register: Register,
+    /// Whether the register must equal `value`, rather than differ from it
+    pub equal: bool,
+    pub value: u32,
+}
+
+impl Condition {
+    /// Parse a comparison of a core register with a number, such as
+    /// `r0 == 5` or `lr != 0xfffffff9`
+    pub fn parse(text: &str) -> Option<Self> {
+        let (register, equal, value) = match text.split_once("==") {
+            Some((register, value)) => (register, true, value),
+            None => {
+                let (register, value) = text.split_once("!=")?;
+                (register, false, value)
+            }
+        };
+        Some(Self {
+            register: Register::core_by_name(register.trim())?,
+            equal,
+            value: parse_value(value.trim())?.try_into().ok()?,
+        })
+    }
+
+    pub fn holds(&self, value: u32) -> bool {
+        (value == self.value) == self.equal
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = if self.equal { "==" } else { "!=" };
+        write!(f, "{} {} {:#x}", self.register, op, self.value)
+    }
+}
+
+/// Options for an `IrisGdbStub`, checked against the model by `build`.
+pub struct IrisGdbStubBuilder<'i, B: IrisBackend + ?Sized = FastModelIris> {
+    iris: &'i mut B,
+    instance_id: u32,
+    space_id: Option<u64>,
+    follow_space: bool,
+    fpu: Option<bool>,
+    timeout: Option<Duration>,
+    watchdog: Option<Watchdog>,
+    semihosting: Option<Semihosting>,
+    breakpoint_kind: BreakpointKind,
+    signals: SignalMap,
+    endianness: Option<Endianness>,
+    device_regions: Vec<(Range<u64>, u64)>,
+    flash_block_size: u64,
+    load_offset: u32,
+    cache_registers: bool,
+    on_detach: SessionEnd,
+    on_kill: SessionEnd,
+}
+
+impl<'i, B: IrisBackend + ?Sized> IrisGdbStubBuilder<'i, B> {
+    /// What `IrisGdbStub::end_session` does after gdb detaches; by default
+    /// the core is left halted.
+    pub fn on_detach(mut self, end: SessionEnd) -> Self {
+        self.on_detach = end;
+        self
+    }
+
+    /// What `IrisGdbStub::end_session` does after gdb kills the target;
+    /// by default the core is left halted.
+    pub fn on_kill(mut self, end: SessionEnd) -> Self {
+        self.on_kill = end;
+        self
+    }
+
+    /// Keep the registers gdb reads and writes while the core is halted,
+    /// so that reading them again, or writing a register the value it
+    /// already has, costs no Iris request. Writes are held back until the
+    /// core is resumed, a monitor command runs or
+    /// `IrisGdbStub::flush_registers` is called, and the cache is dropped
+    /// whenever the core runs.
+    pub fn cache_registers(mut self) -> Self {
+        self.cache_registers = true;
+        self
+    }
+
+    /// Access memory through the space `space_id` rather than the space
+    /// the PC is in when the stub is built. On a TrustZone core this
+    /// selects between the Secure and Non-secure views of memory.
+    pub fn memory_space(mut self, space_id: u64) -> Self {
+        self.space_id = Some(space_id);
+        self
+    }
+
+    /// Move memory accesses to the space the PC is in whenever the core
+    /// stops or `monitor secstate` switches its security state, so that on
+    /// a TrustZone core gdb sees memory as the code running there does.
+    /// Until the first stop, the space given to `memory_space` is used if
+    /// there is one.
+    pub fn follow_security_state(mut self) -> Self {
+        self.follow_space = true;
+        self
+    }
+
+    /// Show gdb the VFP registers, or hide them. By default they are shown
+    /// when the core has them.
+    pub fn enable_fpu(mut self, enable: bool) -> Self {
+        self.fpu = Some(enable);
+        self
+    }
+
+    /// Stop the target and report `SIGALRM`, or the signal given by
+    /// `signal_map`, to gdb when it runs for longer than `timeout` after a
+    /// continue.
+    pub fn resume_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Stop the target and report `SIGXCPU`, or the signal given by
+    /// `signal_map`, to gdb when a continue runs
+    /// for `timeout` without the core executing an instruction, checking
+    /// its instruction count every `interval`. See `Watchdog`.
+    pub fn watchdog(mut self, timeout: Duration, interval: Duration) -> Self {
+        self.watchdog = Some(Watchdog { timeout, interval });
+        self
+    }
+
+    /// Service the semihosting calls of the firmware while it runs. See
+    /// the `semihosting` module for what the model needs for this to work.
+    pub fn semihosting(mut self, semihosting: Semihosting) -> Self {
+        self.semihosting = Some(semihosting);
+        self
+    }
+
+    /// Report stops to gdb with the signals of `signals` rather than those
+    /// of `SignalMap::default`.
+    pub fn signal_map(mut self, signals: SignalMap) -> Self {
+        self.signals = signals;
+        self
+    }
+
+    /// Choose what gdb's `break` sets; `BreakpointKind::Software` by default.
+    pub fn default_breakpoint_kind(mut self, kind: BreakpointKind) -> Self {
+        self.breakpoint_kind = kind;
+        self
+    }
+
+    /// Give registers and memory to gdb in this byte order. By default it
+    /// is the endianness the model reports for the memory space. gdb has
+    /// to be told as well, with `set endian big`, unless the file it debugs
+    /// says so.
+    pub fn endianness(mut self, endianness: Endianness) -> Self {
+        self.endianness = Some(endianness);
+        self
+    }
+
+    /// Read memory from `range` only in units of `width` bytes, widening
+    /// reads to whole aligned units. Regions given here take precedence
+    /// over each other in order, and over the Peripheral, Device and System
+    /// regions of the memory map, which are read in words by default. A
+    /// width of 1 makes a region ordinary memory again.
+    pub fn device_region(mut self, range: Range<u64>, width: u64) -> Self {
+        let at = self.device_regions.len() - DEVICE_REGIONS.len();
+        self.device_regions.insert(at, (range, width));
+        self
+    }
+
+    /// Tell gdb that flash memory is erased in blocks of `size` bytes, aligned
+    /// to the block size; 4 KiB by default.
+    pub fn flash_block_size(mut self, size: u64) -> Self {
+        self.flash_block_size = size;
+        self
+    }
+
+    /// Tell gdb that the image it debugs was loaded `offset` bytes above
+    /// its link addresses, as `image::load_elf_at` reports in
+    /// `LoadSummary::offset`, so that its symbols are moved to match.
+    pub fn load_offset(mut self, offset: u32) -> Self {
+        self.load_offset = offset;
+        self
+    }
+
+    /// Create the stub, as `build` does, and read the registers of the core
+    /// as they are. A running model is stopped first unless `leave_running`
+    /// is set. Neither this nor `build` resets or runs the model, so a model
+    /// part way through a run can be inspected where it is.
+    pub fn attach(
+        self,
+        leave_running: bool,
+    ) -> Result<(IrisGdbStub<'i, B>, GuestState), IrisError> {
+        let stub = self.build()?;
+        if !leave_running {
+            stub.iris.fast_model()?.stop(stub.sim)?;
+        }
+        let mut regs = GuestState::default();
+        read_core_registers(stub.iris, stub.instance_id, stub.has_fpu, &mut regs)?;
+        regs.endianness = stub.endianness;
+        Ok((stub, regs))
+    }
+
+    /// Create the stub. Fails if the memory space doesn't exist, or if the
+    /// FPU is enabled on a core without the VFP registers, a device region
+    /// has a unit width Iris can't read or the flash block size is 0. The
+    /// model is left as it is, running or not.
+    pub fn build(self) -> Result<IrisGdbStub<'i, B>, IrisError> {
+        let Self {
+            iris, instance_id, ..
+        } = self;
+        let sim = iris.simulation_id()?;
+        // Without breakpoint hit events a watchpoint stop can't be told
+        // apart from any other halt, so watchpoints are disabled.
+        let last_hit = iris
+            .fast_model()
+            .and_then(|iris| track_breakpoint_hits(iris, instance_id))
+            .ok();
+        let found_fpu = has_vfp(iris, instance_id)?;
+        let has_fpu = match self.fpu {
+            Some(true) if !found_fpu => {
+                return Err(IrisError::InvalidArgument(format!(
+                    "Instance {} has no VFP registers",
+                    instance_id
+                )))
+            }
+            Some(enable) => enable,
+            None => found_fpu,
+        };
+        let space_id = match self.space_id {
+            Some(space_id) => {
+                check_space(iris, instance_id, space_id)?;
+                space_id
+            }
+            None => pc_space(iris, instance_id)?,
+        };
+        if let Some((range, width)) = self
+            .device_regions
+            .iter()
+            .find(|(_, width)| !(1..=8).contains(width) || !width.is_power_of_two())
+        {
+            return Err(IrisError::InvalidArgument(format!(
+                "Units of {} bytes can't be read from {:#x}..{:#x}",
+                width, range.start, range.end
+            )));
+        }
+        if self.flash_block_size == 0 {
+            return Err(IrisError::InvalidArgument(
+                "Flash blocks can't be empty".to_string(),
+            ));
+        }
+        let regions = iris.memory_regions(instance_id, space_id)?;
+        let endianness = match self.endianness {
+            Some(endianness) => endianness,
+            None => iris
+                .memory_spaces(instance_id)?
+                .iter()
+                .find(|space| space.id == space_id)
+                .map_or(Endianness::Little, |space| space.byte_order()),
+        };
+        let banked = banked_registers(iris, instance_id)?;
+        Ok(IrisGdbStub {
             iris,
             instance_id,
             breakpoints: HashMap::new(),
-            sim: sim.id,
+            conditions: HashMap::new(),
+            sw_breakpoints: HashMap::new(),
+            watchpoints: HashMap::new(),
+            last_hit,
+            space_id,
+            follow_space: self.follow_space,
+            timeout: self.timeout,
+            watchdog: self.watchdog,
+            semihosting: self.semihosting,
+            signals: self.signals,
+            breakpoint_kind: self.breakpoint_kind,
+            has_fpu,
+            resume: Resume::Continue,
+            endianness,
+            device_regions: self.device_regions,
+            load_offset: self.load_offset,
+            register_cache: self.cache_registers.then(RegisterCache::default),
+            observer: None,
+            on_detach: self.on_detach,
+            on_kill: self.on_kill,
+            target_xml: target_xml(has_fpu, &banked),
+            memory_map: memory_map_xml(&regions, self.flash_block_size),
+            flash: flash_ranges(&regions),
+            flash_block_size: self.flash_block_size,
+            sim,
         })
     }
 }
 
-impl Registers for GuestState {
-    type ProgramCounter = u32;
-    fn pc(&self) -> u32 {
-        self.regs[15]
+impl<'i, B: IrisBackend + ?Sized> IrisGdbStub<'i, B> {
+    /// Create a stub with the default options; see `builder` for the rest.
+    pub fn from_instance(iris: &'i mut B, instance_id: u32) -> Result<Self, IrisError> {
+        Self::builder(iris, instance_id).build()
+    }
+
+    pub fn builder(iris: &'i mut B, instance_id: u32) -> IrisGdbStubBuilder<'i, B> {
+        IrisGdbStubBuilder {
+            iris,
+            instance_id,
+            space_id: None,
+            follow_space: false,
+            fpu: None,
+            timeout: None,
+            watchdog: None,
+            semihosting: None,
+            breakpoint_kind: BreakpointKind::Software,
+            signals: SignalMap::default(),
+            endianness: None,
+            device_regions: DEVICE_REGIONS.to_vec(),
+            flash_block_size: FLASH_BLOCK_SIZE,
+            load_offset: 0,
+            cache_registers: false,
+            on_detach: SessionEnd::Halt,
+            on_kill: SessionEnd::Halt,
+        }
     }
-    fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
-        for (num, reg) in self.regs.iter().enumerate() {
-            for byte in reg.to_le_bytes().iter() {
-                write_byte(Some(*byte));
+
+    /// The disassembly mode matching the T bit of XPSR, when the model has
+    /// one. An M-profile core only executes Thumb code, so a clear T bit
+    /// means the next instruction will fault rather than run as Arm code.
+    fn disasm_mode(&mut self) -> Result<Option<String>, IrisError> {
+        let xpsr = read_resource(self.iris, self.instance_id, "XPSR")?.unwrap_or(0);
+        let names: &[&str] = if xpsr & (1 << 24) != 0 {
+            &["Thumb", "T32"]
+        } else {
+            &["ARM", "A32"]
+        };
+        Ok(disasm::modes(self.iris.fast_model()?, self.instance_id)?
+            .into_iter()
+            .map(|mode| mode.name)
+            .find(|name| names.contains(&name.as_str())))
+    }
+
+    /// Whether the core halted on a hardware breakpoint whose condition is
+    /// false. The breakpoint's instruction is then stepped over, so that
+    /// the caller can run on from it.
+    fn skip_hit(&mut self) -> Result<bool, IrisError> {
+        let hit = self.last_hit.as_ref().and_then(|hit| hit.get());
+        if hit.is_some_and(|hit| self.watchpoints.contains_key(&hit.id)) {
+            return Ok(false);
+        }
+        let pc = match self.read_pc()? {
+            Some(pc) if self.breakpoints.contains_key(&pc) => pc,
+            _ => return Ok(false),
+        };
+        let condition = match self.conditions.get(&pc) {
+            Some(&condition) => condition,
+            None => return Ok(false),
+        };
+        let name = &condition.register.iris_names()[0];
+        let value = match read_resource(self.iris, self.instance_id, name)? {
+            Some(value) => condition.register.gdb_value(value as u32),
+            // A condition that can't be checked stops, like gdb's own
+            None => return Ok(false),
+        };
+        if condition.holds(value) {
+            return Ok(false);
+        }
+        step::instruction(self.iris.fast_model()?, self.instance_id, 1)?;
+        Ok(true)
+    }
+
+    /// Work out why the core halted, once it has stopped after a step or a
+    /// continue that gdb did not interrupt.
+    fn find_stop_reason(&mut self, stepping: bool) -> Result<StopReason<u32>, IrisError> {
+        let hit = self.last_hit.as_ref().and_then(|hit| hit.get());
+        if let Some(hit) = hit {
+            if let Some(&(addr, kind)) = self.watchpoints.get(&hit.id) {
+                // gdb is told the address accessed, which may lie anywhere
+                // in the watched range
+                let addr = hit.access_addr.map_or(addr, |access| access as u32);
+                return Ok(StopReason::Watch {
+                    tid: (),
+                    kind,
+                    addr,
+                });
             }
-            // Registers above 16 and below 24 are assumed to be 96 bit by gdb.
-            // So we pad them
-            if num >= 16 && num < 24 {
-                for _ in 0..8 {
-                    write_byte(Some(0));
-                }
+        }
+        if hit.is_some_and(|hit| self.breakpoints.values().any(|&id| id == hit.id)) {
+            return Ok(StopReason::HwBreak(()));
+        }
+        if stepping {
+            // The PC is expected to land anywhere after a step, so only a
+            // hit event tells a breakpoint apart from the end of the step
+            return Ok(StopReason::DoneStep);
+        }
+        match self.read_pc()? {
+            Some(pc) if self.breakpoints.contains_key(&pc) => return Ok(StopReason::HwBreak(())),
+            Some(pc) if self.sw_breakpoints.contains_key(&pc) => {
+                return Ok(StopReason::SwBreak(()))
+            }
+            _ => {}
+        }
+        // The exception number in IPSR says whether the core is handling a
+        // fault
+        let xpsr = read_resource(self.iris, self.instance_id, "XPSR")?.unwrap_or(0);
+        let exception = match xpsr & 0x1ff {
+            exception @ 3..=6 => exception as u32,
+            _ => return Ok(StopReason::Signal(Signal(self.signals.other))),
+        };
+        // The whole decoded fault is left to `monitor fault`
+        let fault = FaultInfo::read(self.iris, self.instance_id)?;
+        let signal = self.signals.for_fault(exception, fault.as_ref());
+        Ok(StopReason::Signal(Signal(signal)))
+    }
+
+    /// Call `observer` with every step, breakpoint or watchpoint hit,
+    /// register access and memory access gdb makes, for tools following
+    /// the session. Unlike `FastModelIris::set_trace`, this describes what
+    /// gdb did rather than the Iris messages it took. Without an observer
+    /// no event is built.
+    pub fn set_observer(&mut self, observer: impl FnMut(TraceEvent) + 'static) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Stop calling the observer.
+    pub fn clear_observer(&mut self) {
+        self.observer = None;
+    }
+
+    /// Pass the event built by `event` to the observer, if there is one
+    fn notify(&mut self, event: impl FnOnce() -> TraceEvent) {
+        if let Some(observer) = &mut self.observer {
+            observer(event());
+        }
+    }
+
+    /// Work out why the core halted, as `find_stop_reason` does, and tell
+    /// the observer about steps and hits.
+    fn stop_reason(&mut self, stepping: bool) -> Result<StopReason<u32>, IrisError> {
+        self.follow_pc_space()?;
+        let reason = self.find_stop_reason(stepping)?;
+        if self.observer.is_none() {
+            return Ok(reason);
+        }
+        let event = match reason {
+            StopReason::Watch { addr, .. } => Some(TraceEvent::Watchpoint { addr }),
+            StopReason::DoneStep => self.read_pc()?.map(|pc| TraceEvent::Step {
+                pc,
+                unit: Unit::Instruction,
+            }),
+            StopReason::HwBreak(()) | StopReason::SwBreak(()) => {
+                self.read_pc()?.map(|pc| TraceEvent::Breakpoint { pc })
             }
+            _ => None,
+        };
+        if let Some(event) = event {
+            self.notify(|| event);
         }
+        Ok(reason)
     }
-    fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
-        if bytes.len() % 4 != 0 {
-            return Err(());
+
+    /// Write the registers gdb changed in the register cache to the model.
+    /// Does nothing without a cache, or when nothing changed.
+    pub fn flush_registers(&mut self) -> Result<(), IrisError> {
+        if let Some(cache) = &mut self.register_cache {
+            if let (Some(state), true) = (&cache.state, cache.dirty) {
+                write_core_registers(self.iris, self.instance_id, state)?;
+                cache.dirty = false;
+            }
         }
-        let mut regs = bytes
-            .chunks_exact(4)
-            .map(|c| u32::from_le_bytes(c.try_into().unwrap()));
-        for reg in &mut self.regs {
-            *reg = regs.next().ok_or(())?;
+        Ok(())
+    }
+
+    /// Move memory accesses to the space the PC is in, when the stub
+    /// follows the security state.
+    fn follow_pc_space(&mut self) -> Result<(), IrisError> {
+        if self.follow_space {
+            self.space_id = pc_space(self.iris, self.instance_id)?;
+        }
+        Ok(())
+    }
+
+    /// Forget the registers in the register cache, so they are read from
+    /// the model again. Changes not yet written with `flush_registers` are
+    /// lost.
+    pub fn invalidate_registers(&mut self) {
+        if let Some(cache) = &mut self.register_cache {
+            *cache = RegisterCache::default();
+        }
+    }
+
+    /// Write the cached registers to the model before it runs or is
+    /// changed behind the cache, and drop them, as they may change.
+    fn sync_registers(&mut self) -> Result<(), IrisError> {
+        self.flush_registers()?;
+        self.invalidate_registers();
+        Ok(())
+    }
+
+    /// The words of `reg` in the register cache, least significant first
+    fn cached_register(&self, reg: Register) -> Option<Vec<u32>> {
+        let state = self.register_cache.as_ref()?.state.as_ref()?;
+        match reg {
+            Register::D(n) => state
+                .fpu
+                .map(|fpu| fpu[2 * n as usize..2 * n as usize + 2].to_vec()),
+            reg => state.get(reg).map(|value| vec![value]),
+        }
+    }
+
+    /// Put `words` in the register cache as the new value of `reg`, and
+    /// mark it to be written. Returns `false` when the cache doesn't hold
+    /// the register.
+    fn cache_register(&mut self, reg: Register, words: &[u32]) -> bool {
+        let cache = match &mut self.register_cache {
+            Some(cache) => cache,
+            None => return false,
+        };
+        let state = match &mut cache.state {
+            Some(state) => state,
+            None => return false,
+        };
+        let stored = match (reg, &mut state.fpu) {
+            (Register::D(n), Some(fpu)) => {
+                fpu[2 * n as usize..2 * n as usize + 2].copy_from_slice(words);
+                true
+            }
+            (Register::D(_), None) => false,
+            (reg, _) => state.set(reg, words[0]),
+        };
+        cache.dirty |= stored;
+        stored
+    }
+
+    /// Reset the model and leave the core halted at the entry of its reset
+    /// handler, with the stack pointer taken from the vector table, as if
+    /// it had just come out of reset.
+    fn reset_to_vector(&mut self) -> Result<(), IrisError> {
+        self.iris.fast_model()?.reset(self.sim, ResetKind::Cold)?;
+        let vtor = read_resource(self.iris, self.instance_id, "VTOR")?.unwrap_or(0);
+        let mut table = [0u8; 8];
+        let valid = read_memory(
+            self.iris,
+            self.instance_id,
+            self.space_id,
+            vtor,
+            &mut table,
+            self.endianness,
+        )?;
+        if valid < table.len() {
+            return Err(IrisError::MemoryFault(vtor));
+        }
+        let sp = self
+            .endianness
+            .u32_from_bytes(table[..4].try_into().unwrap());
+        // The low bit of the entry only marks it as Thumb code
+        let entry = self
+            .endianness
+            .u32_from_bytes(table[4..].try_into().unwrap())
+            & !1;
+        if self.read_pc()? == Some(entry) {
+            return Ok(());
+        }
+        let (ids, values) = self
+            .iris
+            .resource_list(self.instance_id)?
+            .iter()
+            .filter_map(|res| match res.name.as_str() {
+                "R13" => Some((res.id, u64::from(sp))),
+                "R15" => Some((res.id, u64::from(entry))),
+                _ => None,
+            })
+            .unzip();
+        self.iris.write_resources(self.instance_id, ids, values)
+    }
+
+    /// Save a checkpoint of the model to `dir`. The instructions under
+    /// software breakpoints are put back for the save, so the checkpoint
+    /// holds the firmware rather than the `BKPT`s.
+    fn save_checkpoint(&mut self, dir: &Path) -> Result<(), IrisError> {
+        for (&addr, (space, orig)) in &self.sw_breakpoints {
+            self.iris
+                .write_memory(self.instance_id, *space, u64::from(addr), 2, orig)?;
+        }
+        let sim = self.sim;
+        let saved = self
+            .iris
+            .fast_model()
+            .and_then(|iris| iris.save_state(sim, dir));
+        for (&addr, &(space, _)) in &self.sw_breakpoints {
+            self.iris
+                .write_memory(self.instance_id, space, u64::from(addr), 2, &BKPT)?;
+        }
+        saved
+    }
+
+    /// Restore the model from the checkpoint in `dir`, and patch the
+    /// software breakpoints into the restored memory again. Iris keeps the
+    /// hardware breakpoints and watchpoints itself.
+    fn restore_checkpoint(&mut self, dir: &Path) -> Result<(), IrisError> {
+        self.iris.fast_model()?.restore_state(self.sim, dir)?;
+        for (&addr, (space, orig)) in self.sw_breakpoints.iter_mut() {
+            let (addr, space) = (u64::from(addr), *space);
+            let current = self.iris.read_memory(self.instance_id, space, addr, 2, 1)?;
+            match current.data.first() {
+                Some(&unit) if current.error.is_none() => *orig = (unit as u16).to_le_bytes(),
+                _ => return Err(IrisError::MemoryFault(addr)),
+            }
+            self.iris
+                .write_memory(self.instance_id, space, addr, 2, &BKPT)?;
         }
         Ok(())
     }
+
+    /// Read the program counter of the core
+    fn read_pc(&mut self) -> Result<Option<u32>, IrisError> {
+        Ok(read_resource(self.iris, self.instance_id, "R15")?
+            .map(|pc| Register::PC.gdb_value(pc as u32)))
+    }
+
+    /// Read the word at `addr`, or `None` if it faults
+    fn read_word(&mut self, addr: u32) -> Result<Option<u32>, IrisError> {
+        let mut word = [0u8; 4];
+        let valid = read_memory(
+            self.iris,
+            self.instance_id,
+            self.space_id,
+            u64::from(addr),
+            &mut word,
+            self.endianness,
+        )?;
+        Ok((valid == word.len()).then(|| self.endianness.u32_from_bytes(word)))
+    }
+
+    /// The address of the vector table, from VTOR, and its first `count`
+    /// entries, or as many as the core has interrupts for. VTOR is read
+    /// from the System Control Block when the model has no resource for it,
+    /// so a table moved to RAM is found either way. The entries stop early
+    /// where memory can't be read.
+    fn vector_table(&mut self, count: Option<usize>) -> Result<(u32, Vec<u32>), IrisError> {
+        let vtor = match read_resource(self.iris, self.instance_id, "VTOR")? {
+            Some(vtor) => vtor as u32,
+            None => self.read_word(SCB_VTOR)?.unwrap_or(0),
+        };
+        let count = match count {
+            Some(count) => count,
+            // 16 system exceptions, then 32 interrupts for each INTLINESNUM
+            None => match self.read_word(NVIC_ICTR)? {
+                Some(ictr) => 16 + 32 * ((ictr & 0xf) as usize + 1),
+                None => 16,
+            },
+        };
+        let mut table = vec![0u8; count * 4];
+        let valid = read_memory(
+            self.iris,
+            self.instance_id,
+            self.space_id,
+            u64::from(vtor),
+            &mut table,
+            self.endianness,
+        )?;
+        let entries = table[..valid - valid % 4]
+            .chunks_exact(4)
+            .map(|entry| self.endianness.u32_from_bytes(entry.try_into().unwrap()))
+            .collect();
+        Ok((vtor, entries))
+    }
+
+    /// A best effort backtrace of at most `depth` frames, as each frame's
+    /// address and a note on how it was found. Without debug information
+    /// the callers are found by following the frame pointer, R7 in Thumb
+    /// code, which holds the caller's R7 and LR at [R7] and [R7 + 4], and
+    /// the exception frames the core stacks on exception entry, on the
+    /// stack EXC_RETURN names. Code built without frame pointers ends the
+    /// walk early or skips frames.
+    fn backtrace(&mut self, depth: usize) -> Result<Vec<(u32, Option<String>)>, IrisError> {
+        let read = |stub: &mut Self, name| read_resource(stub.iris, stub.instance_id, name);
+        let mut frames = match self.read_pc()? {
+            Some(pc) => vec![(pc, None)],
+            None => return Ok(Vec::new()),
+        };
+        let mut lr = read(self, "R14")?.unwrap_or(0) as u32;
+        let mut fp = read(self, "R7")?.unwrap_or(0) as u32;
+        let mut from_fp = false;
+        while frames.len() < depth {
+            if lr >= 0xffff_ff00 {
+                // EXC_RETURN: bit 2 says which stack the frame is on
+                let (name, stack) = if lr & 4 != 0 {
+                    ("PSP", "process")
+                } else {
+                    ("MSP", "main")
+                };
+                let sp = match read(self, name)? {
+                    Some(sp) => sp as u32,
+                    None => break,
+                };
+                // R0-R3, R12, LR, the return address and XPSR
+                let (ret, stacked_lr) = match (
+                    self.read_word(sp.wrapping_add(24))?,
+                    self.read_word(sp.wrapping_add(20))?,
+                ) {
+                    (Some(ret), Some(stacked_lr)) => (ret, stacked_lr),
+                    _ => break,
+                };
+                frames.push((
+                    ret & !1,
+                    Some(format!("exception frame on the {} stack", stack)),
+                ));
+                lr = stacked_lr;
+                from_fp = false;
+                continue;
+            }
+            if lr < 2 {
+                break;
+            }
+            let addr = lr & !1;
+            // A non-leaf function saves the LR it was called with in its
+            // frame, so the frame pointer may find the same address again
+            if !(from_fp && frames.last().map(|&(last, _)| last) == Some(addr)) {
+                frames.push((addr, from_fp.then(|| "frame pointer".to_string())));
+            }
+            if fp == 0 {
+                break;
+            }
+            let (saved_fp, saved_lr) =
+                match (self.read_word(fp)?, self.read_word(fp.wrapping_add(4))?) {
+                    (Some(saved_fp), Some(saved_lr)) => (saved_fp, saved_lr),
+                    _ => break,
+                };
+            // The stack grows down, so callers' frames are above; anything
+            // else is not a frame
+            if saved_fp != 0 && saved_fp <= fp {
+                break;
+            }
+            from_fp = true;
+            lr = saved_lr;
+            fp = saved_fp;
+        }
+        frames.truncate(depth);
+        Ok(frames)
+    }
+}
+
+/// The Vector Table Offset Register, in the System Control Block
+const SCB_VTOR: u32 = 0xe000_ed08;
+
+/// The Interrupt Controller Type Register, giving the number of interrupts
+const NVIC_ICTR: u32 = 0xe000_e004;
+
+/// How many frames `monitor backtrace` shows unless told otherwise
+const BACKTRACE_DEPTH: usize = 16;
+
+/// Whether the core has the VFP registers
+pub(crate) fn has_vfp<B: IrisBackend + ?Sized>(
+    iris: &mut B,
+    instance_id: u32,
+) -> Result<bool, IrisError> {
+    Ok(iris
+        .resource_list(instance_id)?
+        .iter()
+        .any(|res| res.name == "FPSCR"))
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum Register {
-    R0,
-    R1,
-    R2,
-    R3,
-    R4,
-    R5,
-    R6,
-    R7,
-    R8,
-    R9,
-    R10,
-    R11,
-    R12,
-    SP,
-    LR,
-    PC,
-    XPSR,
-}
-
-impl RegId for Register {
-    fn from_raw_id(id: usize) -> Option<(Self, usize)> {
-        use Register::*;
-        Some(match id {
-            0 => R0,
-            1 => R1,
-            2 => R2,
-            3 => R3,
-            4 => R4,
-            5 => R5,
-            6 => R6,
-            7 => R7,
-            8 => R8,
-            9 => R9,
-            10 => R10,
-            11 => R11,
-            12 => R12,
-            13 => SP,
-            14 => LR,
-            15 => PC,
-            25 => XPSR,
-            _ => return None,
+/// The indices within `BANKED_REGISTERS` of the stack pointers the core
+/// has
+pub(crate) fn banked_registers<B: IrisBackend + ?Sized>(
+    iris: &mut B,
+    instance_id: u32,
+) -> Result<Vec<u8>, IrisError> {
+    let resources = iris.resource_list(instance_id)?;
+    Ok((0..BANKED_REGISTERS.len() as u8)
+        .filter(|&n| {
+            let name = BANKED_REGISTERS[n as usize].1;
+            resources.iter().any(|res| res.name == name)
         })
-        .map(|r| (r, 0))
+        .collect())
+}
+
+/// The target description for a core, with the VFP registers if `has_fpu`
+/// and the stack pointers of `banked`, indices within `BANKED_REGISTERS`.
+/// gdb only shows the Secure and Non-secure banks when it's given all four.
+pub(crate) fn target_xml(has_fpu: bool, banked: &[u8]) -> String {
+    let mut target_xml = TARGET_XML_CORE.to_string();
+    if has_fpu {
+        target_xml.push_str(TARGET_XML_VFP);
+    }
+    let features = [
+        ("org.gnu.gdb.arm.m-system", 0..2),
+        ("org.gnu.gdb.arm.secext", 2..6),
+    ];
+    for (feature, range) in features.iter() {
+        let regs: Vec<u8> = range.clone().filter(|n| banked.contains(n)).collect();
+        if regs.is_empty() || (range.start > 0 && regs.len() < range.len()) {
+            continue;
+        }
+        target_xml.push_str(&format!("  <feature name=\"{}\">\n", feature));
+        for n in regs {
+            let regnum = BANKED_REGNUM + n as usize;
+            target_xml.push_str(&format!(
+                "    <reg name=\"{}\" bitsize=\"32\" regnum=\"{}\" type=\"data_ptr\" group=\"system\"/>\n",
+                Register::Banked(n),
+                regnum
+            ));
+        }
+        target_xml.push_str("  </feature>\n");
+    }
+    target_xml.push_str("</target>\n");
+    target_xml
+}
+
+impl GuestState {
+    /// Read the registers of an Armv7-M core in one request, with the VFP
+    /// registers when the core has them. The PC reads as gdb sees it,
+    /// halfword aligned.
+    pub fn from_iris<B: IrisBackend + ?Sized>(
+        iris: &mut B,
+        instance_id: u32,
+    ) -> Result<Self, IrisError> {
+        let mut regs = GuestState::default();
+        let has_fpu = has_vfp(iris, instance_id)?;
+        read_core_registers(iris, instance_id, has_fpu, &mut regs)?;
+        Ok(regs)
+    }
+}
+
+/// Read the registers of a core, including the VFP registers if `has_fpu`.
+pub(crate) fn read_core_registers<B: IrisBackend + ?Sized>(
+    iris: &mut B,
+    instance_id: u32,
+    has_fpu: bool,
+    regs: &mut GuestState,
+) -> Result<(), IrisError> {
+    if has_fpu {
+        regs.fpu = Some([0; 33]);
+    }
+    let resources = iris.resource_list(instance_id)?;
+    let wanted: Vec<_> = resources
+        .iter()
+        .filter(|res| {
+            register_index(&res.name).is_some()
+                || (has_fpu && fpu_register_index(&res.name).is_some())
+        })
+        .collect();
+    let values = iris.read_resources(instance_id, &wanted)?;
+    for res in wanted {
+        let value = match values.get(&res.id) {
+            Some(words) => narrow(&res.name, words),
+            None => continue,
+        };
+        match (register_index(&res.name), &mut regs.fpu) {
+            (Some(regnum), _) => regs.regs[regnum] = CORE_REGISTERS[regnum].0.gdb_value(value),
+            (None, Some(fpu)) => {
+                if let Some(regnum) = fpu_register_index(&res.name) {
+                    fpu[regnum] = value
+                }
+            }
+            (None, None) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Write the registers of a core, including the VFP registers if `regs`
+/// has them.
+pub(crate) fn write_core_registers<B: IrisBackend + ?Sized>(
+    iris: &mut B,
+    instance_id: u32,
+    regs: &GuestState,
+) -> Result<(), IrisError> {
+    let xpsr = merge_xpsr(iris, instance_id, regs.regs[XPSR_INDEX])?;
+    let (ids, values) = iris
+        .resource_list(instance_id)?
+        .iter()
+        .filter_map(|res| {
+            let value = match (register_index(&res.name), &regs.fpu) {
+                (Some(XPSR_INDEX), _) => xpsr,
+                (Some(regnum), _) => CORE_REGISTERS[regnum].0.iris_value(regs.regs[regnum]),
+                (None, Some(fpu)) => fpu[fpu_register_index(&res.name)?],
+                (None, None) => return None,
+            };
+            Some((res.id, u64::from(value)))
+        })
+        .unzip();
+    iris.write_resources(instance_id, ids, values)
+}
+
+/// The bits of XPSR that gdb may change: the APSR flags N, Z, C, V and Q
+/// (31-27) and GE (19-16), and the EPSR execution state, IT/ICI (26-25 and
+/// 15-10) and T (24). The exception number in IPSR (8-0) and the reserved
+/// bits are kept as they are, since the model rejects changes to them.
+const XPSR_WRITABLE: u32 = 0xff0f_fc00;
+
+/// The index of XPSR within `GuestState::regs`, the last of `CORE_REGISTERS`
+const XPSR_INDEX: usize = CORE_REGISTERS.len() - 1;
+
+/// Describe the execution context given by the exception number in the IPSR
+/// bits of `xpsr`, such as "Thread mode" or "Handler mode, IRQ 3".
+pub fn exception_context(xpsr: u32) -> String {
+    match xpsr & 0x1ff {
+        0 => "Thread mode".to_string(),
+        n => format!("Handler mode, {}", exception_name(n)),
     }
 }
 
-impl<'i> Target for IrisGdbStub<'i> {
+/// The name of exception number `n`, such as "HardFault" or "IRQ 3"
+pub fn exception_name(n: u32) -> String {
+    match n {
+        0 => "Thread mode".to_string(),
+        1 => "Reset".to_string(),
+        2 => "NMI".to_string(),
+        3 => "HardFault".to_string(),
+        4 => "MemManage".to_string(),
+        5 => "BusFault".to_string(),
+        6 => "UsageFault".to_string(),
+        7 => "SecureFault".to_string(),
+        11 => "SVCall".to_string(),
+        12 => "DebugMonitor".to_string(),
+        14 => "PendSV".to_string(),
+        15 => "SysTick".to_string(),
+        n if n >= 16 => format!("IRQ {}", n - 16),
+        n => format!("reserved exception {}", n),
+    }
+}
+
+/// The value to write to XPSR for gdb to set it to `value`, keeping the bits
+/// gdb can't change at their current value.
+fn merge_xpsr<B: IrisBackend + ?Sized>(
+    iris: &mut B,
+    instance_id: u32,
+    value: u32,
+) -> Result<u32, IrisError> {
+    let current = read_resource(iris, instance_id, "XPSR")?
+        .ok_or_else(|| IrisError::ResourceNotFound("XPSR".to_string()))? as u32;
+    Ok((value & XPSR_WRITABLE) | (current & !XPSR_WRITABLE))
+}
+
+/// The low 32 bits of a register value read from Iris. Models may report the
+/// registers of a 32 bit core as wider than they are; a warning is logged if
+/// that hides any set bits.
+fn narrow(name: &str, words: &[u64]) -> u32 {
+    let low = words.first().copied().unwrap_or(0);
+    if low > u64::from(u32::MAX) || words.iter().skip(1).any(|&word| word != 0) {
+        eprintln!(
+            "Warning: {} has bits set above bit 31, which gdb won't see",
+            name
+        );
+    }
+    low as u32
+}
+
+/// The addresses of `region` within the 32 bit address space, or `None`
+/// when it lies above it
+fn address_range(region: &MemRegion) -> Option<Range<u64>> {
+    const END: u64 = 1 << 32;
+    let end = region
+        .size
+        .map_or(END, |size| region.base.saturating_add(size).min(END));
+    (region.base < end).then_some(region.base..end)
+}
+
+/// The address ranges of the flash regions among `regions`
+fn flash_ranges(regions: &[MemRegion]) -> Vec<Range<u64>> {
+    regions
+        .iter()
+        .filter(|region| region.kind == MemKind::Flash)
+        .filter_map(address_range)
+        .collect()
+}
+
+/// Whether `range` lies within one of the flash regions of `flash`, and
+/// starts and ends on a boundary between blocks of `block_size` bytes
+fn is_flash_blocks(flash: &[Range<u64>], block_size: u64, range: &Range<u64>) -> bool {
+    range.start.is_multiple_of(block_size)
+        && range.end.is_multiple_of(block_size)
+        && flash
+            .iter()
+            .any(|region| region.start <= range.start && range.end <= region.end)
+}
+
+/// The memory map gdb is sent for `regions`, with flash erased in blocks of
+/// `flash_block_size` bytes. gdb has `break` set hardware breakpoints
+/// outside RAM, and refuses to access addresses the map leaves out. Device
+/// and unknown memory are given as RAM, as gdb has no other kind, and
+/// memory that can't be written as ROM. The parts of regions above the 32
+/// bit address space are left out.
+pub(crate) fn memory_map_xml(regions: &[MemRegion], flash_block_size: u64) -> String {
+    let mut xml = MEMORY_MAP_HEADER.to_string();
+    for region in regions {
+        let (start, length) = match address_range(region) {
+            Some(range) => (range.start, range.end - range.start),
+            None => continue,
+        };
+        let kind = match region.kind {
+            MemKind::Flash => {
+                xml.push_str(&format!(
+                    "  <memory type=\"flash\" start=\"{:#x}\" length=\"{:#x}\">\n    <property name=\"blocksize\">{:#x}</property>\n  </memory>\n",
+                    start, length, flash_block_size
+                ));
+                continue;
+            }
+            _ if !region.access.write => "rom",
+            _ => "ram",
+        };
+        xml.push_str(&format!(
+            "  <memory type=\"{}\" start=\"{:#x}\" length=\"{:#x}\"/>\n",
+            kind, start, length
+        ));
+    }
+    xml.push_str("</memory-map>\n");
+    xml
+}
+
+/// Map the name of an Iris resource to its index within `GuestState::regs`
+fn register_index(name: &str) -> Option<usize> {
+    CORE_REGISTERS.iter().position(|(_, iris)| *iris == name)
+}
+
+/// Map the name of an Iris resource to its index within `GuestState::fpu`
+fn fpu_register_index(name: &str) -> Option<usize> {
+    match name {
+        "FPSCR" => Some(32),
+        x if x.starts_with('S') => match x[1..].parse() {
+            Ok(regnum) if regnum < 32 => Some(regnum),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Take out the breakpoints and watchpoints gdb left behind, so the next
+/// session starts clean. Failures are ignored, as the model may be gone.
+impl<B: IrisBackend + ?Sized> Drop for IrisGdbStub<'_, B> {
+    fn drop(&mut self) {
+        let _ = self.flush_registers();
+        self.remove_breakpoints();
+    }
+}
+
+impl<B: IrisBackend + ?Sized> IrisGdbStub<'_, B> {
+    /// Leave the model as configured with `IrisGdbStubBuilder::on_detach`
+    /// or `on_kill`, once `GdbStub::run_blocking` has returned with
+    /// `reason`. gdbstub handles the detach packet itself, without telling
+    /// the target, and a kill only ends the session, so this is the stub's
+    /// chance to act on them. The breakpoints gdb left behind are taken
+    /// out first, so that a resumed model doesn't stop on them with no
+    /// debugger attached.
+    pub fn end_session(&mut self, reason: &DisconnectReason) -> Result<(), IrisError> {
+        self.flush_registers()?;
+        self.remove_breakpoints();
+        let end = match reason {
+            DisconnectReason::Disconnect => self.on_detach,
+            DisconnectReason::Kill => self.on_kill,
+            _ => SessionEnd::Halt,
+        };
+        self.invalidate_registers();
+        match end {
+            SessionEnd::Halt => Ok(()),
+            SessionEnd::Resume => self.iris.fast_model()?.run(self.sim),
+            SessionEnd::Reset => self.reset_to_vector(),
+        }
+    }
+
+    /// Take out the breakpoints and watchpoints gdb set. Failures are
+    /// ignored, as the model may be gone.
+    fn remove_breakpoints(&mut self) {
+        for (_, id) in self.breakpoints.drain() {
+            let _ = self.iris.delete_breakpoint(self.instance_id, id);
+        }
+        for (id, _) in self.watchpoints.drain() {
+            let _ = self.iris.delete_breakpoint(self.instance_id, id);
+        }
+        for (addr, (space, orig)) in self.sw_breakpoints.drain() {
+            let _ = self
+                .iris
+                .write_memory(self.instance_id, space, u64::from(addr), 2, &orig);
+        }
+    }
+}
+
+impl<'i, B: IrisBackend + ?Sized> Target for IrisGdbStub<'i, B> {
     type Arch = Armv7mArch;
     type Error = ();
     fn base_ops(&mut self) -> BaseOps<'_, Self::Arch, Self::Error> {
         BaseOps::SingleThread(self)
     }
 
-    fn breakpoints(&mut self) -> Option<BreakpointsOps<Self>> {
+    fn support_breakpoints(&mut self) -> Option<BreakpointsOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_monitor_cmd(&mut self) -> Option<MonitorCmdOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_extended_mode(&mut self) -> Option<ExtendedModeOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_target_description_xml_override(
+        &mut self,
+    ) -> Option<TargetDescriptionXmlOverrideOps<'_, Self>> {
         Some(self)
     }
 
-    fn monitor_cmd(&mut self) -> Option<MonitorCmdOps<Self>> {
+    fn support_section_offsets(&mut self) -> Option<SectionOffsetsOps<'_, Self>> {
         Some(self)
     }
+
+    fn support_memory_map(&mut self) -> Option<MemoryMapOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_flash_operations(&mut self) -> Option<FlashOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+/// gdb's `load` into the flash regions of the memory map. Iris has no
+/// request for programming flash, so flash is erased and written with
+/// debug accesses, which models let through to their flash.
+impl<B: IrisBackend + ?Sized> Flash for IrisGdbStub<'_, B> {
+    /// Fails unless the range is whole blocks of one flash region. Erased
+    /// flash reads as all ones, so that is what the blocks are filled with.
+    fn flash_erase(&mut self, start_addr: u32, length: u32) -> TargetResult<(), Self> {
+        let start = u64::from(start_addr);
+        let range = start..start + u64::from(length);
+        if !is_flash_blocks(&self.flash, self.flash_block_size, &range) {
+            return Err(TargetError::NonFatal);
+        }
+        let erased = vec![0xff; self.flash_block_size as usize];
+        for block in range.step_by(self.flash_block_size as usize) {
+            write_memory(
+                self.iris,
+                self.instance_id,
+                self.space_id,
+                block,
+                &erased,
+                self.endianness,
+            )
+            .map_err(report)?;
+        }
+        Ok(())
+    }
+
+    /// Fails unless all of `data` goes to one flash region
+    fn flash_write(&mut self, start_addr: u32, data: &[u8]) -> TargetResult<(), Self> {
+        let start = u64::from(start_addr);
+        let end = start + data.len() as u64;
+        if !is_flash_blocks(&self.flash, 1, &(start..end)) {
+            return Err(TargetError::NonFatal);
+        }
+        self.notify(|| TraceEvent::MemoryWritten {
+            addr: start_addr,
+            data: data.to_vec(),
+        });
+        write_memory(
+            self.iris,
+            self.instance_id,
+            self.space_id,
+            start,
+            data,
+            self.endianness,
+        )
+        .map_err(report)?;
+        Ok(())
+    }
+
+    /// Each write has already reached the model
+    fn flash_done(&mut self) -> TargetResult<(), Self> {
+        Ok(())
+    }
+}
+
+impl<B: IrisBackend + ?Sized> MemoryMap for IrisGdbStub<'_, B> {
+    fn memory_map_xml(
+        &self,
+        offset: u64,
+        length: usize,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        Ok(copy_range(self.memory_map.as_bytes(), offset, length, buf))
+    }
+}
+
+impl<B: IrisBackend + ?Sized> SectionOffsets for IrisGdbStub<'_, B> {
+    /// The whole image moves together, so text, data and bss share the
+    /// offset
+    fn get_section_offsets(&mut self) -> Result<Offsets<u32>, ()> {
+        Ok(Offsets::Sections {
+            text: self.load_offset,
+            data: self.load_offset,
+            bss: Some(self.load_offset),
+        })
+    }
+}
+
+impl<B: IrisBackend + ?Sized> TargetDescriptionXmlOverride for IrisGdbStub<'_, B> {
+    fn target_description_xml(
+        &self,
+        _annex: &[u8],
+        offset: u64,
+        length: usize,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        Ok(copy_range(self.target_xml.as_bytes(), offset, length, buf))
+    }
+}
+
+impl<B: IrisBackend + ?Sized> IrisGdbStub<'_, B> {
+    /// The Iris resource ids holding `reg`, least significant word first.
+    /// Fails when the core doesn't have the register.
+    fn register_resources(&mut self, reg: Register) -> TargetResult<Vec<u64>, Self> {
+        if reg.is_fpu() && !self.has_fpu {
+            return Err(TargetError::NonFatal);
+        }
+        let resources = self.iris.resource_list(self.instance_id).map_err(report)?;
+        reg.iris_names()
+            .iter()
+            .map(|name| {
+                resources
+                    .iter()
+                    .find(|res| res.name == *name)
+                    .map(|res| res.id)
+                    .ok_or(TargetError::NonFatal)
+            })
+            .collect()
+    }
+}
+
+impl<B: IrisBackend + ?Sized> SingleRegisterAccess<()> for IrisGdbStub<'_, B> {
+    fn read_register(
+        &mut self,
+        _tid: (),
+        reg: Register,
+        dst: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        if let Some(words) = self.cached_register(reg) {
+            for (word, value) in dst.chunks_mut(4).zip(&words) {
+                word.copy_from_slice(&self.endianness.u32_to_bytes(*value)[..word.len()]);
+            }
+            self.notify(|| TraceEvent::RegisterRead {
+                register: reg,
+                value: words,
+            });
+            return Ok(dst.len());
+        }
+        // The model's value may be behind the cache's
+        self.flush_registers().map_err(report)?;
+        let ids = self.register_resources(reg)?;
+        let resources = self.iris.resource_list(self.instance_id).map_err(report)?;
+        let wanted: Vec<_> = ids
+            .iter()
+            .filter_map(|id| resources.iter().find(|res| res.id == *id))
+            .collect();
+        let values = self
+            .iris
+            .read_resources(self.instance_id, &wanted)
+            .map_err(report)?;
+        let mut words = Vec::with_capacity(wanted.len());
+        for (word, res) in dst.chunks_mut(4).zip(&wanted) {
+            let value = values.get(&res.id).ok_or(TargetError::NonFatal)?;
+            let value = reg.gdb_value(narrow(&res.name, value));
+            word.copy_from_slice(&self.endianness.u32_to_bytes(value)[..word.len()]);
+            words.push(value);
+        }
+        self.notify(|| TraceEvent::RegisterRead {
+            register: reg,
+            value: words,
+        });
+        Ok(dst.len())
+    }
+
+    fn write_register(&mut self, _tid: (), reg: Register, val: &[u8]) -> TargetResult<(), Self> {
+        let ids = self.register_resources(reg)?;
+        if val.len() != ids.len() * 4 {
+            return Err(TargetError::NonFatal);
+        }
+        let mut values: Vec<u64> = val
+            .chunks_exact(4)
+            .map(|word| u64::from(self.endianness.u32_from_bytes(word.try_into().unwrap())))
+            .collect();
+        if reg == Register::XPSR {
+            values[0] = u64::from(
+                merge_xpsr(self.iris, self.instance_id, values[0] as u32).map_err(report)?,
+            );
+        }
+        values[0] = u64::from(reg.iris_value(values[0] as u32));
+        let words: Vec<u32> = values.iter().map(|&value| value as u32).collect();
+        self.notify(|| TraceEvent::RegisterWritten {
+            register: reg,
+            value: words.clone(),
+        });
+        if self.cached_register(reg).as_ref() == Some(&words) {
+            return Ok(());
+        }
+        if self.cache_register(reg, &words) {
+            return Ok(());
+        }
+        // SP is one of the banked stack pointers, so it's read again after
+        if reg.is_banked() {
+            self.sync_registers().map_err(report)?;
+        }
+        self.iris
+            .write_resources(self.instance_id, ids, values)
+            .map_err(report)?;
+        Ok(())
+    }
 }
 
-impl SingleThreadOps for IrisGdbStub<'_> {
+impl<B: IrisBackend + ?Sized> SingleThreadBase for IrisGdbStub<'_, B> {
+    fn support_single_register_access(&mut self) -> Option<SingleRegisterAccessOps<'_, (), Self>> {
+        Some(self)
+    }
+
+    fn support_resume(&mut self) -> Option<SingleThreadResumeOps<'_, Self>> {
+        Some(self)
+    }
+
     fn read_registers(&mut self, regs: &mut GuestState) -> TargetResult<(), Self> {
-        for res in
-            resource::get_list(&mut self.iris, self.instance_id, None, None).map_err(|_| ())?
-        {
-            let regnum = match res.name.as_str() {
-                "R0" => 0,
-                "R1" => 1,
-                "R2" => 2,
-                "R3" => 3,
-                "R4" => 4,
-                "R5" => 5,
-                "R6" => 6,
-                "R7" => 7,
-                "R8" => 8,
-                "R9" => 9,
-                "R10" => 10,
-                "R11" => 11,
-                "R12" => 12,
-                "R13" => 13,
-                "R14" => 14,
-                "R15" => 15,
-                "XPSR" => 25,
-                _ => continue,
-            };
-            let val =
-                resource::read(&mut self.iris, self.instance_id, vec![res.id]).map_err(|_| ())?;
-            if !val.data.is_empty() {
-                regs.regs[regnum] = val.data[0] as u32
+        if let Some(state) = self.register_cache.as_ref().and_then(|c| c.state.as_ref()) {
+            *regs = state.clone();
+        } else {
+            read_core_registers(self.iris, self.instance_id, self.has_fpu, regs).map_err(report)?;
+            regs.endianness = self.endianness;
+            if let Some(cache) = &mut self.register_cache {
+                cache.state = Some(regs.clone());
             }
         }
+        self.notify(|| TraceEvent::RegistersRead(regs.clone()));
         Ok(())
     }
 
-    fn read_addrs(&mut self, start_addr: u32, data: &mut [u8]) -> TargetResult<(), Self> {
-        let mem = memory::read(
-            &mut self.iris,
+    /// Reads up to the first address that faults, failing if that is the
+    /// first one. Device regions are read in their own unit width.
+    fn read_addrs(&mut self, start_addr: u32, data: &mut [u8]) -> TargetResult<usize, Self> {
+        let addr = u64::from(start_addr);
+        // The region is chosen by the start address alone, as gdb's reads
+        // are short
+        let width = self
+            .device_regions
+            .iter()
+            .find(|(range, _)| range.contains(&addr))
+            .map_or(1, |(_, width)| *width);
+        let valid = if width > 1 {
+            self.iris.read_units(
+                self.instance_id,
+                self.space_id,
+                addr,
+                data,
+                width,
+                self.endianness,
+            )
+        } else {
+            read_memory(
+                self.iris,
+                self.instance_id,
+                self.space_id,
+                addr,
+                data,
+                self.endianness,
+            )
+        }
+        .map_err(report)?;
+        self.notify(|| TraceEvent::MemoryRead {
+            addr: start_addr,
+            data: data[..valid].to_vec(),
+        });
+        if valid == 0 && !data.is_empty() {
+            return Err(TargetError::NonFatal);
+        }
+        Ok(valid)
+    }
+
+    fn write_addrs(&mut self, start_addr: u32, data: &[u8]) -> TargetResult<(), Self> {
+        self.notify(|| TraceEvent::MemoryWritten {
+            addr: start_addr,
+            data: data.to_vec(),
+        });
+        write_memory(
+            self.iris,
             self.instance_id,
-            0,
-            start_addr as u64,
-            1,
-            data.len() as u64,
+            self.space_id,
+            u64::from(start_addr),
+            data,
+            self.endianness,
         )
-        .map_err(|_| ())?;
-        for (offset, byte) in mem
-            .data
-            .into_iter()
-            .map(|u| u.to_le_bytes())
-            .flatten()
-            .enumerate()
-        {
-            if data.len() > offset {
-                data[offset] = byte;
+        .map_err(report)?;
+        Ok(())
+    }
+    fn write_registers(&mut self, regs: &GuestState) -> TargetResult<(), Self> {
+        let mut regs = regs.in_order(self.endianness);
+        self.notify(|| TraceEvent::RegistersWritten(regs.clone()));
+        let cached = match &self.register_cache {
+            Some(cache) => cache.state.as_ref(),
+            None => {
+                write_core_registers(self.iris, self.instance_id, &regs).map_err(report)?;
+                return Ok(());
             }
+        };
+        if cached == Some(&regs) {
+            return Ok(());
+        }
+        // Keep what the model will hold once written, which for XPSR
+        // isn't quite what gdb sent
+        if cached.is_none_or(|cached| cached.xpsr() != regs.xpsr()) {
+            let xpsr = merge_xpsr(self.iris, self.instance_id, regs.xpsr()).map_err(report)?;
+            regs.set(Register::XPSR, xpsr);
+        }
+        if let Some(cache) = &mut self.register_cache {
+            cache.state = Some(regs);
+            cache.dirty = true;
         }
         Ok(())
     }
+}
+
+/// There's no process to deliver a signal to, so signals are dropped
+impl<B: IrisBackend + ?Sized> SingleThreadResume for IrisGdbStub<'_, B> {
+    fn resume(&mut self, _signal: Option<Signal>) -> Result<(), ()> {
+        self.resume = Resume::Continue;
+        Ok(())
+    }
+
+    fn support_single_step(&mut self) -> Option<SingleThreadSingleStepOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_range_step(&mut self) -> Option<SingleThreadRangeSteppingOps<'_, Self>> {
+        Some(self)
+    }
+}
 
-    fn write_addrs(&mut self, _: u32, _: &[u8]) -> TargetResult<(), Self> {
+impl<B: IrisBackend + ?Sized> SingleThreadSingleStep for IrisGdbStub<'_, B> {
+    fn step(&mut self, _signal: Option<Signal>) -> Result<(), ()> {
+        self.resume = Resume::Step;
         Ok(())
     }
-    fn write_registers(&mut self, _: &GuestState) -> TargetResult<(), Self> {
-        // We don't support writing
+}
+
+impl<B: IrisBackend + ?Sized> SingleThreadRangeStepping for IrisGdbStub<'_, B> {
+    /// Step one instruction at a time until the PC leaves `[start, end)`,
+    /// saving gdb a round trip for every instruction of a source line. A
+    /// breakpoint, watchpoint or interrupt from gdb ends the range early.
+    fn resume_range_step(&mut self, start: u32, end: u32) -> Result<(), ()> {
+        self.resume = Resume::RangeStep(start, end);
         Ok(())
     }
+}
 
-    fn resume(
+impl<B: IrisBackend + ?Sized> IrisGdbStub<'_, B> {
+    /// Run the core until it halts for a reason gdb should hear about,
+    /// servicing semihosting calls and the hits of breakpoints whose
+    /// conditions are false on the way. Returns `None` when gdb interrupted
+    /// it.
+    fn continue_to_stop(
         &mut self,
-        act: ResumeAction,
-        intr: gdbstub::target::ext::base::GdbInterrupt<'_>,
-    ) -> Result<StopReason<u32>, ()> {
-        let mut interrupt = intr.no_async();
-        if act == ResumeAction::Step {
-            step::setup(self.iris, self.instance_id, 1, step::Unit::Instruction).map_err(|_| ())?
-        }
-        if act == ResumeAction::Step || act == ResumeAction::Continue {
-            simulation_time::run(self.iris, self.sim).map_err(|_| ())?;
-            while simulation_time::get(self.iris, self.sim)
-                .map_err(|_| ())?
-                .running
-            {
-                if interrupt.pending() {
-                    simulation_time::stop(self.iris, self.sim).map_err(|_| ())?;
-                    return Ok(StopReason::GdbInterrupt);
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> Result<Option<StopReason<u32>>, ()> {
+        loop {
+            let watchdog = self.watchdog.map(|watchdog| (self.instance_id, watchdog));
+            let (sim, timeout) = (self.sim, self.timeout);
+            let res = self
+                .iris
+                .fast_model()
+                .and_then(|iris| run_until_halt(iris, sim, interrupted, timeout, watchdog));
+            match res {
+                Ok(true) => return Ok(None),
+                Ok(false) => {}
+                Err(IrisError::Timeout) => {
+                    return Ok(Some(StopReason::Signal(Signal(self.signals.timeout))))
+                }
+                // gdb only shows the signal, so say why alongside
+                Err(e @ IrisError::Stalled(_)) => {
+                    eprintln!("{}", e);
+                    return Ok(Some(StopReason::Signal(Signal(self.signals.stalled))));
+                }
+                Err(e) => {
+                    report(e);
+                    return Err(());
                 }
             }
-            if act == ResumeAction::Step {
-                return Ok(StopReason::DoneStep);
-            } else {
-                return Ok(StopReason::HwBreak);
+            let handled = match &mut self.semihosting {
+                Some(semihosting) => semihosting
+                    .handle(
+                        self.iris.fast_model().map_err(report)?,
+                        self.instance_id,
+                        self.space_id,
+                    )
+                    .map_err(report)?,
+                None => false,
+            };
+            if !handled && !self.skip_hit().map_err(report)? {
+                return self.stop_reason(false).map(Some).map_err(report);
+            }
+            if let Some(last_hit) = &self.last_hit {
+                last_hit.set(None);
+            }
+        }
+    }
+
+    /// Step the core until its PC leaves `start..end`, as
+    /// `resume_range_step` asked. Returns `None` when gdb interrupted it.
+    fn range_step_to_stop(
+        &mut self,
+        start: u32,
+        end: u32,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> Result<Option<StopReason<u32>>, ()> {
+        loop {
+            if let Some(last_hit) = &self.last_hit {
+                last_hit.set(None);
+            }
+            let iris = self.iris.fast_model().map_err(report)?;
+            step::instruction(iris, self.instance_id, 1).map_err(report)?;
+            let reason = self.stop_reason(true).map_err(report)?;
+            if !matches!(reason, StopReason::DoneStep) {
+                return Ok(Some(reason));
+            }
+            let pc = match self.read_pc().map_err(report)? {
+                Some(pc) => pc,
+                None => return Ok(Some(reason)),
+            };
+            if !(start..end).contains(&pc) {
+                return Ok(Some(reason));
+            }
+            // The next step would execute the BKPT written over the
+            // instruction, so stop on it as if it had been hit
+            if self.sw_breakpoints.contains_key(&pc) {
+                return Ok(Some(StopReason::SwBreak(())));
+            }
+            if interrupted() {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+impl<B: IrisBackend + ?Sized> RunTarget for IrisGdbStub<'_, B> {
+    type StopReason = StopReason<u32>;
+
+    fn wait_for_stop(
+        &mut self,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> Result<Option<StopReason<u32>>, ()> {
+        self.sync_registers().map_err(report)?;
+        if let Some(last_hit) = &self.last_hit {
+            last_hit.set(None);
+        }
+        match self.resume {
+            Resume::Continue => self.continue_to_stop(interrupted),
+            Resume::Step => {
+                let iris = self.iris.fast_model().map_err(report)?;
+                step::instruction(iris, self.instance_id, 1).map_err(report)?;
+                self.stop_reason(true).map(Some).map_err(report)
             }
+            Resume::RangeStep(start, end) => self.range_step_to_stop(start, end, interrupted),
         }
-        Err(())
+    }
+
+    fn interrupt(&mut self) -> Result<StopReason<u32>, ()> {
+        let iris = self.iris.fast_model().map_err(report)?;
+        iris.stop(self.sim).map_err(report)?;
+        Ok(StopReason::Signal(Signal(self.signals.interrupt)))
     }
 }
 
-impl<'i> Breakpoints for IrisGdbStub<'i> {
-    fn hw_breakpoint(&mut self) -> Option<HwBreakpointOps<Self>> {
+impl<'i, B: IrisBackend + ?Sized> Breakpoints for IrisGdbStub<'i, B> {
+    fn support_hw_breakpoint(&mut self) -> Option<HwBreakpointOps<'_, Self>> {
         Some(self)
     }
 
-    fn sw_breakpoint(&mut self) -> Option<SwBreakpointOps<Self>> {
+    fn support_sw_breakpoint(&mut self) -> Option<SwBreakpointOps<'_, Self>> {
         Some(self)
     }
+
+    fn support_hw_watchpoint(&mut self) -> Option<HwWatchpointOps<'_, Self>> {
+        if self.last_hit.is_some() {
+            Some(self)
+        } else {
+            None
+        }
+    }
 }
-impl<'i> SwBreakpoint for IrisGdbStub<'i> {
+impl<'i, B: IrisBackend + ?Sized> SwBreakpoint for IrisGdbStub<'i, B> {
     fn add_sw_breakpoint(
         &mut self,
         addr: <Self::Arch as Arch>::Usize,
-        k: <Self::Arch as Arch>::BreakpointKind,
+        kind: <Self::Arch as Arch>::BreakpointKind,
     ) -> TargetResult<bool, Self> {
-        self.add_hw_breakpoint(addr, k)
+        if self.breakpoint_kind == BreakpointKind::Hardware {
+            return self.add_hw_breakpoint(addr, kind);
+        }
+        if self.sw_breakpoints.contains_key(&addr) {
+            return Ok(true);
+        }
+        let space = pc_space(self.iris, self.instance_id).map_err(report)?;
+        let addr64 = u64::from(addr);
+        let orig = self
+            .iris
+            .read_memory(self.instance_id, space, addr64, 2, 1)
+            .map_err(report)?;
+        let orig = match orig.data.first() {
+            Some(unit) if orig.error.is_none() => (*unit as u16).to_le_bytes(),
+            _ => return Ok(false),
+        };
+        if self
+            .iris
+            .write_memory(self.instance_id, space, addr64, 2, &BKPT)
+            .is_err()
+        {
+            return Ok(false);
+        }
+        self.sw_breakpoints.insert(addr, (space, orig));
+        Ok(true)
     }
 
     fn remove_sw_breakpoint(
         &mut self,
         addr: <Self::Arch as Arch>::Usize,
-        k: <Self::Arch as Arch>::BreakpointKind,
+        kind: <Self::Arch as Arch>::BreakpointKind,
     ) -> TargetResult<bool, Self> {
-        self.remove_hw_breakpoint(addr, k)
+        if self.breakpoint_kind == BreakpointKind::Hardware {
+            return self.remove_hw_breakpoint(addr, kind);
+        }
+        if let Entry::Occupied(ent) = self.sw_breakpoints.entry(addr) {
+            let (space, orig) = *ent.get();
+            let addr = u64::from(addr);
+            if self
+                .iris
+                .write_memory(self.instance_id, space, addr, 2, &orig)
+                .is_ok()
+            {
+                let _ = ent.remove_entry();
+                Ok(true)
+            } else {
+                Ok(false)
+            }
+        } else {
+            Ok(true)
+        }
     }
 }
 
-impl<'i> HwBreakpoint for IrisGdbStub<'i> {
+impl<'i, B: IrisBackend + ?Sized> HwBreakpoint for IrisGdbStub<'i, B> {
     fn add_hw_breakpoint(
         &mut self,
         addr: <Self::Arch as Arch>::Usize,
@@ -277,15 +1855,11 @@ impl<'i> HwBreakpoint for IrisGdbStub<'i> {
         if self.breakpoints.contains_key(&addr) {
             return Ok(true);
         }
-        if let Ok(id) = breakpoint::code(
-            self.iris,
-            self.instance_id,
-            addr as u64,
-            None,
-            0,
-            true,
-            false,
-        ) {
+        let space = pc_space(self.iris, self.instance_id).map_err(report)?;
+        if let Ok(id) =
+            self.iris
+                .code_breakpoint(self.instance_id, addr as u64, None, space, true, false)
+        {
             self.breakpoints.insert(addr, id);
             Ok(true)
         } else {
@@ -298,7 +1872,7 @@ impl<'i> HwBreakpoint for IrisGdbStub<'i> {
         _: <Self::Arch as Arch>::BreakpointKind,
     ) -> TargetResult<bool, Self> {
         if let Entry::Occupied(ent) = self.breakpoints.entry(addr) {
-            if let Ok(()) = breakpoint::delete(self.iris, self.instance_id, *ent.get()) {
+            if let Ok(()) = self.iris.delete_breakpoint(self.instance_id, *ent.get()) {
                 let _ = ent.remove_entry();
                 Ok(true)
             } else {
@@ -310,72 +1884,985 @@ impl<'i> HwBreakpoint for IrisGdbStub<'i> {
     }
 }
 
-impl<'i> MonitorCmd for IrisGdbStub<'i> {
-    fn handle_monitor_cmd(&mut self, cmd: &[u8], mut out: ConsoleOutput<'_>) -> Result<(), ()> {
-        match String::from_utf8_lossy(cmd).borrow() {
-            "reset" => {
-                simulation::reset(self.iris, self.sim, false).map_err(|_| ())?;
-                simulation::wait(self.iris, self.sim).map_err(|_| ())?;
+impl<'i, B: IrisBackend + ?Sized> HwWatchpoint for IrisGdbStub<'i, B> {
+    /// The comparator watches a range whose length is a power of two and
+    /// that starts at a multiple of its length. gdb is told other ranges
+    /// can't be watched, rather than have accesses next to them stop the
+    /// core.
+    fn add_hw_watchpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        len: <Self::Arch as Arch>::Usize,
+        kind: WatchKind,
+    ) -> TargetResult<bool, Self> {
+        if !len.is_power_of_two() || !addr.is_multiple_of(len) {
+            return Ok(false);
+        }
+        if self.watchpoints.values().any(|&w| w == (addr, kind)) {
+            return Ok(true);
+        }
+        let rw_mode = match kind {
+            WatchKind::Write => "w",
+            WatchKind::Read => "r",
+            WatchKind::ReadWrite => "rw",
+        };
+        let addr64 = u64::from(addr);
+        match self.iris.data_breakpoint(
+            self.instance_id,
+            addr64,
+            u64::from(len),
+            self.space_id,
+            rw_mode,
+            true,
+        ) {
+            Ok(id) => {
+                self.watchpoints.insert(id, (addr, kind));
+                Ok(true)
             }
-            c => {
-                outputln!(out, "Monitor command {} not supported", c);
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn remove_hw_watchpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        _len: <Self::Arch as Arch>::Usize,
+        kind: WatchKind,
+    ) -> TargetResult<bool, Self> {
+        let found = self
+            .watchpoints
+            .iter()
+            .find(|(_, &w)| w == (addr, kind))
+            .map(|(&id, _)| id);
+        match found {
+            Some(id) => {
+                if self.iris.delete_breakpoint(self.instance_id, id).is_ok() {
+                    self.watchpoints.remove(&id);
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
             }
+            None => Ok(true),
         }
-        Ok(())
     }
 }
 
-pub enum Armv7mArch {}
-impl Arch for Armv7mArch {
-    type Usize = u32;
-    type Registers = GuestState;
-    type RegId = Register;
-    type BreakpointKind = usize;
-}
+/// There's only ever the one "process", the model itself
+const PID: Pid = match Pid::new(1) {
+    Some(pid) => pid,
+    None => unreachable!(),
+};
+
+/// With `target extended-remote`, `run` resets the model and halts at the
+/// reset handler rather than loading a program.
+impl<B: IrisBackend + ?Sized> ExtendedMode for IrisGdbStub<'_, B> {
+    fn run(&mut self, _filename: Option<&[u8]>, _args: Args<'_, '_>) -> TargetResult<Pid, Self> {
+        self.invalidate_registers();
+        self.reset_to_vector().map_err(report)?;
+        Ok(PID)
+    }
+
+    fn attach(&mut self, _pid: Pid) -> TargetResult<(), Self> {
+        Ok(())
+    }
+
+    fn query_if_attached(&mut self, _pid: Pid) -> TargetResult<AttachKind, Self> {
+        Ok(AttachKind::Attach)
+    }
 
-pub struct GdbOverPipe {
-    rx: Receiver<Result<u8, IOError>>,
-    write: Stdout,
+    /// Only ends the session; the model is then left as configured with
+    /// `IrisGdbStubBuilder::on_kill`
+    fn kill(&mut self, _pid: Option<Pid>) -> TargetResult<ShouldTerminate, Self> {
+        Ok(ShouldTerminate::Yes)
+    }
+
+    fn restart(&mut self) -> Result<(), Self::Error> {
+        self.invalidate_registers();
+        self.reset_to_vector().map_err(report)
+    }
 }
 
-impl<'a> GdbOverPipe {
-    pub fn new(read: Stdin, write: Stdout) -> Self {
-        let (tx, rx) = channel();
-        spawn(move || {
-            let mut byte = [0u8];
-            let mut read = read;
-            loop {
-                match read.read(&mut byte) {
-                    Ok(0) => break,
-                    Ok(_) => tx.send(Ok(byte[0])).unwrap(),
-                    Err(error) => tx.send(Err(error)).unwrap(),
+impl<'i, B: IrisBackend + ?Sized> MonitorCmd for IrisGdbStub<'i, B> {
+    fn handle_monitor_cmd(&mut self, cmd: &[u8], mut out: ConsoleOutput<'_>) -> Result<(), ()> {
+        // Monitor commands read and write the model directly
+        self.sync_registers().map_err(report)?;
+        // For the closures given the connection, which can't borrow `self`
+        let (instance_id, space_id) = (self.instance_id, self.space_id);
+        match String::from_utf8_lossy(cmd).borrow() {
+            c @ ("reset" | "reset cold" | "reset warm") => {
+                let kind = if c == "reset warm" {
+                    ResetKind::Warm
+                } else {
+                    ResetKind::Cold
+                };
+                let iris = self.iris.fast_model().map_err(report)?;
+                iris.reset(self.sim, kind).map_err(report)?;
+                // gdb doesn't know that monitor commands change registers
+                outputln!(out, "Reset; run `flushregs` to refresh gdb's registers");
+            }
+            c if c.starts_with("save ") || c.starts_with("restore ") => {
+                let (verb, dir) = c.split_once(' ').unwrap();
+                let dir = Path::new(dir.trim());
+                let res = if verb == "save" {
+                    self.save_checkpoint(dir)
+                        .map(|_| format!("Saved to {}", dir.display()))
+                } else {
+                    self.restore_checkpoint(dir).map(|_| {
+                        format!(
+                            "Restored from {}; run `flushregs` to refresh gdb's registers",
+                            dir.display()
+                        )
+                    })
+                };
+                match res {
+                    Ok(msg) => outputln!(out, "{}", msg),
+                    Err(e) => outputln!(out, "{}", e),
                 }
             }
-        });
-        Self { rx, write }
+            // gdbstub only answers qThreadExtraInfo, which would show this
+            // in `info threads`, for multi-threaded targets
+            "context" => match read_resource(self.iris, self.instance_id, "XPSR") {
+                Ok(Some(xpsr)) => outputln!(out, "{}", exception_context(xpsr as u32)),
+                Ok(None) => outputln!(out, "The core has no XPSR"),
+                Err(e) => outputln!(out, "{}", e),
+            },
+            "xpsr" => match read_resource(self.iris, self.instance_id, "XPSR") {
+                Ok(Some(xpsr)) => outputln!(out, "{}", Xpsr(xpsr as u32)),
+                Ok(None) => outputln!(out, "The core has no XPSR"),
+                Err(e) => outputln!(out, "{}", e),
+            },
+            c if c.split_whitespace().next() == Some("backtrace") => {
+                let depth = match c.split_whitespace().nth(1).map(parse_value) {
+                    None => BACKTRACE_DEPTH,
+                    Some(Some(depth)) => depth as usize,
+                    Some(None) => {
+                        outputln!(out, "Usage: backtrace [depth]");
+                        return Ok(());
+                    }
+                };
+                let context = read_resource(self.iris, self.instance_id, "XPSR").and_then(|xpsr| {
+                    Ok((xpsr, read_resource(self.iris, self.instance_id, "CONTROL")?))
+                });
+                match context {
+                    // In Thread mode, SPSEL picks the process stack
+                    Ok((Some(xpsr), control)) => {
+                        let thread = xpsr & 0x1ff == 0;
+                        let stack = match control {
+                            Some(control) if thread && control & 2 != 0 => "process",
+                            _ => "main",
+                        };
+                        outputln!(
+                            out,
+                            "{}, on the {} stack",
+                            exception_context(xpsr as u32),
+                            stack
+                        );
+                    }
+                    Ok((None, _)) => {}
+                    Err(e) => outputln!(out, "{}", e),
+                }
+                match self.backtrace(depth) {
+                    Ok(frames) => {
+                        for (n, (addr, note)) in frames.iter().enumerate() {
+                            match note {
+                                Some(note) => outputln!(out, "#{:<3} {:#010x} ({})", n, addr, note),
+                                None => outputln!(out, "#{:<3} {:#010x}", n, addr),
+                            }
+                        }
+                    }
+                    Err(e) => outputln!(out, "{}", e),
+                }
+            }
+            "regions" => {
+                match self.iris.memory_regions(self.instance_id, self.space_id) {
+                    Ok(regions) => {
+                        for region in regions {
+                            let end = region.size.map_or(u64::MAX, |size| region.base + size - 1);
+                            outputln!(
+                                out,
+                                "{:#010x}-{:#010x} {} {:<7} {}",
+                                region.base,
+                                end,
+                                region.access,
+                                region.kind,
+                                region.name
+                            );
+                        }
+                    }
+                    Err(e) => outputln!(out, "{}", e),
+                }
+                for (range, width) in &self.device_regions {
+                    outputln!(
+                        out,
+                        "{:#010x}-{:#010x} read in {} byte units",
+                        range.start,
+                        range.end.saturating_sub(1),
+                        width
+                    );
+                }
+            }
+            c if c.split_whitespace().next() == Some("vectors") => {
+                let count = match c.split_whitespace().nth(1).map(parse_value) {
+                    None => None,
+                    Some(Some(count)) => Some(count as usize),
+                    Some(None) => {
+                        outputln!(out, "Usage: vectors [count]");
+                        return Ok(());
+                    }
+                };
+                match self.vector_table(count) {
+                    Ok((vtor, entries)) => {
+                        outputln!(out, "Vector table at {:#010x}", vtor);
+                        for (n, entry) in entries.into_iter().enumerate() {
+                            // Unused vectors are commonly left as 0
+                            if n == 0 {
+                                outputln!(out, "{:>4} {:<16} {:#010x}", n, "initial SP", entry);
+                            } else if entry != 0 {
+                                let name = exception_name(n as u32);
+                                // The low bit of a handler's address marks it as Thumb code
+                                outputln!(out, "{:>4} {:<16} {:#010x}", n, name, entry & !1);
+                            }
+                        }
+                    }
+                    Err(e) => outputln!(out, "{}", e),
+                }
+            }
+            c if c.split_whitespace().next() == Some("secstate") => {
+                let state = match c.split_whitespace().nth(1) {
+                    None => None,
+                    Some("secure" | "s") => Some(SecurityState::Secure),
+                    Some("non-secure" | "nonsecure" | "ns") => Some(SecurityState::NonSecure),
+                    Some(_) => {
+                        outputln!(out, "Usage: secstate [secure|non-secure]");
+                        return Ok(());
+                    }
+                };
+                let res = match state {
+                    Some(state) => self
+                        .iris
+                        .fast_model()
+                        .and_then(|iris| iris.set_security_state(instance_id, state))
+                        .and_then(|()| self.follow_pc_space())
+                        .map(|()| state),
+                    None => self
+                        .iris
+                        .fast_model()
+                        .and_then(|iris| iris.security_state(instance_id)),
+                };
+                match res {
+                    // gdb doesn't know that the banked registers changed
+                    Ok(new) if state.is_some() => {
+                        outputln!(out, "{}; run `flushregs` to refresh gdb's registers", new)
+                    }
+                    Ok(current) => outputln!(out, "{}", current),
+                    Err(e) => outputln!(out, "{}", e),
+                }
+            }
+            c if c.split_whitespace().next() == Some("stepcycle") => {
+                let count = match c.split_whitespace().nth(1).map(parse_value) {
+                    None => 1,
+                    Some(Some(count)) if count > 0 => count,
+                    Some(_) => {
+                        outputln!(out, "Usage: stepcycle [count]");
+                        return Ok(());
+                    }
+                };
+                let res = self
+                    .iris
+                    .fast_model()
+                    .and_then(|iris| step::by(iris, instance_id, count, Unit::Cycle))
+                    .and_then(|()| self.read_pc());
+                match res {
+                    Ok(Some(pc)) => {
+                        self.notify(|| TraceEvent::Step {
+                            pc,
+                            unit: Unit::Cycle,
+                        });
+                        outputln!(
+                            out,
+                            "Stopped at {:#010x}; run `flushregs` to refresh gdb's registers",
+                            pc
+                        );
+                    }
+                    Ok(None) => outputln!(out, "The core has no PC"),
+                    Err(e) => outputln!(out, "{}", e),
+                }
+            }
+            "condition" => {
+                for (addr, condition) in &self.conditions {
+                    outputln!(out, "{:#010x} {}", addr, condition);
+                }
+            }
+            c if c.starts_with("condition ") => {
+                let args = c["condition ".len()..].trim();
+                let (addr, expr) = args.split_once(' ').unwrap_or((args, ""));
+                let addr = match parse_value(addr).and_then(|addr| u32::try_from(addr).ok()) {
+                    Some(addr) => addr & !1,
+                    None => {
+                        outputln!(out, "Bad address {}", addr);
+                        return Ok(());
+                    }
+                };
+                if expr.trim().is_empty() {
+                    self.conditions.remove(&addr);
+                    outputln!(out, "Condition at {:#x} removed", addr);
+                } else {
+                    match Condition::parse(expr) {
+                        Some(condition) => {
+                            self.conditions.insert(addr, condition);
+                            outputln!(
+                                out,
+                                "Hardware breakpoints at {:#x} stop only when {}",
+                                addr,
+                                condition
+                            );
+                        }
+                        None => outputln!(
+                            out,
+                            "Expected <register> == <value> or <register> != <value>"
+                        ),
+                    }
+                }
+            }
+            c if c.starts_with("crc ") => {
+                let args: Vec<_> = c.split_whitespace().skip(1).map(parse_value).collect();
+                match args[..] {
+                    [Some(addr), Some(len)] => {
+                        let crc = self
+                            .iris
+                            .fast_model()
+                            .and_then(|iris| memory::crc32(iris, instance_id, space_id, addr, len));
+                        match crc {
+                            Ok(crc) => outputln!(out, "{:#010x}", crc),
+                            Err(e) => outputln!(out, "{}", e),
+                        }
+                    }
+                    _ => outputln!(out, "Usage: crc <address> <length>"),
+                }
+            }
+            c if c.starts_with("dump ") => {
+                let args: Vec<&str> = c.split_whitespace().skip(1).collect();
+                let (addr, len, file) = match args[..] {
+                    [addr, len, file] => match (parse_value(addr), parse_value(len)) {
+                        (Some(addr), Some(len)) => (addr, len, file),
+                        _ => {
+                            outputln!(out, "Bad address or length");
+                            return Ok(());
+                        }
+                    },
+                    _ => {
+                        outputln!(out, "Usage: dump <address> <length> <file>");
+                        return Ok(());
+                    }
+                };
+                let path = Path::new(file);
+                let dumped = self
+                    .iris
+                    .fast_model()
+                    .and_then(|iris| memory::dump(iris, instance_id, space_id, addr, len, path));
+                match dumped {
+                    Ok(done) if done < len => outputln!(
+                        out,
+                        "Only the first {:#x} of {:#x} bytes were readable; wrote them to {}",
+                        done,
+                        len,
+                        path.display()
+                    ),
+                    Ok(done) => outputln!(out, "Wrote {:#x} bytes to {}", done, path.display()),
+                    Err(e) => outputln!(out, "{}", e),
+                }
+            }
+            "fault" => match FaultInfo::read(self.iris, self.instance_id) {
+                Ok(Some(fault)) => outputln!(out, "{}", fault),
+                Ok(None) => outputln!(out, "The core has no fault status registers"),
+                Err(e) => outputln!(out, "{}", e),
+            },
+            c if c.split_whitespace().next() == Some("disassemble") => {
+                let pc = self.read_pc().map_err(report)?.unwrap_or(0);
+                let mode = self.disasm_mode().map_err(report)?;
+                let res = self.iris.fast_model().and_then(|iris| {
+                    disasm_monitor_cmd(
+                        iris,
+                        instance_id,
+                        c,
+                        u64::from(pc),
+                        mode.as_deref(),
+                        &mut out,
+                    )
+                });
+                if let Err(e) = res {
+                    outputln!(out, "{}", e);
+                }
+            }
+            c => match self
+                .iris
+                .fast_model()
+                .and_then(|iris| resource_monitor_cmd(iris, instance_id, c, &mut out))
+            {
+                Ok(true) => {}
+                Ok(false) => outputln!(out, "Monitor command {} not supported", c),
+                Err(e) => outputln!(out, "{}", e),
+            },
+        }
+        Ok(())
     }
 }
 
-impl Connection for GdbOverPipe {
-    type Error = IOError;
-    fn write(&mut self, byte: u8) -> Result<(), Self::Error> {
-        let outbuf = [byte; 1];
-        self.write.write(&outbuf)?;
-        self.write.flush()?;
-        Ok(())
+/// The M-profile core registers, so gdb doesn't fall back to the A-profile
+/// layout with its 96 bit FPA registers. Closed by the caller, after any
+/// optional features.
+const TARGET_XML_CORE: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>arm</architecture>
+  <feature name="org.gnu.gdb.arm.m-profile">
+    <reg name="r0" bitsize="32"/>
+    <reg name="r1" bitsize="32"/>
+    <reg name="r2" bitsize="32"/>
+    <reg name="r3" bitsize="32"/>
+    <reg name="r4" bitsize="32"/>
+    <reg name="r5" bitsize="32"/>
+    <reg name="r6" bitsize="32"/>
+    <reg name="r7" bitsize="32"/>
+    <reg name="r8" bitsize="32"/>
+    <reg name="r9" bitsize="32"/>
+    <reg name="r10" bitsize="32"/>
+    <reg name="r11" bitsize="32"/>
+    <reg name="r12" bitsize="32"/>
+    <reg name="sp" bitsize="32" type="data_ptr"/>
+    <reg name="lr" bitsize="32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="xpsr" bitsize="32"/>
+  </feature>
+"#;
+
+/// The start of a memory map, closed by the caller after the regions
+const MEMORY_MAP_HEADER: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE memory-map PUBLIC "+//IDN gnu.org//DTD GDB Memory Map V1.0//EN"
+    "http://sourceware.org/gdb/gdb-memory-map.dtd">
+<memory-map>
+"#;
+
+/// The VFP registers, from which gdb derives S0-S31 itself
+const TARGET_XML_VFP: &str = r#"  <feature name="org.gnu.gdb.arm.vfp">
+    <reg name="d0" bitsize="64" type="ieee_double"/>
+    <reg name="d1" bitsize="64" type="ieee_double"/>
+    <reg name="d2" bitsize="64" type="ieee_double"/>
+    <reg name="d3" bitsize="64" type="ieee_double"/>
+    <reg name="d4" bitsize="64" type="ieee_double"/>
+    <reg name="d5" bitsize="64" type="ieee_double"/>
+    <reg name="d6" bitsize="64" type="ieee_double"/>
+    <reg name="d7" bitsize="64" type="ieee_double"/>
+    <reg name="d8" bitsize="64" type="ieee_double"/>
+    <reg name="d9" bitsize="64" type="ieee_double"/>
+    <reg name="d10" bitsize="64" type="ieee_double"/>
+    <reg name="d11" bitsize="64" type="ieee_double"/>
+    <reg name="d12" bitsize="64" type="ieee_double"/>
+    <reg name="d13" bitsize="64" type="ieee_double"/>
+    <reg name="d14" bitsize="64" type="ieee_double"/>
+    <reg name="d15" bitsize="64" type="ieee_double"/>
+    <reg name="fpscr" bitsize="32" type="int" group="float"/>
+  </feature>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gdb::backend::MockIris;
+    use crate::iris_client::{received, reply, Answer};
+    use crate::memory::Access;
+    use gdbstub::arch::{RegId, Registers};
+    use serde_json::{json, Value};
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// The core registers of a Cortex-M model as Iris lists them, with the
+    /// resource ids 0-16 in gdb's order
+    fn core_list() -> Value {
+        CORE_REGISTERS
+            .iter()
+            .enumerate()
+            .map(
+                |(id, (_, name))| json!({"rscId": id, "name": name, "cname": name, "bitWidth": 32}),
+            )
+            .collect()
+    }
+
+    /// The names of the methods of the requests a scripted server was sent
+    fn methods(server: &std::net::TcpStream) -> Vec<String> {
+        received(server)
+            .iter()
+            .map(|req| req["method"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    /// How a model of Cortex-M cores answers a stub, as a server made by
+    /// `FastModelIris::answered`. Each register of instance `n` holds `n`
+    /// above its resource id.
+    fn cores(method: &str, params: &Value) -> Result<Value, String> {
+        match method {
+            "instanceRegistry_getInstanceInfoByName" => {
+                Ok(json!({"instId": 0, "instName": params["instName"]}))
+            }
+            "resource_getList" => Ok(core_list()),
+            "resource_read" => {
+                let inst = params["instId"].as_u64().unwrap();
+                let data: Vec<u64> = params["rscIds"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|id| inst << 8 | id.as_u64().unwrap())
+                    .collect();
+                Ok(json!({ "data": data }))
+            }
+            "memory_getMemorySpaces" => Ok(json!([{"spaceId": 0, "name": "Memory"}])),
+            _ => Err(format!("{} is not modelled", method)),
+        }
+    }
+
+    fn region(base: u64, size: Option<u64>, kind: MemKind, access: Access) -> MemRegion {
+        MemRegion {
+            name: format!("{}", kind),
+            base,
+            size,
+            description: None,
+            kind,
+            access,
+        }
+    }
+
+    /// The attributes of a start tag, from the text after its name
+    fn attrs(tag: &str) -> HashMap<&str, &str> {
+        let parts: Vec<_> = tag.split('"').collect();
+        parts
+            .chunks_exact(2)
+            .map(|pair| (pair[0].trim().trim_end_matches('='), pair[1]))
+            .collect()
+    }
+
+    /// The type, start, length and block size of each `memory` element
+    fn parse(xml: &str) -> Vec<(String, u64, u64, Option<u64>)> {
+        let hex = |text: &str| u64::from_str_radix(text.trim_start_matches("0x"), 16).unwrap();
+        let body = xml
+            .split_once("<memory-map>")
+            .and_then(|(_, rest)| rest.split_once("</memory-map>"))
+            .expect("a memory-map element")
+            .0;
+        body.split("<memory ")
+            .skip(1)
+            .map(|elem| {
+                let tag = attrs(&elem[..elem.find('>').unwrap()]);
+                let blocksize = elem
+                    .split_once("<property name=\"blocksize\">")
+                    .map(|(_, rest)| hex(&rest[..rest.find('<').unwrap()]));
+                (
+                    tag["type"].to_string(),
+                    hex(tag["start"]),
+                    hex(tag["length"]),
+                    blocksize,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn memory_map_lists_regions_by_kind() {
+        let read_only = Access {
+            write: false,
+            ..Access::ALL
+        };
+        let regions = [
+            region(0, Some(0x8_0000), MemKind::Flash, Access::ALL),
+            region(0x1000_0000, Some(0x1000), MemKind::Rom, read_only),
+            region(0x1000_1000, Some(0x1000), MemKind::Unknown, read_only),
+            region(0x2000_0000, Some(0x1_0000), MemKind::Ram, Access::ALL),
+            // Reaches the end of the 64 bit space, so is cut at 4 GiB
+            region(0xe000_0000, None, MemKind::Device, Access::ALL),
+            region(0x1_0000_0000, Some(0x1000), MemKind::Ram, Access::ALL),
+        ];
+        let xml = memory_map_xml(&regions, 0x800);
+        assert!(xml.starts_with("<?xml version=\"1.0\"?>\n<!DOCTYPE memory-map"));
+        assert_eq!(
+            parse(&xml),
+            vec![
+                ("flash".to_string(), 0, 0x8_0000, Some(0x800)),
+                ("rom".to_string(), 0x1000_0000, 0x1000, None),
+                ("rom".to_string(), 0x1000_1000, 0x1000, None),
+                ("ram".to_string(), 0x2000_0000, 0x1_0000, None),
+                ("ram".to_string(), 0xe000_0000, 0x2000_0000, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn flash_erase_takes_whole_blocks_of_one_region() {
+        let regions = [
+            region(0, Some(0x8_0000), MemKind::Flash, Access::ALL),
+            region(0x8_0000, Some(0x8_0000), MemKind::Flash, Access::ALL),
+            region(0x2000_0000, Some(0x1_0000), MemKind::Ram, Access::ALL),
+        ];
+        let flash = flash_ranges(&regions);
+        assert_eq!(flash, vec![0..0x8_0000, 0x8_0000..0x10_0000]);
+        assert!(is_flash_blocks(&flash, 0x1000, &(0x1000..0x3000)));
+        assert!(is_flash_blocks(&flash, 0x1000, &(0x7_f000..0x8_0000)));
+        // Not block aligned
+        assert!(!is_flash_blocks(&flash, 0x1000, &(0x800..0x1800)));
+        assert!(!is_flash_blocks(&flash, 0x1000, &(0x1000..0x1800)));
+        // Across two regions, or outside flash
+        assert!(!is_flash_blocks(&flash, 0x1000, &(0x7_f000..0x8_1000)));
+        assert!(!is_flash_blocks(
+            &flash,
+            0x1000,
+            &(0x2000_0000..0x2000_1000)
+        ));
+    }
+
+    #[test]
+    fn memory_map_is_read_in_pieces() {
+        let xml = memory_map_xml(&[region(0, Some(4), MemKind::Ram, Access::ALL)], 0x1000);
+        let mut read = Vec::new();
+        let mut buf = [0u8; 16];
+        loop {
+            let len = copy_range(xml.as_bytes(), read.len() as u64, 10, &mut buf);
+            if len == 0 {
+                break;
+            }
+            read.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(read, xml.as_bytes());
+        assert_eq!(copy_range(xml.as_bytes(), u64::MAX, 10, &mut buf), 0);
+    }
+
+    /// The name, size and gdb register number of each `reg` element, numbered
+    /// on from the one before unless it gives its own
+    fn parse_target(xml: &str) -> Vec<(String, usize, usize)> {
+        let mut regnum = 0;
+        xml.split("<reg ")
+            .skip(1)
+            .map(|elem| {
+                let tag = attrs(&elem[..elem.find("/>").unwrap()]);
+                if let Some(n) = tag.get("regnum") {
+                    regnum = n.parse().unwrap();
+                }
+                let reg = (
+                    tag["name"].to_string(),
+                    tag["bitsize"].parse().unwrap(),
+                    regnum,
+                );
+                regnum += 1;
+                reg
+            })
+            .collect()
+    }
+
+    #[test]
+    fn target_xml_numbers_registers_as_from_raw_id() {
+        let xml = target_xml(true, &[0, 1]);
+        assert!(xml.contains("<feature name=\"org.gnu.gdb.arm.m-profile\">"));
+        assert!(xml.trim_end().ends_with("</target>"));
+        let regs = parse_target(&xml);
+        let names: Vec<_> = regs.iter().map(|(name, ..)| name.as_str()).collect();
+        assert_eq!(&names[..17], CORE_REGISTERS.map(|(reg, _)| reg.to_string()));
+        // 17 core registers, d0-d15, fpscr, msp and psp
+        assert_eq!(regs.len(), 36);
+        for (name, bitsize, regnum) in regs {
+            let (reg, size) = Register::from_raw_id(regnum).unwrap();
+            assert_eq!(reg.to_string(), name);
+            assert_eq!(size.unwrap().get() * 8, bitsize, "{}", name);
+        }
+        assert_eq!(parse_target(&target_xml(false, &[])).len(), 17);
+    }
+
+    #[test]
+    fn register_reads_after_the_first_take_one_request() {
+        let values = json!({"data": (0..17).collect::<Vec<u64>>()});
+        let (mut iris, server) = FastModelIris::scripted(&[
+            reply(0, core_list()),
+            reply(1, values.clone()),
+            reply(2, values.clone()),
+            reply(3, values),
+        ]);
+        for _ in 0..3 {
+            let mut regs = GuestState::default();
+            read_core_registers(&mut iris, 3, false, &mut regs).unwrap();
+            assert_eq!(regs.regs[12], 12);
+        }
+        // The list is fetched once, and all 17 registers are read in one
+        // request each time
+        assert_eq!(
+            methods(&server),
+            [
+                "resource_getList",
+                "resource_read",
+                "resource_read",
+                "resource_read"
+            ]
+        );
+    }
+
+    #[test]
+    fn thumb_pc_is_reported_and_written_halfword_aligned() {
+        // A model that reports the PC of Thumb code with bit 0 set
+        let mut values: Vec<u64> = vec![0; 17];
+        values[15] = 0x0800_0135;
+        values[16] = 1 << 24;
+        let (mut iris, server) = FastModelIris::scripted(&[
+            reply(0, core_list()),
+            reply(1, json!({ "data": values })),
+            reply(2, json!({"data": [1 << 24]})),
+            reply(3, json!(null)),
+        ]);
+        let mut regs = GuestState::default();
+        read_core_registers(&mut iris, 3, false, &mut regs).unwrap();
+        assert_eq!(regs.pc(), 0x0800_0134);
+        // `jump *func` with the address of a Thumb function
+        regs.regs[15] = 0x0800_0201;
+        write_core_registers(&mut iris, 3, &regs).unwrap();
+        let write = &received(&server)[3];
+        assert_eq!(write["method"], "resource_write");
+        assert_eq!(write["params"]["data"][15], 0x0800_0200);
+    }
+
+    #[test]
+    fn registers_reported_as_two_words_keep_the_low_one() {
+        assert_eq!(narrow("R0", &[0x1234_5678, 0]), 0x1234_5678);
+        // Bits gdb can't see are warned about, not kept
+        assert_eq!(narrow("R0", &[0x1234_5678, 1]), 0x1234_5678);
+        assert_eq!(narrow("R0", &[]), 0);
     }
-    fn flush(&mut self) -> Result<(), Self::Error> {
-        self.write.flush()
+
+    #[test]
+    fn xpsr_write_keeps_the_exception_number() {
+        // Halted in the HardFault handler, in Thumb state
+        let (mut iris, server) = FastModelIris::scripted(&[
+            reply(0, core_list()),
+            reply(1, json!({"data": [0x0100_0003]})),
+            reply(2, json!(null)),
+        ]);
+        let mut regs = GuestState::default();
+        // `set $xpsr = 0xf10001ff` sets the flags and tries to change IPSR
+        regs.regs[XPSR_INDEX] = 0xf100_01ff;
+        write_core_registers(&mut iris, 3, &regs).unwrap();
+        let requests = received(&server);
+        assert_eq!(requests[1]["params"]["rscIds"], json!([16]));
+        assert_eq!(requests[2]["params"]["data"][16], 0xf100_0003u32);
     }
-    fn read(&mut self) -> Result<u8, Self::Error> {
-        self.rx
-            .recv()
-            .map_err(|_| std::io::ErrorKind::ConnectionReset)?
+
+    #[test]
+    fn set_pc_moves_a_thumb_core() {
+        // The model as it is after the writes below, for the read after them
+        let mut values: Vec<u64> = vec![0; 17];
+        values[15] = 0x0800_0200;
+        values[16] = 1 << 24;
+        let (mut iris, server) = FastModelIris::scripted(&[
+            reply(0, core_list()),
+            reply(1, json!(null)),
+            reply(2, json!({"data": [0]})),
+            reply(3, json!(null)),
+            reply(4, json!({ "data": values })),
+        ]);
+        // The address of a Thumb function, with bit 0 set
+        iris.set_pc(3, 0x0800_0201).unwrap();
+        let mut regs = GuestState::default();
+        read_core_registers(&mut iris, 3, false, &mut regs).unwrap();
+        assert_eq!(regs.pc(), 0x0800_0200);
+        let requests = received(&server);
+        let writes: Vec<_> = requests
+            .iter()
+            .filter(|req| req["method"] == "resource_write")
+            .map(|req| {
+                (
+                    req["params"]["rscIds"].clone(),
+                    req["params"]["data"].clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            writes,
+            [
+                (json!([15]), json!([0x0800_0200])),
+                (json!([16]), json!([1 << 24]))
+            ]
+        );
     }
-    fn peek(&mut self) -> Result<Option<u8>, Self::Error> {
-        match self.rx.try_recv() {
-            Ok(res) => res.map(Some),
-            Err(_) => Ok(None),
+
+    #[test]
+    fn stubs_for_two_cores_run_on_two_threads() {
+        let answer: Arc<Answer> = Arc::new(cores);
+        let threads: Vec<_> = [1u32, 2]
+            .iter()
+            .map(|&instance_id| {
+                let answer = answer.clone();
+                std::thread::spawn(move || {
+                    // A connection for each core, as `--separate` serves them
+                    let mut iris = FastModelIris::answered(answer);
+                    let mut stub = IrisGdbStub::from_instance(&mut iris, instance_id).unwrap();
+                    for _ in 0..20 {
+                        let mut regs = GuestState::default();
+                        assert!(stub.read_registers(&mut regs).is_ok());
+                        assert_eq!(regs.regs[0], instance_id << 8);
+                        assert_eq!(regs.regs[12], instance_id << 8 | 12);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
         }
     }
+
+    #[test]
+    fn detach_takes_out_every_breakpoint() {
+        // The breakpoints set in the model, its memory by halfword and
+        // whether it was run with no breakpoints left
+        let set = Arc::new(Mutex::new(HashSet::new()));
+        let memory = Arc::new(Mutex::new(HashMap::new()));
+        let resumed_clean = Arc::new(AtomicBool::new(false));
+        let answer = {
+            let (set, memory, resumed_clean) = (set.clone(), memory.clone(), resumed_clean.clone());
+            move |method: &str, params: &Value| {
+                let address = params["address"].as_u64();
+                match method {
+                    "breakpoint_set" => {
+                        set.lock().unwrap().insert(address.unwrap());
+                        Ok(json!(address))
+                    }
+                    "breakpoint_delete" => {
+                        set.lock()
+                            .unwrap()
+                            .remove(&params["bptId"].as_u64().unwrap());
+                        Ok(json!(null))
+                    }
+                    // Unwritten memory holds NOPs
+                    "memory_read" => {
+                        let unit = memory.lock().unwrap().get(&address).copied();
+                        Ok(json!({"data": [unit.unwrap_or(0xbf00)]}))
+                    }
+                    "memory_write" => {
+                        let unit = params["data"][0].as_u64().unwrap();
+                        memory.lock().unwrap().insert(address, unit);
+                        Ok(json!({}))
+                    }
+                    "simulationTime_run" => {
+                        let clean = set.lock().unwrap().is_empty()
+                            && memory.lock().unwrap().values().all(|&unit| unit != 0xbe00);
+                        resumed_clean.store(clean, Ordering::SeqCst);
+                        Ok(json!(null))
+                    }
+                    _ => cores(method, params),
+                }
+            }
+        };
+        let mut iris = FastModelIris::answered(Arc::new(answer));
+        let mut stub = IrisGdbStub::builder(&mut iris, 1)
+            .on_detach(SessionEnd::Resume)
+            .build()
+            .unwrap();
+        for &addr in &[0x100, 0x200] {
+            assert!(matches!(stub.add_hw_breakpoint(addr, 2), Ok(true)));
+        }
+        assert!(matches!(stub.add_sw_breakpoint(0x300, 2), Ok(true)));
+        assert_eq!(set.lock().unwrap().len(), 2);
+        assert_eq!(memory.lock().unwrap()[&Some(0x300)], 0xbe00);
+        stub.end_session(&DisconnectReason::Disconnect).unwrap();
+        assert!(set.lock().unwrap().is_empty());
+        assert!(stub.iris.breakpoints.is_empty());
+        assert_eq!(memory.lock().unwrap()[&Some(0x300)], 0xbf00);
+        // Only once the breakpoints are out is the model resumed
+        assert!(resumed_clean.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn exceptions_are_named_by_number() {
+        let names: Vec<_> = (0..8).map(exception_name).collect();
+        assert_eq!(
+            names,
+            [
+                "Thread mode",
+                "Reset",
+                "NMI",
+                "HardFault",
+                "MemManage",
+                "BusFault",
+                "UsageFault",
+                "SecureFault"
+            ]
+        );
+        assert_eq!(exception_name(11), "SVCall");
+        assert_eq!(exception_name(15), "SysTick");
+        assert_eq!(exception_name(9), "reserved exception 9");
+        assert_eq!(exception_name(16), "IRQ 0");
+        assert_eq!(exception_name(16 + 239), "IRQ 239");
+        assert_eq!(exception_context(0x0100_0013), "Handler mode, IRQ 3");
+        assert_eq!(exception_context(0x0100_0000), "Thread mode");
+    }
+
+    #[test]
+    fn vfp_resources_map_to_their_fpu_index() {
+        assert_eq!(fpu_register_index("S0"), Some(0));
+        assert_eq!(fpu_register_index("S31"), Some(31));
+        assert_eq!(fpu_register_index("FPSCR"), Some(32));
+        // Not single precision registers of the VFP
+        assert_eq!(fpu_register_index("S32"), None);
+        assert_eq!(fpu_register_index("SP"), None);
+        assert_eq!(fpu_register_index("S"), None);
+        assert_eq!(fpu_register_index("D0"), None);
+    }
+
+    /// A core whose registers hold `0x100` above their resource id, with
+    /// RAM at 0x2000_0000
+    fn mock_core() -> MockIris {
+        MockIris::default()
+            .with_resources(1, core_list(), |id| 0x100 + id)
+            .with_memory(0x2000_0000, &[0x11, 0x22, 0x33, 0x44])
+    }
+
+    #[test]
+    fn mock_core_is_read_like_a_model() {
+        let mut iris = mock_core();
+        let mut stub = IrisGdbStub::from_instance(&mut iris, 1).unwrap();
+        let mut regs = GuestState::default();
+        assert!(stub.read_registers(&mut regs).is_ok());
+        assert_eq!(regs.regs[0], 0x100);
+        assert_eq!(regs.regs[13], 0x10d);
+        // The PC is shown halfword aligned
+        assert_eq!(regs.pc(), 0x10e);
+        assert!(regs.fpu.is_none());
+        // A read stops at the first address that faults
+        let mut data = [0; 8];
+        assert!(matches!(stub.read_addrs(0x2000_0002, &mut data), Ok(2)));
+        assert_eq!(data[..2], [0x33, 0x44]);
+        assert!(stub.read_addrs(0x1000, &mut data).is_err());
+    }
+
+    #[test]
+    fn mock_core_keeps_the_exception_number_on_writes() {
+        let mut iris = mock_core();
+        let mut stub = IrisGdbStub::from_instance(&mut iris, 1).unwrap();
+        let mut regs = GuestState::default();
+        regs.regs[4] = 0x1234;
+        regs.regs[XPSR_INDEX] = 0xf100_0000;
+        assert!(stub.write_registers(&regs).is_ok());
+        assert_eq!(stub.iris.values[&(1, 4)], [0x1234]);
+        // XPSR reads 0x110 on the mock, whose low bits are the IPSR
+        assert_eq!(stub.iris.values[&(1, 16)], [0xf100_0110]);
+    }
+
+    #[test]
+    fn mock_core_breakpoints_are_taken_out_on_drop() {
+        let mut iris = mock_core();
+        let mut stub = IrisGdbStub::from_instance(&mut iris, 1).unwrap();
+        assert!(matches!(stub.add_hw_breakpoint(0x2000_0000, 2), Ok(true)));
+        assert!(matches!(stub.add_sw_breakpoint(0x2000_0002, 2), Ok(true)));
+        assert_eq!(stub.iris.breakpoints.len(), 1);
+        assert_eq!(stub.iris.memory[&0x2000_0002], BKPT[0]);
+        // Software breakpoints can't be set where there's no memory
+        assert!(matches!(stub.add_sw_breakpoint(0x1000, 2), Ok(false)));
+        drop(stub);
+        assert!(iris.breakpoints.is_empty());
+        assert_eq!(iris.memory[&0x2000_0002], 0x33);
+        assert_eq!(iris.memory[&0x2000_0003], 0x44);
+    }
 }
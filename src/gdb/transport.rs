@@ -0,0 +1,279 @@
+//! The connections to gdb: over stdin and stdout, with a thread reading
+//! stdin, or over TCP. These need `std`, and are left out without the `std`
+//! feature.
+//!
+//! gdbstub writes its packets a byte at a time and flushes each one once
+//! it is complete, so both connections hold the bytes until the flush.
+//! Both links are reliable, and gdbstub offers gdb `QStartNoAckMode`, which
+//! gdb takes up to stop both sides acknowledging every packet. Until then,
+//! an acknowledgement is sent on its own as soon as it is written, since
+//! gdbstub doesn't flush it before handling the packet, and gdb resends a
+//! packet that isn't acknowledged in time.
+
+use std::collections::VecDeque;
+use std::io::{Error as IOError, ErrorKind, Read, Stdin, Stdout, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::Mutex;
+use std::thread::spawn;
+use std::time::Duration;
+
+use gdbstub::conn::{Connection, ConnectionExt};
+
+/// Whether `byte` is an acknowledgement written to the empty buffer `out`.
+/// Packets start with `$`, so a `+` there is never part of one.
+fn is_ack(out: &[u8], byte: u8) -> bool {
+    out.is_empty() && byte == b'+'
+}
+
+/// What the thread reading stdin sends: as many bytes as one read returned
+type Chunk = Result<Vec<u8>, IOError>;
+
+/// The most bytes the thread reading stdin takes in one read. gdb sends
+/// whole packets at once, so a packet generally arrives as one chunk.
+const READ_CHUNK: usize = 4096;
+
+pub struct GdbOverPipe {
+    rx: Receiver<Chunk>,
+    /// Bytes taken from `rx` but not read yet, in order
+    pending: VecDeque<u8>,
+    write: Stdout,
+    /// The bytes written since the last flush
+    out: Vec<u8>,
+}
+
+/// The receiving end of the thread reading stdin, with the bytes taken from
+/// it but not read, while no `GdbOverPipe` holds it. A read from stdin
+/// can't be interrupted, so a reader thread can't be stopped when its pipe
+/// is dropped; left running, it would take the first bytes meant for the
+/// next pipe. Instead one thread reads stdin for the whole process, and
+/// each pipe hands the receiver back here when it is dropped.
+type StdinBytes = (Receiver<Chunk>, VecDeque<u8>);
+static STDIN_BYTES: Mutex<Option<StdinBytes>> = Mutex::new(None);
+
+impl GdbOverPipe {
+    /// Talk to gdb over stdin and stdout. The thread reading stdin is
+    /// started by the first pipe and shared with the pipes created after it
+    /// is dropped.
+    pub fn new(read: Stdin, write: Stdout) -> Self {
+        let (rx, pending) = STDIN_BYTES
+            .lock()
+            .ok()
+            .and_then(|mut rx| rx.take())
+            .unwrap_or_else(|| (Self::spawn_reader(read), VecDeque::new()));
+        Self {
+            rx,
+            pending,
+            write,
+            out: Vec::new(),
+        }
+    }
+
+    fn spawn_reader(read: Stdin) -> Receiver<Chunk> {
+        let (tx, rx) = channel();
+        // The thread ends, dropping `tx`, at end of file, on a read error or
+        // once the receiver is gone. `read` and `peek` then see the channel
+        // disconnected.
+        spawn(move || {
+            let mut buf = vec![0u8; READ_CHUNK];
+            let mut read = read;
+            loop {
+                let res = match read.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => tx.send(Ok(buf[..n].to_vec())),
+                    Err(error) => {
+                        let _ = tx.send(Err(error));
+                        break;
+                    }
+                };
+                if res.is_err() {
+                    break;
+                }
+            }
+        });
+        rx
+    }
+
+    /// Wait up to `timeout` for a byte from gdb without consuming it, so a
+    /// loop waiting on both gdb and the model can block here instead of
+    /// spinning on `peek`. Returns `None` if nothing arrived in time.
+    pub fn peek_timeout(&mut self, timeout: Duration) -> Result<Option<u8>, IOError> {
+        if self.pending.is_empty() {
+            match self.rx.recv_timeout(timeout) {
+                Ok(chunk) => self.pending.extend(chunk?),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Err(ErrorKind::UnexpectedEof.into()),
+            }
+        }
+        Ok(self.pending.front().copied())
+    }
+
+    /// Like `read`, but give up with `None` after `timeout`.
+    pub fn read_timeout(&mut self, timeout: Duration) -> Result<Option<u8>, IOError> {
+        self.peek_timeout(timeout)?;
+        Ok(self.pending.pop_front())
+    }
+}
+
+impl Drop for GdbOverPipe {
+    /// Hand the stdin receiver back for the next pipe, with the bytes this
+    /// pipe took from it but didn't read.
+    fn drop(&mut self) {
+        let _ = Connection::flush(self);
+        let (_, unused) = channel();
+        let rx = std::mem::replace(&mut self.rx, unused);
+        let pending = std::mem::take(&mut self.pending);
+        if let Ok(mut slot) = STDIN_BYTES.lock() {
+            slot.get_or_insert((rx, pending));
+        }
+    }
+}
+
+impl Connection for GdbOverPipe {
+    type Error = IOError;
+    fn write(&mut self, byte: u8) -> Result<(), Self::Error> {
+        let ack = is_ack(&self.out, byte);
+        self.out.push(byte);
+        if ack {
+            Connection::flush(self)?;
+        }
+        Ok(())
+    }
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        self.out.extend_from_slice(buf);
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.write.write_all(&self.out)?;
+        self.out.clear();
+        self.write.flush()
+    }
+}
+
+impl ConnectionExt for GdbOverPipe {
+    fn read(&mut self) -> Result<u8, Self::Error> {
+        // The reader thread never sends an empty chunk
+        while self.pending.is_empty() {
+            let chunk = self.rx.recv().map_err(|_| ErrorKind::UnexpectedEof)?;
+            self.pending.extend(chunk?);
+        }
+        Ok(self.pending.pop_front().unwrap())
+    }
+    /// Doesn't block: only the chunks the reader thread has already sent
+    /// are looked at
+    fn peek(&mut self) -> Result<Option<u8>, Self::Error> {
+        if self.pending.is_empty() {
+            match self.rx.try_recv() {
+                Ok(chunk) => self.pending.extend(chunk?),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => return Err(ErrorKind::UnexpectedEof.into()),
+            }
+        }
+        Ok(self.pending.front().copied())
+    }
+}
+
+/// A gdb connection over TCP, for use with `target remote`
+pub struct GdbOverTcp {
+    stream: TcpStream,
+    /// The bytes written since the last flush
+    out: Vec<u8>,
+}
+
+impl GdbOverTcp {
+    /// Listen on `addr` and block until gdb connects
+    pub fn listen(addr: impl ToSocketAddrs) -> Result<Self, IOError> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            out: Vec::new(),
+        })
+    }
+}
+
+impl Drop for GdbOverTcp {
+    fn drop(&mut self) {
+        let _ = Connection::flush(self);
+    }
+}
+
+impl Connection for GdbOverTcp {
+    type Error = IOError;
+    fn write(&mut self, byte: u8) -> Result<(), Self::Error> {
+        let ack = is_ack(&self.out, byte);
+        self.out.push(byte);
+        if ack {
+            Connection::flush(self)?;
+        }
+        Ok(())
+    }
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        self.out.extend_from_slice(buf);
+        Ok(())
+    }
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        // The stream is left non-blocking by `peek`
+        self.stream.set_nonblocking(false)?;
+        Write::write_all(&mut self.stream, &self.out)?;
+        self.out.clear();
+        Write::flush(&mut self.stream)
+    }
+}
+
+impl ConnectionExt for GdbOverTcp {
+    fn read(&mut self) -> Result<u8, Self::Error> {
+        let mut byte = [0u8];
+        self.stream.set_nonblocking(false)?;
+        Read::read_exact(&mut self.stream, &mut byte)?;
+        Ok(byte[0])
+    }
+    fn peek(&mut self) -> Result<Option<u8>, Self::Error> {
+        let mut byte = [0u8];
+        self.stream.set_nonblocking(true)?;
+        match self.stream.peek(&mut byte) {
+            Ok(0) => Err(ErrorKind::ConnectionReset.into()),
+            Ok(_) => Ok(Some(byte[0])),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipes_share_one_reader() {
+        // Stand in for the thread reading stdin, which is then never started
+        let (tx, rx) = channel();
+        *STDIN_BYTES.lock().unwrap() = Some((rx, VecDeque::new()));
+        for _ in 0..100 {
+            drop(GdbOverPipe::new(std::io::stdin(), std::io::stdout()));
+        }
+        tx.send(Ok(b"+$g#67".to_vec())).unwrap();
+        let mut pipe = GdbOverPipe::new(std::io::stdin(), std::io::stdout());
+        assert_eq!(ConnectionExt::read(&mut pipe).unwrap(), b'+');
+        // What one pipe took but didn't read goes to the next
+        drop(pipe);
+        let mut pipe = GdbOverPipe::new(std::io::stdin(), std::io::stdout());
+        assert_eq!(pipe.peek().unwrap(), Some(b'$'));
+        drop(tx);
+        let rest: Vec<u8> = (0..5)
+            .map(|_| ConnectionExt::read(&mut pipe).unwrap())
+            .collect();
+        assert_eq!(rest, b"$g#67");
+        assert!(ConnectionExt::read(&mut pipe).is_err());
+    }
+
+    #[test]
+    fn only_a_plus_between_packets_is_an_ack() {
+        assert!(is_ack(b"", b'+'));
+        // Within a packet, such as the binary data of an `X` packet
+        assert!(!is_ack(b"$X0,1:", b'+'));
+        assert!(!is_ack(b"", b'-'));
+        assert!(!is_ack(b"", b'$'));
+    }
+}
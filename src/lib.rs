@@ -1,28 +1,264 @@
+pub mod error {
+    use std::fmt;
+    use std::io::{Error as IOError, ErrorKind};
+
+    use serde_json::Value;
+
+    /// The ways that talking to Iris can fail
+    #[derive(Debug)]
+    pub enum IrisError {
+        /// The connection to the Iris server failed
+        Io(IOError),
+        /// The Iris server closed the connection before responding
+        ConnectionClosed,
+        /// The instance has no resource with this name
+        ResourceNotFound(String),
+        /// A memory access faulted at this address
+        MemoryFault(u64),
+        /// Memory read back after a write differs from what was written,
+        /// first at this address
+        VerifyFailed(u64),
+        /// The Iris server rejected a request or sent something unexpected
+        Protocol(String),
+        /// The Iris server did not respond in time
+        Timeout,
+        /// The model ran for this long without executing an instruction
+        Stalled(std::time::Duration),
+        /// A request was malformed before it was sent
+        InvalidArgument(String),
+        /// The model doesn't provide what was asked for
+        Unsupported(String),
+        /// A value was too wide for the resource it was written to
+        ValueTooWide {
+            resource: String,
+            bit_width: u64,
+            value: u64,
+        },
+    }
+
+    impl IrisError {
+        /// Build an error from the `error` object of an Iris response
+        pub fn from_response(error: &Value) -> Self {
+            match (error["code"].as_i64(), error["message"].as_str()) {
+                (Some(code), Some(message)) => {
+                    Self::Protocol(format!("{} (code {})", message, code))
+                }
+                _ => Self::Protocol(error.to_string()),
+            }
+        }
+    }
+
+    impl fmt::Display for IrisError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::Io(e) => write!(f, "Iris connection failed: {}", e),
+                Self::ConnectionClosed => write!(f, "Connection closed before response"),
+                Self::ResourceNotFound(name) => write!(f, "No resource named {}", name),
+                Self::MemoryFault(addr) => write!(f, "Memory access faulted at {:#x}", addr),
+                Self::VerifyFailed(addr) => {
+                    write!(f, "Memory differs from what was written at {:#x}", addr)
+                }
+                Self::Protocol(msg) => write!(f, "Iris error: {}", msg),
+                Self::Timeout => write!(f, "Timed out waiting for Iris"),
+                Self::Stalled(time) => write!(
+                    f,
+                    "The target appears stuck: no instructions executed in {:?}",
+                    time
+                ),
+                Self::InvalidArgument(msg) => write!(f, "Invalid argument: {}", msg),
+                Self::Unsupported(msg) => write!(f, "Unsupported: {}", msg),
+                Self::ValueTooWide {
+                    resource,
+                    bit_width,
+                    value,
+                } => write!(
+                    f,
+                    "{:#x} does not fit in the {} bit resource {}",
+                    value, bit_width, resource
+                ),
+            }
+        }
+    }
+
+    impl std::error::Error for IrisError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                Self::Io(e) => Some(e),
+                _ => None,
+            }
+        }
+    }
+
+    impl From<IOError> for IrisError {
+        fn from(e: IOError) -> Self {
+            match e.kind() {
+                ErrorKind::TimedOut | ErrorKind::WouldBlock => Self::Timeout,
+                ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset => Self::ConnectionClosed,
+                _ => Self::Io(e),
+            }
+        }
+    }
+
+    impl From<serde_json::Error> for IrisError {
+        fn from(e: serde_json::Error) -> Self {
+            Self::Protocol(e.to_string())
+        }
+    }
+}
+
 pub mod iris_client {
     use std::collections::{HashMap, HashSet};
     use std::ffi::OsStr;
     use std::io::{BufRead, BufReader, Error as IOError, Write};
     use std::marker::PhantomData;
-    use std::net::{SocketAddr, TcpStream};
+    use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+    use std::path::Path;
     use std::process::{Child, Command, Stdio};
     use std::str::FromStr;
-    use std::time::Instant;
+    use std::sync::mpsc::{channel, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
 
     use bufstream::BufStream;
     use serde::{de::DeserializeOwned, Deserialize, Serialize};
     use serde_json;
 
-    use crate::instance_registry;
+    use crate::{
+        breakpoint, checkpoint, event, event_stream, instance_registry, memory, resource,
+        simulation, simulation_time, step, IrisError,
+    };
 
     /// An Iris connection to a fast model.
+    ///
+    /// Each request is sent, and its reply waited for, within a call taking
+    /// `&mut self`, so the requests of one connection never interleave on
+    /// the socket; replies that arrive out of turn are kept by message id
+    /// for the request they answer. A connection is `Send`, so threads can
+    /// share one as a `SharedIris`, holding the lock for each request.
+    /// Threads that drive a model concurrently, such as gdb stubs for
+    /// different cores, are better off each opening their own connection
+    /// to the server, with `connect` and the address from `peer_addr`, so
+    /// that a long wait in one doesn't hold up the others. Iris keeps the
+    /// clients apart, routing each reply and event to the client that
+    /// asked for it, and breakpoints and event streams belong to the
+    /// connection that made them. The simulation is still shared: running
+    /// it from one connection runs every core.
     pub struct FastModelIris {
         proc: Option<Child>,
         ipc: BufStream<TcpStream>,
         inst_id: Option<u32>,
         pub startup_time: Instant,
         current_msg_id: u32,
-        callbacks: HashMap<String, Box<dyn FnMut(serde_json::Value) -> Result<(), IOError>>>,
+        callbacks: HashMap<String, Callback>,
+        /// Event stream ids, with the instance they belong to and where to
+        /// send their events
+        subscriptions: HashMap<u64, (u32, Sender<event::Event>)>,
+        /// The event streams made by `subscribe` and `subscribe_callback`,
+        /// by the id they were first given, so that `reconnect` can make
+        /// them again
+        streams: HashMap<u64, Stream>,
+        /// Resource lists by instance id, filled by `resource::cached_list`
+        pub(crate) resources: HashMap<u32, Arc<Vec<resource::ResourceInfo>>>,
+        /// Breakpoints set through `breakpoint`, by instance and the
+        /// breakpoint id they were first given, so they can be removed when
+        /// the connection is dropped and set again by `reconnect`
+        pub(crate) breakpoints: HashMap<(u32, u64), breakpoint::Recorded>,
+        /// Where the messages to and from Iris are logged, see `set_trace`
+        trace: Option<Box<dyn Write + Send>>,
+        /// Responses that arrived while waiting on other requests, by
+        /// message id
+        responses: HashMap<u64, Reply>,
+        /// The start of a line cut short by a read timeout
+        partial: String,
+        /// The most bytes of memory moved by a single request
+        pub(crate) max_request_bytes: usize,
+        /// The read timeout set by `set_response_timeout`, applied again
+        /// to the connection made by `reconnect`
+        response_timeout: Option<Duration>,
+        /// The Iris server's address, for `reconnect`
+        peer: Option<SocketAddr>,
+        /// How many times `reconnect` tries to connect; 0 when lost
+        /// connections aren't reestablished on their own
+        reconnect_attempts: u32,
+        /// Whether `reconnect` is running, so that the requests it makes
+        /// don't reconnect in turn
+        reconnecting: bool,
+        /// Whether the last `reconnect` failed, possibly leaving breakpoints
+        /// or event streams unmade. Requests then fail rather than go on
+        /// without them.
+        broken: bool,
+    }
+
+    /// How an event stream was made, so that `reconnect` can make it again
+    struct Stream {
+        instance_id: u32,
+        /// The name of the event source
+        source: String,
+        /// The callback the events go to, for streams made by
+        /// `subscribe_callback`
+        func: Option<String>,
+        /// The id Iris knows the stream by, which changes when it's made
+        /// again
+        current: u64,
+    }
+
+    /// How long `reconnect` waits after its first failed attempt; each
+    /// further attempt waits this much longer
+    const RECONNECT_DELAY: Duration = Duration::from_millis(200);
+
+    /// The Thumb execution state bit of the Armv7-M XPSR
+    const XPSR_T: u64 = 1 << 24;
+
+    /// The Armv8-M Debug Security Control and Status Register, for models
+    /// that don't have it as a resource
+    const DSCSR_ADDR: u64 = 0xE000_EE08;
+
+    /// The Current Domain Secure bit of DSCSR
+    const DSCSR_CDS: u64 = 1 << 16;
+
+    /// The shortest and longest pause between two polls of a running
+    /// simulation; see `Backoff`
+    const POLL_MIN: Duration = Duration::from_millis(1);
+    const POLL_MAX: Duration = Duration::from_millis(20);
+
+    /// The pause between polls of a running simulation, doubling from
+    /// `POLL_MIN` up to `POLL_MAX`, so that a long run doesn't flood the
+    /// connection and the trace while a short one still ends promptly.
+    pub(crate) struct Backoff(Duration);
+
+    impl Backoff {
+        pub(crate) fn new() -> Self {
+            Self(POLL_MIN)
+        }
+
+        /// Sleep before the next poll.
+        pub(crate) fn wait(&mut self) {
+            std::thread::sleep(self.0);
+            self.0 = (self.0 * 2).min(POLL_MAX);
+        }
     }
+
+    /// Longest message logged in full by the trace; the rest is cut short
+    const TRACE_LIMIT: usize = 512;
+
+    /// A handler for an Iris event, invoked with the event's parameters.
+    pub type Callback = Box<dyn FnMut(serde_json::Value) -> Result<(), IrisError> + Send>;
+
+    /// The result of a request as Iris answered it, an error for an error
+    /// response
+    type Reply = Result<serde_json::Value, IrisError>;
+
+    /// A connection shared between threads. Lock it for each request, or
+    /// for as long as a sequence of requests must not be split up, such as
+    /// setting up a step and waiting for it.
+    pub type SharedIris = Arc<Mutex<FastModelIris>>;
+
+    // A connection must stay `Send` for `SharedIris` to be shared
+    const _: fn() = || {
+        fn assert_send<T: Send>() {}
+        assert_send::<FastModelIris>();
+    };
+
     pub struct RpcReq<'a, S> {
         pub method: &'a str,
         pub params: &'a S,
@@ -36,6 +272,8 @@ pub mod iris_client {
         id: u64,
     }
 
+    // The variants are tried in order, and with its defaulted `result` a
+    // `Responce` matches any message with an id, so `Error` comes first
     #[derive(Deserialize, Debug)]
     #[serde(untagged)]
     pub enum RpcRes {
@@ -44,6 +282,10 @@ pub mod iris_client {
             #[serde(default)]
             params: serde_json::Value,
         },
+        Error {
+            error: serde_json::Value,
+            id: u64,
+        },
         Responce {
             // some functions have no return value; others return Null. We treat
             // those the same
@@ -51,10 +293,6 @@ pub mod iris_client {
             result: serde_json::Value,
             id: u64,
         },
-        Error {
-            error: serde_json::Value,
-            id: u64,
-        },
     }
 
     #[allow(unused)]
@@ -84,9 +322,19 @@ pub mod iris_client {
         type Out: DeserializeOwned + std::fmt::Debug;
     }
 
+    /// The parameters of a `FastModelIris::request`, answered with whatever
+    /// Iris returns
+    #[derive(Serialize)]
+    #[serde(transparent)]
+    struct RawParams(serde_json::Value);
+
+    impl IrisOut for RawParams {
+        type Out = serde_json::Value;
+    }
+
     impl FastModelIris {
         /// Construct a Fast Model from command line arguments
-        pub fn from_args<I, S>(args: I) -> Result<Self, IOError>
+        pub fn from_args<I, S>(args: I) -> Result<Self, IrisError>
         where
             I: IntoIterator<Item = S>,
             S: AsRef<OsStr>,
@@ -114,41 +362,94 @@ pub mod iris_client {
             }
         }
 
-        pub fn from_port(proc: Option<Child>, portnum: u16) -> Result<Self, IOError> {
-            let startup_time = Instant::now();
+        pub fn from_port(proc: Option<Child>, portnum: u16) -> Result<Self, IrisError> {
             let ipc = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], portnum)))?;
-            let ipc = BufStream::new(ipc);
-            Ok(Self {
+            Ok(Self::from_stream(proc, ipc))
+        }
+
+        fn from_stream(proc: Option<Child>, ipc: TcpStream) -> Self {
+            Self {
                 proc,
-                ipc,
+                peer: ipc.peer_addr().ok(),
+                ipc: BufStream::new(ipc),
                 inst_id: None,
-                startup_time,
+                startup_time: Instant::now(),
                 current_msg_id: 0,
                 callbacks: HashMap::new(),
-            })
+                subscriptions: HashMap::new(),
+                streams: HashMap::new(),
+                resources: HashMap::new(),
+                breakpoints: HashMap::new(),
+                trace: None,
+                responses: HashMap::new(),
+                partial: String::new(),
+                max_request_bytes: memory::MAX_REQUEST_BYTES,
+                response_timeout: None,
+                reconnect_attempts: 0,
+                reconnecting: false,
+                broken: false,
+            }
+        }
+
+        /// Connect to a running Iris server, such as a model started with
+        /// `-I`, and register with it.
+        pub fn connect(addr: impl ToSocketAddrs) -> Result<Self, IrisError> {
+            let mut iris = Self::from_stream(None, TcpStream::connect(addr)?);
+            iris.register()?;
+            Ok(iris)
+        }
+
+        /// Like `connect`, but give up on an address when connecting to it or
+        /// registering with it takes longer than `timeout`.
+        pub fn connect_timeout(
+            addr: impl ToSocketAddrs,
+            timeout: Duration,
+        ) -> Result<Self, IrisError> {
+            let mut last_err = None;
+            for addr in addr.to_socket_addrs()? {
+                let ipc = match TcpStream::connect_timeout(&addr, timeout) {
+                    Ok(ipc) => ipc,
+                    Err(e) => {
+                        last_err = Some(e.into());
+                        continue;
+                    }
+                };
+                ipc.set_read_timeout(Some(timeout))?;
+                let mut iris = Self::from_stream(None, ipc);
+                match iris.register() {
+                    Ok(_) => {
+                        // Responses to later requests, such as waiting on
+                        // the simulation, may legitimately take a while
+                        iris.ipc.get_ref().set_read_timeout(None)?;
+                        return Ok(iris);
+                    }
+                    Err(e) => last_err = Some(e),
+                }
+            }
+            Err(last_err.unwrap_or_else(|| {
+                IrisError::InvalidArgument("No address to connect to".to_string())
+            }))
         }
 
         /// Register this struct as a component within Iris within the attached fast
         /// model. This will negotiate protocl, version and serialization formats.
-        pub fn register(&mut self) -> Result<u32, IOError> {
+        pub fn register(&mut self) -> Result<u32, IrisError> {
             // Send initial Handshake, including supported serialization.
             self.ipc
-                .write(b"CONNECT / IrisRpc/1.0\r\nSupported-Formats: IrisJson\r\n\r\n")?;
+                .write_all(b"CONNECT / IrisRpc/1.0\r\nSupported-Formats: IrisJson\r\n\r\n")?;
             self.ipc.flush()?;
             // Assert that the Iris server supportes the serialization formats that
             // we can send.
             match self.read_formats()? {
                 None => {
-                    return Err(IOError::new(
-                        std::io::ErrorKind::Other,
-                        "The Iris server hug up before completing the handshake",
+                    return Err(IrisError::Protocol(
+                        "The Iris server hug up before completing the handshake".to_string(),
                     ))
                 }
                 Some(formats) => {
                     if !formats.contains(&"IrisJson".to_string()) {
-                        return Err(IOError::new(
-                            std::io::ErrorKind::Other,
-                            "The Iris server does not support IrisJson",
+                        return Err(IrisError::Protocol(
+                            "The Iris server does not support IrisJson".to_string(),
                         ));
                     }
                 }
@@ -162,13 +463,13 @@ pub mod iris_client {
         }
 
         #[doc(hidden)]
-        fn read_formats(&mut self) -> Result<Option<Vec<String>>, IOError> {
+        fn read_formats(&mut self) -> Result<Option<Vec<String>>, IrisError> {
             for line in BufReader::new(&mut self.ipc).lines() {
                 let line = line?;
                 if let Some(formats) = line.strip_prefix("Supported-Formats: ") {
                     let formats = formats
                         .split_ascii_whitespace()
-                        .map(|x| x.trim_end_matches(",").to_string());
+                        .map(|x| x.trim_end_matches(',').to_string());
                     return Ok(Some(formats.collect()));
                 }
             }
@@ -181,13 +482,10 @@ pub mod iris_client {
         pub fn send<'a, M: Serialize + 'a, I: Into<RpcReq<'a, M>>>(
             &mut self,
             message: I,
-        ) -> Result<MessageHandle<M>, IOError> {
+        ) -> Result<MessageHandle<M>, IrisError> {
             let input = vec![message.into()];
             let output = self.send_many(input)?;
-            for v in output.into_iter() {
-                return Ok(v);
-            }
-            unreachable!()
+            Ok(output.into_iter().next().unwrap())
         }
 
         /// Send a batch of messages to Iris within the Fast Model. This returns a
@@ -196,12 +494,15 @@ pub mod iris_client {
         pub fn send_many<'a, Itr, Itm, M>(
             &mut self,
             messages: Itr,
-        ) -> Result<Vec<MessageHandle<M>>, IOError>
+        ) -> Result<Vec<MessageHandle<M>>, IrisError>
         where
             Itr: IntoIterator<Item = Itm>,
             Itm: Into<RpcReq<'a, M>>,
             M: Serialize + 'a,
         {
+            if self.broken {
+                return Err(IrisError::ConnectionClosed);
+            }
             let mut res = Vec::new();
             for msg in messages.into_iter() {
                 let RpcReq { method, params } = msg.into();
@@ -213,121 +514,476 @@ pub mod iris_client {
                 };
                 self.current_msg_id += 1;
                 let msg_text = serde_json::to_string(&msg).unwrap();
-                //eprintln!("-> {:?}", msg_text);
+                self.log("->", &msg_text);
                 res.push(MessageHandle(msg.id, PhantomData));
-                write!(self.ipc, "IrisJson:{}:{}\n", msg_text.len(), msg_text)?;
+                writeln!(self.ipc, "IrisJson:{}:{}", msg_text.len(), msg_text)
+                    .map_err(|e| self.recover(e.into()))?;
             }
-            self.ipc.flush()?;
+            self.ipc.flush().map_err(|e| self.recover(e.into()))?;
             Ok(res)
         }
 
-        /// Wait for a message with the specified handle. Throws away all other
-        /// messages that are read from the channel.
+        /// Wait for a message with the specified handle. Responses to other
+        /// requests read meanwhile are kept for their own `wait`.
         pub fn wait<M: IrisOut>(
             &mut self,
             msg: MessageHandle<M>,
-        ) -> Result<<M as IrisOut>::Out, IOError> {
+        ) -> Result<<M as IrisOut>::Out, IrisError> {
             let input = vec![msg];
             let output = self.wait_for_many(input)?;
-            for v in output.into_iter() {
-                return Ok(v);
-            }
-            Err(IOError::new(
-                std::io::ErrorKind::Other,
-                "Connection closed before response",
-            ))
+            output.into_iter().next().ok_or(IrisError::ConnectionClosed)
         }
 
-        /// Wait for all messages within the specified handle set. Throws away all other
-        /// messages that are read from the channel.
-        pub fn wait_for_many<I, M>(&mut self, msgs: I) -> Result<Vec<<M as IrisOut>::Out>, IOError>
+        /// Wait for all messages within the specified handle set, returning
+        /// their results in the order of the handles. Responses to other
+        /// requests are kept for the `wait` on their own handle.
+        pub fn wait_for_many<I, M>(
+            &mut self,
+            msgs: I,
+        ) -> Result<Vec<<M as IrisOut>::Out>, IrisError>
         where
             I: IntoIterator<Item = MessageHandle<M>>,
             M: IrisOut,
         {
-            let mut msgs = msgs
-                .into_iter()
-                .map(|MessageHandle(id, ..)| id)
-                .collect::<HashSet<_>>();
-            if msgs.len() < 1 {
-                return Ok(Vec::new());
+            let ids: Vec<u64> = msgs.into_iter().map(|MessageHandle(id, ..)| id).collect();
+            // Responses may have arrived while waiting on earlier requests
+            let mut results = HashMap::with_capacity(ids.len());
+            for &id in &ids {
+                if let Some(result) = self.responses.remove(&id) {
+                    results.insert(id, result);
+                }
             }
-            let mut out = Vec::with_capacity(msgs.len());
-            for line in (&mut self.ipc).lines() {
-                let line = line?;
-                if let Some(without_header) = line.strip_prefix("IrisJson:") {
-                    let mut parts = without_header.splitn(2, ":");
-                    let size = parts.next().map(usize::from_str);
-                    let payload = parts.next();
-                    match (size, payload) {
-                        (Some(size), Some(payload)) => {
-                            let size = size.unwrap();
-                            if payload.len() == size {
-                                //eprintln!("<- {:?}",payload);
-                                let res: Result<RpcRes, _> = serde_json::from_str(payload);
-                                match res {
-                                    Ok(RpcRes::Responce { id, result, .. }) => {
-                                        if msgs.contains(&id) {
-                                            msgs.remove(&id);
-                                            out.push(serde_json::from_value(result)?);
-                                            if msgs.is_empty() {
-                                                return Ok(out);
-                                            }
-                                        } else {
-                                            eprintln!(
-                                                "Received unexpected response: {} {:#?}",
-                                                id, result
-                                            );
-                                        }
-                                    }
-                                    Ok(RpcRes::Event { method, params, .. }) => {
-                                        if let Some(cb) = self.callbacks.get_mut(&method) {
-                                            cb(params)?;
-                                        } else {
-                                            eprintln!(
-                                                "Warn: Unhandled callback {} {:#?}",
-                                                method, params
-                                            );
-                                        }
-                                    }
-                                    Ok(RpcRes::Error { error, .. }) => {
-                                        return Err(IOError::new(
-                                            std::io::ErrorKind::Other,
-                                            error.to_string(),
-                                        ))
-                                    }
-                                    Err(_e) => {
-                                        return Err(IOError::new(
-                                            std::io::ErrorKind::Other,
-                                            payload.to_string(),
-                                        ))
-                                    }
-                                }
-                            } else {
-                                eprintln!("Error: ipc length did not match computed length");
-                            }
-                        }
-                        (Some(_), None) => eprintln!("Error: ipc missing payload"),
-                        (None, Some(_)) => {
-                            unreachable!("Somehow got something afte a : but nothing before it")
+            let mut waiting: HashSet<u64> = ids
+                .iter()
+                .copied()
+                .filter(|id| !results.contains_key(id))
+                .collect();
+            while !waiting.is_empty() {
+                let line = match self.read_line().map_err(|e| self.recover(e))? {
+                    Some(line) => line,
+                    None => return Err(self.recover(IrisError::ConnectionClosed)),
+                };
+                if let Some((id, result)) = self.dispatch(&line)? {
+                    if waiting.remove(&id) {
+                        results.insert(id, result);
+                    } else {
+                        self.responses.insert(id, result);
+                    }
+                }
+            }
+            // Every response is in by now, so a failed request doesn't leave
+            // the responses to the others to be read by a later wait
+            ids.into_iter()
+                .map(|id| Ok(serde_json::from_value(results.remove(&id).unwrap()?)?))
+                .collect()
+        }
+
+        /// The address of the Iris server, or `None` if the socket couldn't
+        /// tell when connecting.
+        pub fn peer_addr(&self) -> Option<SocketAddr> {
+            self.peer
+        }
+
+        /// Reconnect to the Iris server when a request fails because the
+        /// connection was lost, making up to `attempts` attempts; 0 turns
+        /// this off, which is the default. The request that failed still
+        /// fails, as Iris may or may not have carried it out, but the
+        /// requests after it go through the new connection. See
+        /// `reconnect` for what carries over.
+        pub fn set_reconnect(&mut self, attempts: u32) {
+            self.reconnect_attempts = attempts;
+        }
+
+        /// Replace the connection to the Iris server with a new one, and
+        /// register with it again. The cached resource lists are dropped.
+        /// Event streams belong to the old registration, so they are made
+        /// again, as are the breakpoints that didn't survive the old
+        /// connection. Both keep the ids they were first given: events and
+        /// `breakpoint::delete` are translated to the ids Iris knows them
+        /// by now. If any can't be made again, every later request fails,
+        /// rather than go on with breakpoints that no longer stop the model
+        /// or events that never come. Returns the new client instance id.
+        /// Reconnection events are logged to the trace, see `set_trace`.
+        pub fn reconnect(&mut self) -> Result<u32, IrisError> {
+            let addr = self.peer.ok_or_else(|| {
+                IrisError::InvalidArgument("No Iris server address to reconnect to".to_string())
+            })?;
+            self.reconnecting = true;
+            self.broken = false;
+            let res = self.reconnect_to(addr);
+            self.reconnecting = false;
+            self.broken = res.is_err();
+            match &res {
+                Ok(client) => self.log(
+                    "--",
+                    &format!("reconnected to {} as client {}", addr, client),
+                ),
+                Err(e) => self.log("--", &format!("reconnecting to {} failed: {}", addr, e)),
+            }
+            res
+        }
+
+        fn reconnect_to(&mut self, addr: SocketAddr) -> Result<u32, IrisError> {
+            self.log("--", &format!("connection lost, reconnecting to {}", addr));
+            let attempts = self.reconnect_attempts.max(1);
+            let mut attempt = 0;
+            let ipc = loop {
+                match TcpStream::connect(addr) {
+                    Ok(ipc) => break ipc,
+                    Err(e) => {
+                        attempt += 1;
+                        self.log("--", &format!("attempt {} failed: {}", attempt, e));
+                        if attempt == attempts {
+                            return Err(e.into());
                         }
-                        (None, None) => eprintln!("Error: ipc missing length, payload"),
+                        std::thread::sleep(RECONNECT_DELAY * attempt);
                     }
-                } else {
+                }
+            };
+            ipc.set_read_timeout(self.response_timeout)?;
+            self.ipc = BufStream::new(ipc);
+            // Nothing read from the old connection can be answered anymore
+            self.partial.clear();
+            self.responses.clear();
+            self.inst_id = None;
+            let client = self.register()?;
+            self.invalidate_resources();
+            self.resync_streams()?;
+            self.resync_breakpoints()?;
+            Ok(client)
+        }
+
+        /// Make the event streams again, for the new registration.
+        fn resync_streams(&mut self) -> Result<(), IrisError> {
+            let ids: Vec<u64> = self.streams.keys().copied().collect();
+            for id in ids {
+                let stream = &self.streams[&id];
+                let (instance_id, source, func) = (
+                    stream.instance_id,
+                    stream.source.clone(),
+                    stream.func.clone(),
+                );
+                let current = self.create_stream(instance_id, &source, func)?;
+                self.streams.get_mut(&id).unwrap().current = current;
+                self.log(
+                    "--",
+                    &format!(
+                        "made event stream {} of instance {} again as {}",
+                        id, instance_id, current
+                    ),
+                );
+            }
+            Ok(())
+        }
+
+        /// Set again the breakpoints Iris no longer has, such as those
+        /// removed along with the registration of a lost connection.
+        fn resync_breakpoints(&mut self) -> Result<(), IrisError> {
+            let instances: HashSet<u32> = self.breakpoints.keys().map(|&(inst, _)| inst).collect();
+            for instance_id in instances {
+                let live: HashSet<u64> = breakpoint::list(self, instance_id)?
+                    .into_iter()
+                    .map(|info| info.id)
+                    .collect();
+                let lost: Vec<(u32, u64)> = self
+                    .breakpoints
+                    .iter()
+                    .filter(|(&(inst, _), bpt)| inst == instance_id && !live.contains(&bpt.current))
+                    .map(|(&key, _)| key)
+                    .collect();
+                for key in lost {
+                    let current =
+                        breakpoint::restore(self, key.0, &self.breakpoints[&key].clone())?;
+                    self.breakpoints.get_mut(&key).unwrap().current = current;
+                    self.log(
+                        "--",
+                        &format!(
+                            "set breakpoint {} of instance {} again as {}",
+                            key.1, key.0, current
+                        ),
+                    );
+                }
+            }
+            Ok(())
+        }
+
+        /// Give an event the ids its event stream and breakpoint were first
+        /// given, as they're known by outside the client, in place of those
+        /// Iris knows them by since `reconnect` made them again.
+        fn translate_ids(&self, params: &mut serde_json::Value) {
+            let es_id = params["esId"].as_u64();
+            if let Some((&id, _)) = self.streams.iter().find(|(_, s)| Some(s.current) == es_id) {
+                params["esId"] = id.into();
+            }
+            let instance_id = params["sInstId"].as_u64();
+            let bpt_id = params["fields"]["BPT_ID"].as_u64();
+            let first = self.breakpoints.iter().find(|(&(inst, _), bpt)| {
+                Some(u64::from(inst)) == instance_id && Some(bpt.current) == bpt_id
+            });
+            if let Some((&(_, id), _)) = first {
+                params["fields"]["BPT_ID"] = id.into();
+            }
+        }
+
+        /// Reconnect if `e` means the connection was lost and reconnecting
+        /// is turned on, and hand `e` back to be returned.
+        fn recover(&mut self, e: IrisError) -> IrisError {
+            let lost = matches!(e, IrisError::ConnectionClosed | IrisError::Io(_));
+            if lost && self.reconnect_attempts > 0 && !self.reconnecting {
+                // The outcome is logged; the request fails either way
+                let _ = self.reconnect();
+            }
+            e
+        }
+
+        /// Limit the memory reads and writes of the `memory` and `image`
+        /// modules to `bytes` per request, splitting larger ones. The
+        /// default of 4 KiB stays well within what Iris accepts; larger
+        /// requests move more data per round trip. Fails below 8 bytes, the
+        /// widest unit.
+        pub fn set_max_request_bytes(&mut self, bytes: usize) -> Result<(), IrisError> {
+            if bytes < 8 {
+                return Err(IrisError::InvalidArgument(format!(
+                    "Requests of {} bytes can't hold a whole unit",
+                    bytes
+                )));
+            }
+            self.max_request_bytes = bytes;
+            Ok(())
+        }
+
+        /// Fail with `IrisError::Timeout` when Iris sends nothing for
+        /// `timeout` while a response is awaited, rather than waiting
+        /// forever. A response that turns up after its request timed out
+        /// is set aside, so it can't be mistaken for another. Requests that
+        /// legitimately take long, such as waiting on a reset, are subject
+        /// to the timeout as well.
+        pub fn set_response_timeout(&mut self, timeout: Option<Duration>) -> Result<(), IrisError> {
+            self.ipc.get_ref().set_read_timeout(timeout)?;
+            self.response_timeout = timeout;
+            Ok(())
+        }
+
+        /// Log every message sent to and received from Iris to `out`, with
+        /// the time since the connection was made. Long messages, such as
+        /// large memory reads, are cut short.
+        pub fn set_trace(&mut self, out: impl Write + Send + 'static) {
+            self.trace = Some(Box::new(out));
+        }
+
+        /// Stop logging messages.
+        pub fn clear_trace(&mut self) {
+            self.trace = None;
+        }
+
+        fn log(&mut self, direction: &str, text: &str) {
+            let out = match &mut self.trace {
+                Some(out) => out,
+                None => return,
+            };
+            let elapsed = self.startup_time.elapsed().as_secs_f64();
+            // A failing log shouldn't fail the request being logged
+            let _ = match text.char_indices().nth(TRACE_LIMIT) {
+                Some((end, _)) => writeln!(
+                    out,
+                    "[{:>12.6}] {} {}... ({} bytes)",
+                    elapsed,
+                    direction,
+                    &text[..end],
+                    text.len()
+                ),
+                None => writeln!(out, "[{:>12.6}] {} {}", elapsed, direction, text),
+            };
+        }
+
+        /// Read a line from Iris, without its line ending. Returns `None` once
+        /// the connection is closed.
+        fn read_line(&mut self) -> Result<Option<String>, IrisError> {
+            // A read that times out keeps what it read so far in `partial`,
+            // so the line can be finished by the next read
+            if self.ipc.read_line(&mut self.partial)? == 0 && self.partial.is_empty() {
+                return Ok(None);
+            }
+            let mut line = std::mem::take(&mut self.partial);
+            let len = line.trim_end_matches(['\r', '\n']).len();
+            line.truncate(len);
+            Ok(Some(line))
+        }
+
+        /// Handle a line read from Iris. Events are passed to their
+        /// subscription or callback here, and a response is returned as its
+        /// message id and result, which is an error for an error response.
+        fn dispatch(&mut self, line: &str) -> Result<Option<(u64, Reply)>, IrisError> {
+            let without_header = match line.strip_prefix("IrisJson:") {
+                Some(without_header) => without_header,
+                None => {
                     eprintln!(
                         "Error: line from ipc in did not start with IrisJson\n{}",
                         line
                     );
+                    return Ok(None);
+                }
+            };
+            let mut parts = without_header.splitn(2, ':');
+            let size = parts.next().map(usize::from_str);
+            let payload = parts.next();
+            let payload = match (size, payload) {
+                (Some(Ok(size)), Some(payload)) if payload.len() == size => payload,
+                (Some(_), Some(_)) => {
+                    eprintln!("Error: ipc length did not match computed length");
+                    return Ok(None);
+                }
+                (Some(_), None) => {
+                    eprintln!("Error: ipc missing payload");
+                    return Ok(None);
+                }
+                (None, Some(_)) => {
+                    unreachable!("Somehow got something afte a : but nothing before it")
+                }
+                (None, None) => {
+                    eprintln!("Error: ipc missing length, payload");
+                    return Ok(None);
+                }
+            };
+            self.log("<-", payload);
+            match serde_json::from_str(payload) {
+                Ok(RpcRes::Responce { id, result, .. }) => Ok(Some((id, Ok(result)))),
+                Ok(RpcRes::Event {
+                    method, mut params, ..
+                }) => {
+                    self.translate_ids(&mut params);
+                    let subscription = params["esId"]
+                        .as_u64()
+                        .and_then(|es_id| self.subscriptions.get(&es_id));
+                    if let Some((_, tx)) = subscription {
+                        // Nobody is listening once the receiver is dropped,
+                        // which is as good as unsubscribing
+                        let _ = tx.send(event::Event::from_params(params));
+                    } else if let Some(cb) = self.callbacks.get_mut(&method) {
+                        cb(params)?;
+                    } else {
+                        eprintln!("Warn: Unhandled callback {} {:#?}", method, params);
+                    }
+                    Ok(None)
+                }
+                Ok(RpcRes::Error { error, id }) => {
+                    Ok(Some((id, Err(IrisError::from_response(&error)))))
+                }
+                Err(_e) => Err(IrisError::Protocol(payload.to_string())),
+            }
+        }
+
+        /// Deliver the events Iris sends within `timeout` to their
+        /// subscriptions and callbacks. Events are otherwise only delivered
+        /// while waiting on the response to a request.
+        pub fn poll_events(&mut self, timeout: Duration) -> Result<(), IrisError> {
+            let deadline = Instant::now() + timeout;
+            while Instant::now() < deadline {
+                // Only read a line once part of one has arrived, so that the
+                // read below can block without overrunning the deadline much
+                self.ipc.get_ref().set_nonblocking(true)?;
+                let pending = match self.ipc.fill_buf() {
+                    Ok(buf) => Ok(!buf.is_empty()),
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(false),
+                    Err(e) => Err(e),
+                };
+                self.ipc.get_ref().set_nonblocking(false)?;
+                if !pending? {
+                    std::thread::sleep(Duration::from_millis(1));
+                    continue;
+                }
+                match self.read_line()? {
+                    Some(line) => {
+                        if let Some((id, result)) = self.dispatch(&line)? {
+                            self.responses.insert(id, result);
+                        }
+                    }
+                    None => return Err(IrisError::ConnectionClosed),
                 }
             }
-            Err(IOError::new(
-                std::io::ErrorKind::Other,
-                "Connection closed before response",
-            ))
+            Ok(())
+        }
+
+        /// Have Iris send the events of `kind` from the instance to this
+        /// client. The events are sent on the returned subscription's channel
+        /// as they are read from the connection; see `poll_events`.
+        pub fn subscribe(
+            &mut self,
+            instance_id: u32,
+            kind: event::EventKind,
+        ) -> Result<event::Subscription, IrisError> {
+            let es_id = self.record_stream(instance_id, kind.source_name(), None)?;
+            let (tx, events) = channel();
+            self.subscriptions.insert(es_id, (instance_id, tx));
+            Ok(event::Subscription { id: es_id, events })
+        }
+
+        /// Have Iris send the events from the event source `source` of the
+        /// instance to the callback `cb`, registered as `func`. Returns the
+        /// event stream's id, to pass to `unsubscribe`.
+        pub(crate) fn subscribe_callback(
+            &mut self,
+            instance_id: u32,
+            source: &str,
+            func: String,
+            cb: Callback,
+        ) -> Result<u64, IrisError> {
+            let es_id = self.record_stream(instance_id, source, Some(func.clone()))?;
+            self.register_callback(func, cb);
+            Ok(es_id)
+        }
+
+        /// Make an event stream and keep how it was made, for `reconnect`.
+        fn record_stream(
+            &mut self,
+            instance_id: u32,
+            source: &str,
+            func: Option<String>,
+        ) -> Result<u64, IrisError> {
+            let es_id = self.create_stream(instance_id, source, func.clone())?;
+            let stream = Stream {
+                instance_id,
+                source: source.to_string(),
+                func,
+                current: es_id,
+            };
+            self.streams.insert(es_id, stream);
+            Ok(es_id)
+        }
+
+        fn create_stream(
+            &mut self,
+            instance_id: u32,
+            source: &str,
+            func: Option<String>,
+        ) -> Result<u64, IrisError> {
+            let client = self
+                .client_id()
+                .ok_or_else(|| IrisError::Protocol("Not registered with Iris".to_string()))?;
+            let source = event::source(self, instance_id, source.to_string())?;
+            event_stream::create(
+                self,
+                Some(instance_id),
+                false,
+                client,
+                source.id,
+                func,
+                false,
+            )
+        }
+
+        /// Stop Iris sending the events of a subscription. Its channel is
+        /// disconnected once the events already received are drained.
+        pub fn unsubscribe(&mut self, id: u64) -> Result<(), IrisError> {
+            self.subscriptions.remove(&id);
+            if let Some(stream) = self.streams.remove(&id) {
+                event_stream::destroy(self, stream.instance_id, stream.current)?;
+            }
+            Ok(())
         }
 
         /// Execute an RPC with Iris within the Fast Model.
-        pub fn execute<'a, M, I>(&mut self, message: I) -> Result<<M as IrisOut>::Out, IOError>
+        pub fn execute<'a, M, I>(&mut self, message: I) -> Result<<M as IrisOut>::Out, IrisError>
         where
             M: Serialize + IrisOut + 'a,
             I: Into<RpcReq<'a, M>>,
@@ -335,11 +991,29 @@ pub mod iris_client {
             self.send(message).and_then(|r| self.wait(r))
         }
 
+        /// Call the Iris method `method` with `params`, usually an object,
+        /// and return its result as Iris sent it. This is a low level escape
+        /// hatch for methods without a wrapper in this crate: the method
+        /// names and their parameters and results are those of the Iris
+        /// version the model implements, and may differ between versions.
+        /// An error response is returned as `IrisError::Protocol`.
+        pub fn request(
+            &mut self,
+            method: &str,
+            params: serde_json::Value,
+        ) -> Result<serde_json::Value, IrisError> {
+            let params = RawParams(params);
+            self.execute(RpcReq {
+                method,
+                params: &params,
+            })
+        }
+
         /// Execute a Batch of with Iris within the Fast Model.
         pub fn batch<'a, M, Itr, Itm>(
             &mut self,
             messages: Itr,
-        ) -> Result<Vec<<M as IrisOut>::Out>, IOError>
+        ) -> Result<Vec<<M as IrisOut>::Out>, IrisError>
         where
             M: Serialize + IrisOut + 'a,
             Itr: IntoIterator<Item = Itm>,
@@ -349,94 +1023,759 @@ pub mod iris_client {
         }
 
         #[allow(unused)]
-        pub fn close(mut self) -> Result<(), IOError> {
-            if let Some(mut proc) = self.proc {
+        pub fn close(mut self) -> Result<(), IrisError> {
+            self.disconnect();
+            if let Some(mut proc) = self.proc.take() {
                 proc.kill()?;
                 proc.wait()?;
             }
             Ok(())
         }
 
-        pub fn register_callback(
-            &mut self,
-            method: String,
-            cb: Box<dyn FnMut(serde_json::Value) -> Result<(), IOError>>,
-        ) {
-            self.callbacks.insert(method, cb);
+        /// Undo what this client set up in the model: its event streams, its
+        /// breakpoints and its registration. Errors are ignored, as the
+        /// model may already be gone.
+        fn disconnect(&mut self) {
+            let streams: Vec<u64> = self.streams.keys().copied().collect();
+            for id in streams {
+                let _ = self.unsubscribe(id);
+            }
+            for ((instance_id, _), bpt) in std::mem::take(&mut self.breakpoints) {
+                let _ = breakpoint::unset(self, instance_id, bpt.current);
+            }
+            if let Some(id) = self.inst_id.take() {
+                let _ = instance_registry::unregister_instance(self, id);
+            }
         }
-    }
-}
 
-macro_rules! iris_rpc_fn {
-    ($name:ident $method:literal $reqname:ident {$($(#[$reqattr: meta])? $reqident: ident: $reqty: ty),*} -> $resname:ty) => {
-        pub fn $name(fvp: &mut crate::iris_client::FastModelIris, $($reqident: $reqty),*) -> Result<$resname, std::io::Error> {
-            let resource_handle = fvp.send(crate::iris_client::RpcReq {
-                method: $method,
-                params: &$reqname{
-                    $($reqident),*
-                },
-            })?;
-            fvp.wait(resource_handle)
+        /// The number of instructions the core has executed, from its step
+        /// counter. Reading it before and after running between two
+        /// breakpoints measures the code in between.
+        pub fn instruction_count(&mut self, instance_id: u32) -> Result<u64, IrisError> {
+            match step::counter(self, instance_id, step::Unit::Instruction) {
+                Err(IrisError::Protocol(msg)) => Err(IrisError::Unsupported(format!(
+                    "Instance {} has no instruction counter ({})",
+                    instance_id, msg
+                ))),
+                res => res,
+            }
         }
 
-        #[derive(serde::Serialize)]
-        pub struct $reqname {
-            $($(#[$reqattr])? pub $reqident: $reqty),*
+        /// Start the simulation through its simulation engine instance, and
+        /// return without waiting for it to stop. Follow it with
+        /// `wait_for_stop`, or poll `is_running`, or subscribe to
+        /// `event::EventKind::SimulationTime` to learn when it halts.
+        pub fn run(&mut self, instance_id: u32) -> Result<(), IrisError> {
+            simulation_time::run(self, instance_id)
         }
 
-        impl<'a> From<&'a $reqname> for crate::iris_client::RpcReq<'a, $reqname> {
-            fn from(params: &'a $reqname) -> Self {
-                Self {
-                    method: $method,
-                    params
-                }
+        /// Stop the simulation through its simulation engine instance, and
+        /// wait until it reports that it has halted. Stopping a simulation
+        /// that isn't running does nothing.
+        pub fn stop(&mut self, instance_id: u32) -> Result<(), IrisError> {
+            if !self.is_running(instance_id)? {
+                return Ok(());
+            }
+            simulation_time::stop(self, instance_id)?;
+            let mut backoff = Backoff::new();
+            while self.is_running(instance_id)? {
+                backoff.wait();
             }
+            Ok(())
         }
 
-        impl crate::iris_client::IrisOut for $reqname {
-            type Out = $resname;
+        /// Whether the simulation is running, asking its simulation engine
+        /// instance.
+        pub fn is_running(&mut self, instance_id: u32) -> Result<bool, IrisError> {
+            Ok(simulation_time::get(self, instance_id)?.running)
         }
-    };
 
-    ($name:ident $method:literal $reqname:ident {$($(#[$reqattr: meta])? $reqident: ident: $reqty: ty,)*} -> $resname:ty) => {
-        iris_rpc_fn!($name $method
-            $reqname {
-                $($(#[$reqattr])? $reqident: $reqty),*
-            } -> $resname
-        );
-    };
-}
+        /// Wait for the simulation to stop running, polling its simulation
+        /// engine instance. When it is still running after `timeout`, the
+        /// simulation is stopped and this fails with `IrisError::Timeout`.
+        pub fn wait_for_stop(
+            &mut self,
+            instance_id: u32,
+            timeout: Option<Duration>,
+        ) -> Result<simulation_time::StopEvent, IrisError> {
+            self.wait_for_stop_or(instance_id, timeout, || false)
+        }
 
-pub mod instance_registry {
-    use crate::iris_client::AttributeInfo;
-    use serde::Deserialize;
-    use std::collections::HashMap;
+        /// Run the simulation until the core reaches `addr`, using a
+        /// temporary breakpoint, which is removed however the run ends. The
+        /// run may also end on another breakpoint, or on `timeout` expiring,
+        /// in which case the simulation is stopped. Bit 0 of `addr` is
+        /// dropped on Armv7-M, as for `set_pc`.
+        pub fn run_to(
+            &mut self,
+            instance_id: u32,
+            addr: u64,
+            timeout: Option<Duration>,
+        ) -> Result<simulation_time::RunTo, IrisError> {
+            let sim = instance_registry::get_instance_by_name(
+                self,
+                "framework.SimulationEngine".to_string(),
+            )?;
+            let arch = self.detect_arch(instance_id)?;
+            let (addr, pc) = match arch {
+                instance_registry::ArchKind::Armv7m => (addr & !1, "R15"),
+                instance_registry::ArchKind::AArch64 => (addr, "PC"),
+            };
+            let space = match resource::read_by_name(self, instance_id, "PC_MEMSPACE") {
+                Err(IrisError::ResourceNotFound(_)) => 0,
+                res => res?,
+            };
+            let bpt = breakpoint::code(self, instance_id, addr, None, space, true, false)?;
+            let stop = self
+                .run(sim.id)
+                .and_then(|()| self.wait_for_stop(sim.id, timeout));
+            breakpoint::delete(self, instance_id, bpt)?;
+            match stop {
+                Ok(_) => {}
+                Err(IrisError::Timeout) => return Ok(simulation_time::RunTo::TimedOut),
+                Err(e) => return Err(e),
+            }
+            let pc = resource::read_by_name(self, instance_id, pc)?;
+            Ok(if pc == addr {
+                simulation_time::RunTo::Reached
+            } else {
+                simulation_time::RunTo::Stopped { pc }
+            })
+        }
 
-    iris_rpc_fn!(register_instance "instanceRegistry_registerInstance"
-        RegisterInstance {
-            #[serde(rename = "instName")]
-            inst_name: String,
-            uniquify: bool,
-        } -> RegisterInstanceRes
-    );
+        /// Like `wait_for_stop`, but also stop the simulation as soon as
+        /// `interrupt` returns true. An interrupt before the timeout expires
+        /// wins over the timeout.
+        pub fn wait_for_stop_or(
+            &mut self,
+            instance_id: u32,
+            timeout: Option<Duration>,
+            mut interrupt: impl FnMut() -> bool,
+        ) -> Result<simulation_time::StopEvent, IrisError> {
+            let deadline = timeout.map(|timeout| Instant::now() + timeout);
+            let mut interrupted = false;
+            let mut timed_out = false;
+            let mut backoff = Backoff::new();
+            loop {
+                let time = simulation_time::get(self, instance_id)?;
+                if !time.running {
+                    if timed_out {
+                        return Err(IrisError::Timeout);
+                    }
+                    return Ok(simulation_time::StopEvent {
+                        ticks: time.ticks,
+                        interrupted,
+                    });
+                }
+                if interrupted || timed_out {
+                    backoff.wait();
+                    continue;
+                }
+                if interrupt() {
+                    self.stop(instance_id)?;
+                    interrupted = true;
+                } else if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    self.stop(instance_id)?;
+                    timed_out = true;
+                } else {
+                    backoff.wait();
+                }
+            }
+        }
 
-    #[derive(Deserialize, Debug, Clone)]
-    pub struct Instance {
-        #[serde(rename = "instId")]
-        pub id: u32,
-        #[serde(rename = "instName")]
-        pub name: String,
-    }
+        /// Reset the model through its simulation engine instance, and wait
+        /// for the reset to finish. The cached resource lists are dropped,
+        /// since the reset may change them.
+        pub fn reset(
+            &mut self,
+            instance_id: u32,
+            kind: simulation::ResetKind,
+        ) -> Result<(), IrisError> {
+            let allow_partial = kind == simulation::ResetKind::Warm;
+            simulation::reset(self, instance_id, allow_partial)?;
+            simulation::wait(self, instance_id)?;
+            self.invalidate_resources();
+            Ok(())
+        }
 
-    #[derive(Deserialize, Debug)]
-    pub struct RegisterInstanceRes {
-        #[serde(rename = "instName")]
-        pub name: String,
-        #[serde(rename = "instId")]
-        pub id: u32,
-    }
+        /// Save the state of the model to the checkpoint directory `dir`,
+        /// through its simulation engine instance.
+        pub fn save_state(&mut self, instance_id: u32, dir: &Path) -> Result<(), IrisError> {
+            checkpoint::save(self, instance_id, checkpoint::dir_name(dir)?)
+        }
 
-    #[allow(unused)]
+        /// Restore the state of the model from the checkpoint directory
+        /// `dir`, through its simulation engine instance. The cached resource
+        /// lists are dropped, as after a reset.
+        pub fn restore_state(&mut self, instance_id: u32, dir: &Path) -> Result<(), IrisError> {
+            checkpoint::restore(self, instance_id, checkpoint::dir_name(dir)?)?;
+            self.invalidate_resources();
+            Ok(())
+        }
+
+        /// List every instance in the model
+        pub fn instances(&mut self) -> Result<Vec<instance_registry::InstanceInfo>, IrisError> {
+            let mut infos = Vec::new();
+            for inst in instance_registry::list_instances(self, String::new())? {
+                let props = instance_registry::get_properties(self, inst.id)?;
+                let is_cpu = props
+                    .get("executesSoftware")
+                    .and_then(|v| v.as_u64())
+                    .is_some_and(|v| v != 0);
+                infos.push(instance_registry::InstanceInfo {
+                    id: inst.id,
+                    name: inst.name,
+                    is_cpu,
+                });
+            }
+            Ok(infos)
+        }
+
+        /// Work out the register layout of a core from its resources. Fails
+        /// with `IrisError::Unsupported` for instances that are neither
+        /// M-profile nor AArch64 cores, such as an Armv7-A core in AArch32,
+        /// which has CPSR rather than XPSR.
+        pub fn detect_arch(
+            &mut self,
+            instance_id: u32,
+        ) -> Result<instance_registry::ArchKind, IrisError> {
+            let resources = resource::cached_list(self, instance_id)?;
+            let has_all = |names: &[String]| {
+                names
+                    .iter()
+                    .all(|name| resources.iter().any(|res| res.name == *name))
+            };
+            let mut aarch64: Vec<String> = (0..=30).map(|n| format!("X{}", n)).collect();
+            aarch64.push("PC".to_string());
+            let mut armv7m: Vec<String> = (0..=15).map(|n| format!("R{}", n)).collect();
+            armv7m.push("XPSR".to_string());
+            if has_all(&aarch64) {
+                Ok(instance_registry::ArchKind::AArch64)
+            } else if has_all(&armv7m) {
+                Ok(instance_registry::ArchKind::Armv7m)
+            } else {
+                Err(IrisError::Unsupported(format!(
+                    "Instance {} is neither an Armv7-M nor an AArch64 core",
+                    instance_id
+                )))
+            }
+        }
+
+        /// Set the PC of a halted core, so that it resumes at `addr`. An
+        /// Armv7-M core only runs Thumb code, so there bit 0 of `addr`, as in
+        /// the address of a function, is dropped and the T bit of XPSR is
+        /// set, for the core not to take a UsageFault on resuming.
+        pub fn set_pc(&mut self, instance_id: u32, addr: u64) -> Result<(), IrisError> {
+            match self.detect_arch(instance_id)? {
+                instance_registry::ArchKind::AArch64 => {
+                    resource::write_by_name(self, instance_id, "PC", addr)
+                }
+                instance_registry::ArchKind::Armv7m => {
+                    resource::write_by_name(self, instance_id, "R15", addr & !1)?;
+                    resource::update_by_name(self, instance_id, "XPSR", |xpsr| xpsr | XPSR_T)?;
+                    Ok(())
+                }
+            }
+        }
+
+        /// The security state of an Armv8-M core with the Security Extension,
+        /// from the CDS bit of its DSCSR. Models that don't have DSCSR as a
+        /// resource have it read from the System Control Space, in the
+        /// memory space the PC is in.
+        pub fn security_state(
+            &mut self,
+            instance_id: u32,
+        ) -> Result<instance_registry::SecurityState, IrisError> {
+            Ok(if self.read_dscsr(instance_id)? & DSCSR_CDS != 0 {
+                instance_registry::SecurityState::Secure
+            } else {
+                instance_registry::SecurityState::NonSecure
+            })
+        }
+
+        /// Switch a halted Armv8-M core to `state`, by writing the CDS bit
+        /// of DSCSR. The core must be halted, and models may ignore the
+        /// write, in which case `IrisError::Unsupported` is returned.
+        pub fn set_security_state(
+            &mut self,
+            instance_id: u32,
+            state: instance_registry::SecurityState,
+        ) -> Result<(), IrisError> {
+            let dscsr = self.read_dscsr(instance_id)?;
+            let dscsr = match state {
+                instance_registry::SecurityState::Secure => dscsr | DSCSR_CDS,
+                instance_registry::SecurityState::NonSecure => dscsr & !DSCSR_CDS,
+            };
+            match resource::write_by_name(self, instance_id, "DSCSR", dscsr) {
+                Err(IrisError::ResourceNotFound(_)) => {
+                    let space = self.dscsr_space(instance_id)?;
+                    let bytes = (dscsr as u32).to_le_bytes();
+                    memory::write(self, instance_id, space, DSCSR_ADDR, 4, &bytes)?;
+                }
+                res => res?,
+            }
+            if self.security_state(instance_id)? == state {
+                Ok(())
+            } else {
+                Err(IrisError::Unsupported(format!(
+                    "Instance {} can't be switched to the {} state",
+                    instance_id, state
+                )))
+            }
+        }
+
+        fn read_dscsr(&mut self, instance_id: u32) -> Result<u64, IrisError> {
+            // DSCSR reads as zero without the Security Extension, which
+            // would pass for the Non-secure state
+            let resources = resource::cached_list(self, instance_id)?;
+            if !resources.iter().any(|res| res.name == "MSP_S") {
+                return Err(IrisError::Unsupported(format!(
+                    "Instance {} has no Security Extension",
+                    instance_id
+                )));
+            }
+            match resource::read_by_name(self, instance_id, "DSCSR") {
+                Err(IrisError::ResourceNotFound(_)) => {}
+                res => return res,
+            }
+            let space = self.dscsr_space(instance_id)?;
+            let mut bytes = [0u8; 4];
+            if memory::read_block(self, instance_id, space, DSCSR_ADDR, &mut bytes)? < 4 {
+                return Err(IrisError::Protocol("Could not read DSCSR".to_string()));
+            }
+            Ok(u32::from_le_bytes(bytes) as u64)
+        }
+
+        fn dscsr_space(&mut self, instance_id: u32) -> Result<u64, IrisError> {
+            match resource::read_by_name(self, instance_id, "PC_MEMSPACE") {
+                Err(IrisError::ResourceNotFound(_)) => Ok(0),
+                res => res,
+            }
+        }
+
+        /// Find an instance by its full name, or by the last components of its
+        /// name, so that `cpu0` finds `component.cpu0`. A partial name must be
+        /// unique within the model.
+        pub fn find_instance(
+            &mut self,
+            name: &str,
+        ) -> Result<Option<instance_registry::InstanceInfo>, IrisError> {
+            let suffix = format!(".{}", name);
+            let mut found: Vec<_> = self
+                .instances()?
+                .into_iter()
+                .filter(|inst| inst.name == name || inst.name.ends_with(&suffix))
+                .collect();
+            if let Some(pos) = found.iter().position(|inst| inst.name == name) {
+                return Ok(Some(found.swap_remove(pos)));
+            }
+            match found.len() {
+                0 | 1 => Ok(found.pop()),
+                _ => Err(IrisError::InvalidArgument(format!(
+                    "{} matches more than one instance: {}",
+                    name,
+                    found
+                        .iter()
+                        .map(|inst| inst.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))),
+            }
+        }
+
+        /// The instance id that Iris assigned to this client in `register`
+        pub fn client_id(&self) -> Option<u32> {
+            self.inst_id
+        }
+
+        /// The resources of the instance `id`, with their groups; see
+        /// `resource::table`.
+        pub fn resources(&mut self, id: u32) -> Result<resource::Resources, IrisError> {
+            resource::table(self, id)
+        }
+
+        /// Forget the cached resource lists, so that they are fetched from
+        /// Iris again. Needed once the resources of an instance may have
+        /// changed, such as after a reset.
+        pub fn invalidate_resources(&mut self) {
+            self.resources.clear();
+        }
+
+        pub fn register_callback(&mut self, method: String, cb: Callback) {
+            self.callbacks.insert(method, cb);
+        }
+    }
+
+    impl Drop for FastModelIris {
+        fn drop(&mut self) {
+            self.disconnect();
+        }
+    }
+
+    #[cfg(test)]
+    impl FastModelIris {
+        /// A connection to a socket standing in for the Iris server, which
+        /// answers whatever is sent to it with `messages`, already queued.
+        /// Without registering, requests are numbered from 0. The server
+        /// end is returned to keep the connection open.
+        pub(crate) fn scripted(messages: &[serde_json::Value]) -> (Self, TcpStream) {
+            let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (mut server, _) = listener.accept().unwrap();
+            for message in messages {
+                server.write_all(framed(message).as_bytes()).unwrap();
+            }
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            (Self::from_stream(None, client), server)
+        }
+
+        /// A connection to a socket standing in for the Iris server, which
+        /// answers each request as it arrives with `answer` of its method
+        /// and parameters, from a thread of its own. Connections made with
+        /// the same `answer` act as clients of one server.
+        pub(crate) fn answered(answer: Arc<Answer>) -> Self {
+            let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (server, _) = listener.accept().unwrap();
+            std::thread::spawn(move || {
+                let mut out = server.try_clone().unwrap();
+                for line in BufReader::new(server).lines().map_while(Result::ok) {
+                    let req = unframed(&line);
+                    let id = match req["id"].as_u64() {
+                        Some(id) => id,
+                        None => continue,
+                    };
+                    let method = req["method"].as_str().unwrap_or_default();
+                    let res = match answer(method, &req["params"]) {
+                        Ok(result) => reply(id, result),
+                        Err(message) => error_reply(id, &message),
+                    };
+                    if out.write_all(framed(&res).as_bytes()).is_err() {
+                        break;
+                    }
+                }
+            });
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            Self::from_stream(None, client)
+        }
+    }
+
+    /// How a server made by `FastModelIris::answered` answers a request of
+    /// a method with its parameters: with a result, or an error message
+    #[cfg(test)]
+    pub(crate) type Answer =
+        dyn Fn(&str, &serde_json::Value) -> Result<serde_json::Value, String> + Send + Sync;
+
+    /// Iris's answer `result` to request `id`
+    #[cfg(test)]
+    pub(crate) fn reply(id: u64, result: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({"jsonrpc": "2.0", "result": result, "id": id})
+    }
+
+    /// Iris's error answer to request `id`
+    #[cfg(test)]
+    pub(crate) fn error_reply(id: u64, message: &str) -> serde_json::Value {
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "error": {"code": 1, "message": message},
+            "id": id,
+        })
+    }
+
+    /// A message as Iris puts it on the wire
+    #[cfg(test)]
+    pub(crate) fn framed(message: &serde_json::Value) -> String {
+        let text = message.to_string();
+        format!("IrisJson:{}:{}\n", text.len(), text)
+    }
+
+    /// The requests a scripted server has been sent so far, in order
+    #[cfg(test)]
+    pub(crate) fn received(server: &TcpStream) -> Vec<serde_json::Value> {
+        // Requests are written out before their responses are waited on,
+        // so they have all arrived unless the client is still running
+        server
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        BufReader::new(server)
+            .lines()
+            .map_while(Result::ok)
+            .map(|line| unframed(&line))
+            .collect()
+    }
+
+    /// A message as framed by `framed`
+    #[cfg(test)]
+    fn unframed(line: &str) -> serde_json::Value {
+        // Framed as IrisJson:<length>:<message>
+        serde_json::from_str(line.splitn(3, ':').nth(2).unwrap()).unwrap()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde_json::json;
+
+        #[test]
+        fn error_response_is_an_error() {
+            let (mut iris, _server) = FastModelIris::scripted(&[]);
+            let line = framed(&json!({
+                "jsonrpc": "2.0",
+                "error": {"code": 4, "message": "No such instance"},
+                "id": 0,
+            }));
+            match iris.dispatch(line.trim_end()) {
+                Ok(Some((0, Err(IrisError::Protocol(msg))))) => {
+                    assert_eq!(msg, "No such instance (code 4)")
+                }
+                res => panic!("expected a protocol error for 0, got {:?}", res),
+            }
+        }
+
+        #[test]
+        fn responses_reach_their_requests() {
+            // Answers to requests 0, 1 and 2, sent out of order, with the
+            // one to request 1 an error
+            let (mut iris, _server) = FastModelIris::scripted(&[
+                json!({"jsonrpc": "2.0", "result": "two", "id": 2}),
+                json!({"jsonrpc": "2.0", "error": {"code": 1, "message": "one"}, "id": 1}),
+                json!({"jsonrpc": "2.0", "result": "zero", "id": 0}),
+            ]);
+            let params = RawParams(json!({}));
+            let req = |method| RpcReq {
+                method,
+                params: &params,
+            };
+            let zero = iris.send(req("zero")).unwrap();
+            let one = iris.send(req("one")).unwrap();
+            let two = iris.send(req("two")).unwrap();
+            assert_eq!(iris.wait(two).unwrap(), "two");
+            assert_eq!(iris.wait(zero).unwrap(), "zero");
+            assert!(matches!(iris.wait(one), Err(IrisError::Protocol(_))));
+        }
+
+        #[test]
+        fn batch_results_follow_request_order() {
+            let (mut iris, _server) = FastModelIris::scripted(&[
+                json!({"jsonrpc": "2.0", "result": 1, "id": 1}),
+                json!({"jsonrpc": "2.0", "result": 0, "id": 0}),
+            ]);
+            let params = RawParams(json!({}));
+            let reqs = ["zero", "one"].map(|method| RpcReq {
+                method,
+                params: &params,
+            });
+            assert_eq!(iris.batch(reqs).unwrap(), vec![json!(0), json!(1)]);
+        }
+
+        #[test]
+        fn failed_request_is_an_error() {
+            let (mut iris, _server) = FastModelIris::scripted(&[json!({
+                "jsonrpc": "2.0",
+                "error": {"code": 4, "message": "No such instance"},
+                "id": 0,
+            })]);
+            let res = iris.request("simulationTime_run", json!({"instId": 7}));
+            assert!(matches!(res, Err(IrisError::Protocol(_))), "{:?}", res);
+        }
+
+        #[test]
+        fn instruction_count_without_counter_is_unsupported() {
+            let (mut iris, _server) = FastModelIris::scripted(&[error_reply(0, "No counter")]);
+            let res = iris.instruction_count(3);
+            assert!(matches!(res, Err(IrisError::Unsupported(_))), "{:?}", res);
+        }
+
+        #[test]
+        fn instruction_count_reads_step_counter() {
+            let (mut iris, _server) = FastModelIris::scripted(&[reply(0, json!(1234))]);
+            assert_eq!(iris.instruction_count(3).unwrap(), 1234);
+        }
+
+        #[test]
+        fn events_keep_the_ids_first_given() {
+            let (mut iris, _server) = FastModelIris::scripted(&[]);
+            // A stream and a breakpoint first made as 5 and 9, and made
+            // again as 6 and 12 by a reconnection
+            let hits = Arc::new(Mutex::new(Vec::new()));
+            let seen = hits.clone();
+            iris.register_callback(
+                "ec_hit".to_string(),
+                Box::new(move |params| {
+                    let ids = (params["esId"].as_u64(), params["fields"]["BPT_ID"].as_u64());
+                    seen.lock().unwrap().push(ids);
+                    Ok(())
+                }),
+            );
+            let stream = Stream {
+                instance_id: 3,
+                source: "IRIS_BREAKPOINT_HIT".to_string(),
+                func: Some("ec_hit".to_string()),
+                current: 6,
+            };
+            iris.streams.insert(5, stream);
+            let bpt = breakpoint::Recorded {
+                address: 0x100,
+                rw_mode: None,
+                size: None,
+                space_id: 1,
+                sync: true,
+                typ: breakpoint::Type::Code,
+                dont_stop: false,
+                current: 12,
+            };
+            iris.breakpoints.insert((3, 9), bpt);
+            let event = |bpt_id| {
+                framed(&json!({
+                    "jsonrpc": "2.0",
+                    "method": "ec_hit",
+                    "params": {"esId": 6, "sInstId": 3, "fields": {"BPT_ID": bpt_id}},
+                }))
+            };
+            iris.dispatch(event(12).trim_end()).unwrap();
+            iris.dispatch(event(13).trim_end()).unwrap();
+            assert_eq!(
+                *hits.lock().unwrap(),
+                vec![(Some(5), Some(9)), (Some(5), Some(13))]
+            );
+        }
+
+        #[test]
+        fn null_result_is_ok() {
+            let (mut iris, _server) =
+                FastModelIris::scripted(&[json!({"jsonrpc": "2.0", "result": null, "id": 0})]);
+            let res = iris.request("simulationTime_run", json!({"instId": 7}));
+            assert_eq!(res.unwrap(), serde_json::Value::Null);
+        }
+
+        #[test]
+        fn shared_connection_answers_each_thread() {
+            // A server that echoes each request back as its result
+            let iris: SharedIris = Arc::new(Mutex::new(FastModelIris::answered(Arc::new(
+                |method, params| Ok(json!({"method": method, "params": params})),
+            ))));
+            let threads: Vec<_> = (0..8)
+                .map(|thread| {
+                    let iris = iris.clone();
+                    std::thread::spawn(move || {
+                        for n in 0..50 {
+                            let mut iris = iris.lock().unwrap();
+                            // Two requests in flight, waited for in reverse
+                            let first = RawParams(json!({"thread": thread, "n": n}));
+                            let second = RawParams(json!({"thread": thread, "n": -n}));
+                            let first = iris
+                                .send(RpcReq {
+                                    method: "resource_read",
+                                    params: &first,
+                                })
+                                .unwrap();
+                            let second = iris
+                                .send(RpcReq {
+                                    method: "resource_write",
+                                    params: &second,
+                                })
+                                .unwrap();
+                            assert_eq!(
+                                iris.wait(second).unwrap(),
+                                json!({
+                                    "method": "resource_write",
+                                    "params": {"thread": thread, "n": -n},
+                                })
+                            );
+                            assert_eq!(
+                                iris.wait(first).unwrap(),
+                                json!({
+                                    "method": "resource_read",
+                                    "params": {"thread": thread, "n": n},
+                                })
+                            );
+                        }
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+        }
+    }
+}
+
+macro_rules! iris_rpc_fn {
+    ($name:ident $method:literal $reqname:ident {$($(#[$reqattr: meta])? $reqident: ident: $reqty: ty),*} -> $resname:ty) => {
+        #[allow(clippy::too_many_arguments)]
+        pub fn $name(fvp: &mut crate::iris_client::FastModelIris, $($reqident: $reqty),*) -> Result<$resname, crate::IrisError> {
+            let resource_handle = fvp.send(crate::iris_client::RpcReq {
+                method: $method,
+                params: &$reqname{
+                    $($reqident),*
+                },
+            })?;
+            fvp.wait(resource_handle)
+        }
+
+        #[derive(serde::Serialize)]
+        pub struct $reqname {
+            $($(#[$reqattr])? pub $reqident: $reqty),*
+        }
+
+        impl<'a> From<&'a $reqname> for crate::iris_client::RpcReq<'a, $reqname> {
+            fn from(params: &'a $reqname) -> Self {
+                Self {
+                    method: $method,
+                    params
+                }
+            }
+        }
+
+        impl crate::iris_client::IrisOut for $reqname {
+            type Out = $resname;
+        }
+    };
+
+    ($name:ident $method:literal $reqname:ident {$($(#[$reqattr: meta])? $reqident: ident: $reqty: ty,)*} -> $resname:ty) => {
+        iris_rpc_fn!($name $method
+            $reqname {
+                $($(#[$reqattr])? $reqident: $reqty),*
+            } -> $resname
+        );
+    };
+}
+
+pub mod instance_registry {
+    use crate::iris_client::AttributeInfo;
+    use serde::Deserialize;
+    use serde_json::Value;
+    use std::collections::HashMap;
+
+    iris_rpc_fn!(register_instance "instanceRegistry_registerInstance"
+        RegisterInstance {
+            #[serde(rename = "instName")]
+            inst_name: String,
+            uniquify: bool,
+        } -> RegisterInstanceRes
+    );
+
+    #[derive(Deserialize, Debug, Clone)]
+    pub struct Instance {
+        #[serde(rename = "instId")]
+        pub id: u32,
+        #[serde(rename = "instName")]
+        pub name: String,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct RegisterInstanceRes {
+        #[serde(rename = "instName")]
+        pub name: String,
+        #[serde(rename = "instId")]
+        pub id: u32,
+    }
+
+    #[allow(unused)]
     #[derive(Deserialize, Debug)]
     pub struct FunctionInfo {
         args: HashMap<String, AttributeInfo>,
@@ -444,6 +1783,13 @@ pub mod instance_registry {
         retval: AttributeInfo,
     }
 
+    iris_rpc_fn!(unregister_instance "instanceRegistry_unregisterInstance"
+        UnregisterInstance {
+            #[serde(rename = "aInstId")]
+            id: u32,
+        } -> ()
+    );
+
     iris_rpc_fn!(list_instances "instanceRegistry_getList"
         ListInsnances { prefix: String } -> Vec<Instance>
     );
@@ -467,13 +1813,61 @@ pub mod instance_registry {
             prefix: String,
         } -> HashMap<String, FunctionInfo>
     );
+    iris_rpc_fn!(get_properties "instance_getProperties"
+        GetPropertiesReq {
+            #[serde(rename = "instId")]
+            id: u32,
+        } -> HashMap<String, Value>
+    );
+
+    /// An instance in the model, as found by `FastModelIris::instances`
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct InstanceInfo {
+        pub id: u32,
+        /// The full name of the instance, such as `component.cpu0`
+        pub name: String,
+        /// Whether the instance executes software, and so may be debugged
+        pub is_cpu: bool,
+    }
+
+    /// The register layout of a core, which decides the gdb stub that can
+    /// debug it
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ArchKind {
+        /// An M-profile core, with R0-R15 and XPSR
+        Armv7m,
+        /// A 64 bit core, with X0-X30 and PC
+        AArch64,
+    }
+
+    /// The security state of an Armv8-M core with the Security Extension;
+    /// see `FastModelIris::security_state`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum SecurityState {
+        Secure,
+        NonSecure,
+    }
+
+    impl std::fmt::Display for SecurityState {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(match self {
+                SecurityState::Secure => "Secure",
+                SecurityState::NonSecure => "Non-secure",
+            })
+        }
+    }
 }
 
 pub mod memory {
-    use crate::iris_client::AttributeInfo;
+    use crate::iris_client::{AttributeInfo, FastModelIris};
+    use crate::{resource, step, IrisError};
     use serde::Deserialize;
     use serde_json::Value;
     use std::collections::HashMap;
+    use std::fmt;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
 
     #[derive(Deserialize, Debug)]
     #[serde(rename_all = "camelCase")]
@@ -490,6 +1884,41 @@ pub mod memory {
         pub id: u64,
     }
 
+    /// The byte order of the units in a memory space
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub enum Endianness {
+        #[default]
+        Little,
+        Big,
+    }
+
+    impl Endianness {
+        pub fn u32_to_bytes(self, value: u32) -> [u8; 4] {
+            match self {
+                Self::Little => value.to_le_bytes(),
+                Self::Big => value.to_be_bytes(),
+            }
+        }
+
+        pub fn u32_from_bytes(self, bytes: [u8; 4]) -> u32 {
+            match self {
+                Self::Little => u32::from_le_bytes(bytes),
+                Self::Big => u32::from_be_bytes(bytes),
+            }
+        }
+    }
+
+    impl Space {
+        /// The byte order of the space, little endian unless the model
+        /// says otherwise
+        pub fn byte_order(&self) -> Endianness {
+            match self.endianness.as_deref() {
+                Some(order) if order.eq_ignore_ascii_case("big") => Endianness::Big,
+                _ => Endianness::Little,
+            }
+        }
+    }
+
     iris_rpc_fn!(spaces "memory_getMemorySpaces"
         GetFuncInfoReq {
             #[serde(rename = "instId")]
@@ -497,6 +1926,198 @@ pub mod memory {
         } -> Vec<Space>
     );
 
+    /// What backs a memory region, as far as the model says
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum MemKind {
+        Ram,
+        Rom,
+        Flash,
+        /// Peripheral registers, which reads and writes may have effects on
+        Device,
+        /// The model doesn't say
+        Unknown,
+    }
+
+    impl MemKind {
+        fn from_name(name: &str) -> Self {
+            let name = name.to_ascii_lowercase();
+            if name.contains("flash") {
+                Self::Flash
+            } else if name.contains("rom") {
+                Self::Rom
+            } else if ["device", "peripheral", "io"]
+                .iter()
+                .any(|n| name.contains(n))
+            {
+                Self::Device
+            } else if name.contains("ram") || name.contains("memory") {
+                Self::Ram
+            } else {
+                Self::Unknown
+            }
+        }
+    }
+
+    impl fmt::Display for MemKind {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(match self {
+                Self::Ram => "RAM",
+                Self::Rom => "ROM",
+                Self::Flash => "flash",
+                Self::Device => "device",
+                Self::Unknown => "unknown",
+            })
+        }
+    }
+
+    /// The accesses a memory region allows
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Access {
+        pub read: bool,
+        pub write: bool,
+        pub execute: bool,
+    }
+
+    impl Access {
+        /// Every access, which is what a region allows unless the model
+        /// says otherwise
+        pub const ALL: Self = Self {
+            read: true,
+            write: true,
+            execute: true,
+        };
+    }
+
+    /// Shown as the `rwx` of `ls -l`
+    impl fmt::Display for Access {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let flag = |set, c| if set { c } else { '-' };
+            write!(
+                f,
+                "{}{}{}",
+                flag(self.read, 'r'),
+                flag(self.write, 'w'),
+                flag(self.execute, 'x')
+            )
+        }
+    }
+
+    /// A named range of addresses in a memory space
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct MemRegion {
+        pub name: String,
+        pub base: u64,
+        /// The number of bytes in the region, or `None` when it reaches the
+        /// end of a 64 bit address space and so won't fit in a `u64`
+        pub size: Option<u64>,
+        pub description: Option<String>,
+        pub kind: MemKind,
+        pub access: Access,
+    }
+
+    iris_rpc_fn!(sideband_info "memory_getSidebandInfo"
+        SidebandInfoReq {
+            #[serde(rename = "instId")]
+            id: u32,
+            #[serde(rename = "spaceId")]
+            space: u64,
+            address: u64
+        } -> Value
+    );
+
+    /// The most regions `regions` walks through in one space
+    const MAX_REGIONS: usize = 256;
+
+    /// The region described by the sideband information Iris has for an
+    /// address, or `None` when it gives no bounds. `regionEnd` is the last
+    /// address of the region, as `maxAddr` is for a space.
+    fn sideband_region(info: &Value, space: &Space) -> Option<MemRegion> {
+        let base = info["regionStart"].as_u64()?;
+        let end = info["regionEnd"].as_u64()?;
+        let text = |key: &str| info[key].as_str().map(str::to_string);
+        let name = text("regionName")
+            .or_else(|| text("name"))
+            .unwrap_or_else(|| space.name.clone());
+        let kind = text("memoryType")
+            .or_else(|| text("type"))
+            .map_or(MemKind::Unknown, |kind| MemKind::from_name(&kind));
+        let mut access = match info["access"].as_str() {
+            Some(flags) => Access {
+                read: flags.contains('r'),
+                write: flags.contains('w'),
+                execute: flags.contains('x'),
+            },
+            None => Access::ALL,
+        };
+        if info["readOnly"].as_bool() == Some(true) || kind == MemKind::Rom {
+            access.write = false;
+        }
+        if kind == MemKind::Device {
+            access.execute = false;
+        }
+        Some(MemRegion {
+            size: end.saturating_sub(base).checked_add(1),
+            base,
+            name,
+            description: text("description"),
+            kind,
+            access,
+        })
+    }
+
+    /// The regions of memory space `space`, with what backs them and the
+    /// accesses they allow, from the sideband information Iris has for
+    /// their addresses. Models that don't give region bounds have the space
+    /// itself as the one region, covering the addresses the model reports
+    /// for it, of unknown kind and allowing every access.
+    pub fn regions(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+    ) -> Result<Vec<MemRegion>, IrisError> {
+        let info = match spaces(fvp, id)?.into_iter().find(|info| info.id == space) {
+            Some(info) => info,
+            None => return Ok(Vec::new()),
+        };
+        let min = info.min_addr.unwrap_or(0);
+        let max = info.max_addr.unwrap_or(u64::MAX);
+        let mut regions = Vec::new();
+        let mut address = min;
+        while regions.len() < MAX_REGIONS {
+            let region = match sideband_info(fvp, id, space, address) {
+                Ok(sideband) => sideband_region(&sideband, &info),
+                // Models without sideband information reject the request
+                Err(IrisError::Protocol(_)) => None,
+                Err(e) => return Err(e),
+            };
+            // The walk must move forward for it to end
+            let region = match region {
+                Some(region) if region.base <= address => region,
+                _ => break,
+            };
+            let end = region.size.map_or(u64::MAX, |size| region.base + size - 1);
+            if end < address {
+                break;
+            }
+            regions.push(region);
+            match end.checked_add(1) {
+                Some(next) if next <= max => address = next,
+                _ => break,
+            }
+        }
+        if regions.is_empty() {
+            regions.push(MemRegion {
+                size: max.saturating_sub(min).checked_add(1),
+                base: min,
+                name: info.name,
+                description: info.description,
+                kind: MemKind::Unknown,
+                access: Access::ALL,
+            });
+        }
+        Ok(regions)
+    }
+
     #[derive(Deserialize, Debug)]
     #[serde(rename_all = "camelCase")]
     pub struct ReadRes {
@@ -517,12 +2138,513 @@ pub mod memory {
                 count: u64,
             } -> ReadRes
     );
+
+    /// The default for the largest number of bytes read or written in a
+    /// single request, to stay well within the payload size Iris accepts;
+    /// see `FastModelIris::set_max_request_bytes`.
+    pub(crate) const MAX_REQUEST_BYTES: usize = 0x1000;
+
+    /// Width of the units used for the aligned part of a block read.
+    const BLOCK_WIDTH: u64 = 4;
+
+    /// Read `data.len()` bytes starting at `address`, returning how many
+    /// were read before the first fault. The word aligned part of the range
+    /// is read in 4 byte units and the ragged ends a byte at a time, split
+    /// into requests as `FastModelIris::set_max_request_bytes` allows.
+    pub fn read_block(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        address: u64,
+        data: &mut [u8],
+    ) -> Result<usize, IrisError> {
+        read_block_ordered(fvp, id, space, address, data, Endianness::Little)
+    }
+
+    /// Like `read_block`, for a space whose units are in `order`. Iris
+    /// returns each unit as a value, so the units of a big endian space are
+    /// unpacked most significant byte first to keep `data` in address
+    /// order.
+    pub fn read_block_ordered(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        address: u64,
+        data: &mut [u8],
+        order: Endianness,
+    ) -> Result<usize, IrisError> {
+        let len = data.len();
+        let head = (((BLOCK_WIDTH - address % BLOCK_WIDTH) % BLOCK_WIDTH) as usize).min(len);
+        let body_end = head + (len - head) / BLOCK_WIDTH as usize * BLOCK_WIDTH as usize;
+        let max_bytes = fvp.max_request_bytes - fvp.max_request_bytes % BLOCK_WIDTH as usize;
+        let mut valid = 0;
+        for (range, width) in [
+            (0..head, 1),
+            (head..body_end, BLOCK_WIDTH),
+            (body_end..len, 1),
+        ] {
+            for chunk in data[range].chunks_mut(max_bytes) {
+                let addr = address + valid as u64;
+                let res = read(fvp, id, space, addr, width, chunk.len() as u64 / width)?;
+                let mut filled = 0;
+                let bytes = res.data.into_iter().flat_map(|u| {
+                    let mut bytes = u.to_le_bytes();
+                    if order == Endianness::Big {
+                        bytes
+                            .chunks_mut(width as usize)
+                            .for_each(|unit| unit.reverse());
+                    }
+                    bytes
+                });
+                for (dst, byte) in chunk.iter_mut().zip(bytes) {
+                    *dst = byte;
+                    filled += 1;
+                }
+                valid += filled;
+                if res.error.is_some() || filled < chunk.len() {
+                    return Ok(valid);
+                }
+            }
+        }
+        Ok(valid)
+    }
+
+    /// Read `data.len()` bytes starting at `address` using only accesses of
+    /// `width` bytes, for memory such as peripheral registers that must not
+    /// be read a byte at a time. The range is widened to whole aligned
+    /// units, and only the requested bytes are kept. Returns how many bytes
+    /// were read before the first fault.
+    pub fn read_units(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        address: u64,
+        data: &mut [u8],
+        width: u64,
+        order: Endianness,
+    ) -> Result<usize, IrisError> {
+        if !(1..=8).contains(&width) || !width.is_power_of_two() {
+            return Err(IrisError::InvalidArgument(format!(
+                "Units of {} bytes can't be read",
+                width
+            )));
+        }
+        let skip = (address % width) as usize;
+        let mut units = vec![0u8; (skip + data.len()).div_ceil(width as usize) * width as usize];
+        let start = address - skip as u64;
+        let mut valid = 0;
+        let max_bytes = fvp.max_request_bytes - fvp.max_request_bytes % width as usize;
+        for chunk in units.chunks_mut(max_bytes) {
+            let addr = start + valid as u64;
+            let res = read(fvp, id, space, addr, width, chunk.len() as u64 / width)?;
+            let mut filled = 0;
+            for (word, dst) in res.data.into_iter().zip(chunk.chunks_mut(8)) {
+                let mut bytes = word.to_le_bytes();
+                if order == Endianness::Big {
+                    bytes
+                        .chunks_mut(width as usize)
+                        .for_each(|unit| unit.reverse());
+                }
+                dst.copy_from_slice(&bytes[..dst.len()]);
+                filled += dst.len();
+            }
+            valid += filled;
+            if res.error.is_some() || filled < chunk.len() {
+                break;
+            }
+        }
+        let valid = valid.saturating_sub(skip).min(data.len());
+        data[..valid].copy_from_slice(&units[skip..skip + valid]);
+        Ok(valid)
+    }
+
+    #[derive(Deserialize, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct WriteRes {
+        pub written_count: Option<u64>,
+        pub error: Option<Value>,
+    }
+
+    iris_rpc_fn!(
+        write_words "memory_write"
+            MemoryWriteReq {
+                #[serde(rename = "instId")]
+                id: u32,
+                #[serde(rename = "spaceId")]
+                space: u64,
+                address: u64,
+                #[serde(rename = "byteWidth")]
+                width: u64,
+                count: u64,
+                data: Vec<u64>,
+            } -> WriteRes
+    );
+
+    /// Write `data` to memory as `data.len() / width` units of `width` bytes,
+    /// returning the number of units written. The units are sent in requests
+    /// of the size `FastModelIris::set_max_request_bytes` allows. When Iris
+    /// stops part way through, the units before the fault are still written
+    /// and counted, and no further requests are made; a fault on the very
+    /// first unit is an error.
+    pub fn write(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        address: u64,
+        width: u64,
+        data: &[u8],
+    ) -> Result<u64, IrisError> {
+        if width == 0 || !(data.len() as u64).is_multiple_of(width) {
+            return Err(IrisError::InvalidArgument(format!(
+                "{} bytes is not a whole number of {} byte units",
+                data.len(),
+                width
+            )));
+        }
+        let chunk_units = (fvp.max_request_bytes as u64 / width).max(1);
+        let mut done = 0;
+        for chunk in data.chunks((chunk_units * width) as usize) {
+            let units = chunk.len() as u64 / width;
+            let addr = address + done * width;
+            let written = match write_request(fvp, id, space, addr, width, chunk) {
+                Err(IrisError::MemoryFault(_)) if done > 0 => 0,
+                res => res?,
+            };
+            done += written;
+            if written < units {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Write `data` as units of `width` bytes in a single request. Iris
+    /// expects the units packed, little endian, into 64 bit words.
+    fn write_request(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        address: u64,
+        width: u64,
+        data: &[u8],
+    ) -> Result<u64, IrisError> {
+        let count = data.len() as u64 / width;
+        let words = data
+            .chunks(8)
+            .map(|chunk| {
+                let mut word = [0u8; 8];
+                word[..chunk.len()].copy_from_slice(chunk);
+                u64::from_le_bytes(word)
+            })
+            .collect();
+        let res = write_words(fvp, id, space, address, width, count, words)?;
+        match (res.error, res.written_count) {
+            (None, written) => Ok(written.unwrap_or(count)),
+            (Some(_), Some(written)) if written > 0 => Ok(written),
+            (Some(_), _) => Err(IrisError::MemoryFault(address)),
+        }
+    }
+
+    /// Write `count` units of `byte_width` bytes, each holding the low bytes
+    /// of `value`, starting at `address`. The units are sent in requests of
+    /// the size `FastModelIris::set_max_request_bytes` allows, and a fault
+    /// part way through is reported at the first unit that was not written.
+    pub fn fill(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        address: u64,
+        byte_width: u64,
+        value: u64,
+        count: u64,
+    ) -> Result<(), IrisError> {
+        if !(1..=8).contains(&byte_width) {
+            return Err(IrisError::InvalidArgument(format!(
+                "Units of {} bytes can't be filled",
+                byte_width
+            )));
+        }
+        let unit = &value.to_le_bytes()[..byte_width as usize];
+        let chunk_units = fvp.max_request_bytes as u64 / byte_width;
+        let chunk: Vec<u8> = unit
+            .iter()
+            .copied()
+            .cycle()
+            .take((chunk_units.min(count) * byte_width) as usize)
+            .collect();
+        let mut done = 0;
+        while done < count {
+            let units = chunk_units.min(count - done);
+            let addr = address + done * byte_width;
+            let data = &chunk[..(units * byte_width) as usize];
+            let written = write(fvp, id, space, addr, byte_width, data)?;
+            if written < units {
+                return Err(IrisError::MemoryFault(addr + written * byte_width));
+            }
+            done += units;
+        }
+        Ok(())
+    }
+
+    /// Compare the memory at `address` with `expected`, returning the offset
+    /// of the first byte that differs, or `None` when they all match. A
+    /// fault before any difference is found is an error.
+    pub fn compare(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        address: u64,
+        expected: &[u8],
+    ) -> Result<Option<u64>, IrisError> {
+        let mut actual = vec![0u8; expected.len()];
+        let valid = read_block(fvp, id, space, address, &mut actual)?;
+        if let Some(offset) = actual[..valid]
+            .iter()
+            .zip(expected)
+            .position(|(a, e)| a != e)
+        {
+            return Ok(Some(offset as u64));
+        }
+        if valid < expected.len() {
+            return Err(IrisError::MemoryFault(address + valid as u64));
+        }
+        Ok(None)
+    }
+
+    /// How much memory `dump` and `crc32` read at once.
+    const DUMP_CHUNK: usize = 0x10000;
+
+    /// Copy `len` bytes of memory starting at `address` to the file at
+    /// `path`, as raw bytes, returning how many were copied. The copy stops
+    /// at the first fault, so the file then holds only the readable start
+    /// of the range.
+    pub fn dump(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        address: u64,
+        len: u64,
+        path: &Path,
+    ) -> Result<u64, IrisError> {
+        let mut file = File::create(path)?;
+        let mut buf = vec![0u8; DUMP_CHUNK];
+        let mut done = 0;
+        while done < len {
+            let chunk = &mut buf[..(len - done).min(DUMP_CHUNK as u64) as usize];
+            let valid = read_block(fvp, id, space, address + done, chunk)?;
+            file.write_all(&chunk[..valid])?;
+            done += valid as u64;
+            if valid < chunk.len() {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Continue the CRC `crc` over `data`, the way gdb's `qCRC` computes
+    /// it: CRC-32 with the polynomial 0x04c11db7, most significant bit
+    /// first, without the final inversion. gdb starts from 0xffffffff.
+    pub fn gdb_crc32(mut crc: u32, data: &[u8]) -> u32 {
+        for &byte in data {
+            crc ^= u32::from(byte) << 24;
+            for _ in 0..8 {
+                crc = if crc & 0x8000_0000 != 0 {
+                    (crc << 1) ^ 0x04c1_1db7
+                } else {
+                    crc << 1
+                };
+            }
+        }
+        crc
+    }
+
+    /// The CRC gdb computes for `len` bytes starting at `address`; see
+    /// `gdb_crc32`. A fault part way through is an error.
+    pub fn crc32(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        address: u64,
+        len: u64,
+    ) -> Result<u32, IrisError> {
+        let mut buf = vec![0u8; DUMP_CHUNK];
+        let mut crc = 0xffff_ffff;
+        let mut done = 0;
+        while done < len {
+            let chunk = &mut buf[..(len - done).min(DUMP_CHUNK as u64) as usize];
+            let valid = read_block(fvp, id, space, address + done, chunk)?;
+            if valid < chunk.len() {
+                return Err(IrisError::MemoryFault(address + done + valid as u64));
+            }
+            crc = gdb_crc32(crc, chunk);
+            done += valid as u64;
+        }
+        Ok(crc)
+    }
+
+    /// Read the unit of `width` bytes at `address` as a number
+    fn read_unit(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        address: u64,
+        width: u64,
+    ) -> Result<u64, IrisError> {
+        let res = read(fvp, id, space, address, width, 1)?;
+        match (res.error, res.data.first()) {
+            (None, Some(&word)) if width < 8 => Ok(word & ((1 << (width * 8)) - 1)),
+            (None, Some(&word)) => Ok(word),
+            _ => Err(IrisError::MemoryFault(address)),
+        }
+    }
+
+    /// Single-step the core until the unit of `width` bytes at `address`
+    /// changes value, returning the address of the instruction that changed
+    /// it, or `None` when `budget` instructions ran without a change. The
+    /// memory is read after every step, so this is slow, but it finds the
+    /// writer where no watchpoint can be set.
+    pub fn watch_value(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        address: u64,
+        width: u64,
+        budget: u64,
+    ) -> Result<Option<u64>, IrisError> {
+        if !(1..=8).contains(&width) {
+            return Err(IrisError::InvalidArgument(format!(
+                "Units of {} bytes can't be watched",
+                width
+            )));
+        }
+        let initial = read_unit(fvp, id, space, address, width)?;
+        for _ in 0..budget {
+            let pc = match resource::read_by_name(fvp, id, "PC") {
+                Err(IrisError::ResourceNotFound(_)) => resource::read_by_name(fvp, id, "R15")?,
+                pc => pc?,
+            };
+            step::instruction(fvp, id, 1)?;
+            if read_unit(fvp, id, space, address, width)? != initial {
+                return Ok(Some(pc));
+            }
+        }
+        Ok(None)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::iris_client::{error_reply, received, reply};
+        use serde_json::json;
+
+        fn space() -> Value {
+            json!([{"spaceId": 5, "name": "Memory", "minAddr": 0, "maxAddr": 0xffff_ffffu64}])
+        }
+
+        #[test]
+        fn gdb_crc32_is_crc32_mpeg2() {
+            // The check value of CRC-32/MPEG-2, the variant gdb's qCRC uses
+            assert_eq!(gdb_crc32(0xffff_ffff, b"123456789"), 0x0376_e6e7);
+            // Continuing a CRC gives the same as computing it in one go
+            let crc = gdb_crc32(0xffff_ffff, b"1234");
+            assert_eq!(gdb_crc32(crc, b"56789"), 0x0376_e6e7);
+        }
+
+        #[test]
+        fn regions_follow_sideband_info() {
+            let (mut iris, _server) = FastModelIris::scripted(&[
+                reply(0, space()),
+                reply(
+                    1,
+                    json!({"regionStart": 0, "regionEnd": 0x7fff_ffffu64,
+                           "regionName": "flash0", "memoryType": "Flash"}),
+                ),
+                reply(
+                    2,
+                    json!({"regionStart": 0x8000_0000u64, "regionEnd": 0xffff_ffffu64,
+                           "regionName": "uart0", "memoryType": "peripheral"}),
+                ),
+            ]);
+            let regions = regions(&mut iris, 3, 5).unwrap();
+            assert_eq!(regions.len(), 2);
+            assert_eq!(regions[0].name, "flash0");
+            assert_eq!(regions[0].kind, MemKind::Flash);
+            assert_eq!(regions[0].size, Some(0x8000_0000));
+            assert_eq!(regions[1].base, 0x8000_0000);
+            assert_eq!(regions[1].kind, MemKind::Device);
+            assert_eq!(regions[1].access.to_string(), "rw-");
+        }
+
+        #[test]
+        fn regions_without_sideband_info_are_the_space() {
+            let (mut iris, _server) =
+                FastModelIris::scripted(&[reply(0, space()), error_reply(1, "Unknown function")]);
+            let regions = regions(&mut iris, 3, 5).unwrap();
+            assert_eq!(
+                regions,
+                vec![MemRegion {
+                    name: "Memory".to_string(),
+                    base: 0,
+                    size: Some(0x1_0000_0000),
+                    description: None,
+                    kind: MemKind::Unknown,
+                    access: Access::ALL,
+                }]
+            );
+        }
+
+        #[test]
+        fn writes_are_split_and_stop_at_a_short_chunk() {
+            let (mut iris, server) = FastModelIris::scripted(&[
+                reply(0, json!({})),
+                reply(1, json!({"writtenCount": 1, "error": {"code": 1}})),
+            ]);
+            iris.set_max_request_bytes(8).unwrap();
+            let data: Vec<u8> = (0..24).collect();
+            assert_eq!(write(&mut iris, 3, 5, 0x1000, 4, &data).unwrap(), 3);
+            let requests = received(&server);
+            let params: Vec<_> = requests
+                .iter()
+                .map(|req| {
+                    (
+                        req["params"]["address"].clone(),
+                        req["params"]["count"].clone(),
+                    )
+                })
+                .collect();
+            // The third chunk isn't sent after the second stopped short
+            assert_eq!(
+                params,
+                [(json!(0x1000), json!(2)), (json!(0x1008), json!(2))]
+            );
+            assert_eq!(
+                requests[1]["params"]["data"],
+                json!([u64::from_le_bytes([8, 9, 10, 11, 12, 13, 14, 15])])
+            );
+        }
+
+        #[test]
+        fn a_fault_after_the_first_chunk_is_a_short_write() {
+            let (mut iris, _server) = FastModelIris::scripted(&[
+                reply(0, json!({})),
+                reply(1, json!({"error": {"code": 1}})),
+            ]);
+            iris.set_max_request_bytes(8).unwrap();
+            assert_eq!(write(&mut iris, 3, 5, 0, 1, &[0; 12]).unwrap(), 8);
+            let (mut iris, _server) =
+                FastModelIris::scripted(&[reply(0, json!({"error": {"code": 1}}))]);
+            assert!(matches!(
+                write(&mut iris, 3, 5, 0x40, 4, &[0; 8]),
+                Err(IrisError::MemoryFault(0x40))
+            ));
+        }
+    }
 }
 
 pub mod breakpoint {
     use crate::iris_client::FastModelIris;
+    use crate::IrisError;
     use serde::{Deserialize, Serialize};
-    use std::io::Error as IOError;
 
     #[allow(unused)]
     #[derive(Deserialize, Debug)]
@@ -535,7 +2657,7 @@ pub mod breakpoint {
         bpt_types: Option<Vec<Type>>,
     }
 
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
     #[serde(rename_all = "camelCase")]
     pub enum Type {
         Code,
@@ -572,7 +2694,23 @@ pub mod breakpoint {
         } -> u64
     );
 
-    iris_rpc_fn!(delete "breakpoint_delete"
+    /// A breakpoint as listed by Iris
+    #[derive(Deserialize, Debug)]
+    pub struct BreakpointInfo {
+        #[serde(rename = "bptId")]
+        pub id: u64,
+        #[serde(default)]
+        pub address: Option<u64>,
+    }
+
+    iris_rpc_fn!(list "breakpoint_getList"
+        GetList {
+            #[serde(rename = "instId")]
+            id: u32,
+        } -> Vec<BreakpointInfo>
+    );
+
+    iris_rpc_fn!(unset "breakpoint_delete"
         Delete {
             #[serde(rename = "instId")]
             instance: u32,
@@ -581,6 +2719,41 @@ pub mod breakpoint {
         } -> ()
     );
 
+    /// How a breakpoint set by `code` or `data` was set, so that
+    /// `FastModelIris::reconnect` can set it again
+    #[derive(Debug, Clone)]
+    pub(crate) struct Recorded {
+        pub address: u64,
+        pub rw_mode: Option<String>,
+        pub size: Option<u64>,
+        pub space_id: u64,
+        pub sync: bool,
+        pub typ: Type,
+        pub dont_stop: bool,
+        /// The id Iris knows the breakpoint by, which changes when it's set
+        /// again
+        pub current: u64,
+    }
+
+    /// Set a breakpoint again as it was first set, returning its new id.
+    pub(crate) fn restore(
+        fvp: &mut FastModelIris,
+        id: u32,
+        bpt: &Recorded,
+    ) -> Result<u64, IrisError> {
+        set(
+            fvp,
+            id,
+            bpt.address,
+            bpt.rw_mode.clone(),
+            bpt.size,
+            Some(bpt.space_id),
+            bpt.sync,
+            bpt.typ,
+            bpt.dont_stop,
+        )
+    }
+
     pub fn code(
         fvp: &mut FastModelIris,
         id: u32,
@@ -589,8 +2762,8 @@ pub mod breakpoint {
         space_id: u64,
         sync: bool,
         dont_stop: bool,
-    ) -> Result<u64, IOError> {
-        set(
+    ) -> Result<u64, IrisError> {
+        let bpt = set(
             fvp,
             id,
             addr,
@@ -600,11 +2773,73 @@ pub mod breakpoint {
             sync,
             Type::Code,
             dont_stop,
-        )
+        )?;
+        let recorded = Recorded {
+            address: addr,
+            rw_mode: None,
+            size,
+            space_id,
+            sync,
+            typ: Type::Code,
+            dont_stop,
+            current: bpt,
+        };
+        fvp.breakpoints.insert((id, bpt), recorded);
+        Ok(bpt)
+    }
+
+    /// Set a data breakpoint (watchpoint) on `size` bytes at `addr`.
+    /// `rw_mode` is one of `"r"`, `"w"` or `"rw"`.
+    pub fn data(
+        fvp: &mut FastModelIris,
+        id: u32,
+        addr: u64,
+        size: u64,
+        space_id: u64,
+        rw_mode: &str,
+        sync: bool,
+    ) -> Result<u64, IrisError> {
+        let bpt = set(
+            fvp,
+            id,
+            addr,
+            Some(rw_mode.to_string()),
+            Some(size),
+            Some(space_id),
+            sync,
+            Type::Data,
+            false,
+        )?;
+        let recorded = Recorded {
+            address: addr,
+            rw_mode: Some(rw_mode.to_string()),
+            size: Some(size),
+            space_id,
+            sync,
+            typ: Type::Data,
+            dont_stop: false,
+            current: bpt,
+        };
+        fvp.breakpoints.insert((id, bpt), recorded);
+        Ok(bpt)
+    }
+
+    /// Delete a breakpoint set by `code` or `data`.
+    pub fn delete(fvp: &mut FastModelIris, id: u32, breakpoint: u64) -> Result<(), IrisError> {
+        let current = fvp
+            .breakpoints
+            .get(&(id, breakpoint))
+            .map_or(breakpoint, |bpt| bpt.current);
+        unset(fvp, id, current)?;
+        fvp.breakpoints.remove(&(id, breakpoint));
+        Ok(())
     }
 }
 
 pub mod checkpoint {
+    use crate::IrisError;
+    use std::path::Path;
+
     iris_rpc_fn!(save "checkpoint_save"
         Save {
             #[serde(rename = "instId")]
@@ -621,37 +2856,207 @@ pub mod checkpoint {
             dir: String
         } -> ()
     );
+
+    /// The checkpoint directory as Iris expects it, which has to be UTF-8
+    pub(crate) fn dir_name(dir: &Path) -> Result<String, IrisError> {
+        dir.to_str().map(str::to_string).ok_or_else(|| {
+            IrisError::InvalidArgument(format!("{} is not valid UTF-8", dir.display()))
+        })
+    }
 }
 
 pub mod step {
+    use crate::{
+        breakpoint, disasm, instance_registry, resource, simulation_time, FastModelIris, IrisError,
+    };
     use serde::{Deserialize, Serialize};
+    use std::time::Duration;
+
+    /// What a step counts
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "camelCase")]
+    pub enum Unit {
+        Instruction,
+        Cycle,
+    }
+    iris_rpc_fn!(setup "step_setup"
+        Setup {
+            #[serde(rename = "instId")]
+            id: u32,
+            steps: u64,
+            unit: Unit
+        } -> ()
+    );
+    iris_rpc_fn!(counter "step_getStepCounterValue"
+        Counter {
+            #[serde(rename = "instId")]
+            id: u32,
+            unit: Unit
+        } -> u64
+    );
+    iris_rpc_fn!(remaining "step_getRemainingSteps"
+        Remain {
+            #[serde(rename = "instId")]
+            id: u32,
+            unit: Unit
+        } -> u64
+    );
+
+    /// Execute `count` instructions on the instance, blocking until the
+    /// simulation halts again. The step ends early if a breakpoint is hit.
+    pub fn instruction(fvp: &mut FastModelIris, id: u32, count: u64) -> Result<(), IrisError> {
+        by(fvp, id, count, Unit::Instruction)
+    }
+
+    /// Execute `count` steps of `unit` on the instance, as `instruction`
+    /// does. Fails with `IrisError::Unsupported` when the model can't step
+    /// the instance by `unit`, as with cycles on models that don't count
+    /// them.
+    pub fn by(fvp: &mut FastModelIris, id: u32, count: u64, unit: Unit) -> Result<(), IrisError> {
+        let sim =
+            instance_registry::get_instance_by_name(fvp, "framework.SimulationEngine".to_string())?;
+        match setup(fvp, id, count, unit) {
+            Err(IrisError::Protocol(msg)) => {
+                return Err(IrisError::Unsupported(format!(
+                    "Instance {} can't step by {:?} ({})",
+                    id, unit, msg
+                )))
+            }
+            res => res?,
+        }
+        fvp.run(sim.id)?;
+        fvp.wait_for_stop(sim.id, None)?;
+        Ok(())
+    }
+
+    /// Whether the disassembled instruction is a call, which writes the
+    /// return address to the link register.
+    fn is_call(text: &str) -> bool {
+        let mnemonic = text.split_whitespace().next().unwrap_or("");
+        // Drop a Thumb width qualifier such as `.w`
+        let mnemonic = mnemonic.split('.').next().unwrap_or("").to_lowercase();
+        matches!(
+            mnemonic.as_str(),
+            "bl" | "blx" | "blr" | "blraa" | "blraaz" | "blrab" | "blrabz"
+        )
+    }
+
+    /// Execute one instruction, running a call to completion rather than
+    /// stopping at its first instruction. See `over_or`.
+    pub fn over(
+        fvp: &mut FastModelIris,
+        id: u32,
+        timeout: Option<Duration>,
+    ) -> Result<simulation_time::StopEvent, IrisError> {
+        over_or(fvp, id, timeout, || false)
+    }
+
+    /// Execute one instruction, as `instruction` does, unless it is a call.
+    /// A call is run until it returns, using a temporary breakpoint after
+    /// it, and may stop early on another breakpoint, when `interrupted`
+    /// returns `true` or when `timeout` expires. The temporary breakpoint is
+    /// removed however the run ends.
+    pub fn over_or(
+        fvp: &mut FastModelIris,
+        id: u32,
+        timeout: Option<Duration>,
+        interrupted: impl FnMut() -> bool,
+    ) -> Result<simulation_time::StopEvent, IrisError> {
+        let sim =
+            instance_registry::get_instance_by_name(fvp, "framework.SimulationEngine".to_string())?;
+        let resources = resource::cached_list(fvp, id)?;
+        let wanted: Vec<_> = resources
+            .iter()
+            .filter(|res| ["PC", "R15", "PC_MEMSPACE"].contains(&res.name.as_str()))
+            .collect();
+        let values = resource::read_values(fvp, id, &wanted)?;
+        let value = |name: &str| {
+            wanted
+                .iter()
+                .find(|res| res.name == name)
+                .and_then(|res| values.get(&res.id))
+                .copied()
+        };
+        let pc = value("PC")
+            .or_else(|| value("R15"))
+            .ok_or_else(|| IrisError::ResourceNotFound("PC".to_string()))?;
+        let space = value("PC_MEMSPACE").unwrap_or(0);
+        // The address of the second line is the return address of a call
+        let lines = disasm::at(fvp, id, pc, 2, None)?;
+        let ret = match (lines.first(), lines.get(1)) {
+            (Some(insn), Some(next)) if is_call(&insn.text) => next.address,
+            _ => {
+                setup(fvp, id, 1, Unit::Instruction)?;
+                fvp.run(sim.id)?;
+                return fvp.wait_for_stop_or(sim.id, timeout, interrupted);
+            }
+        };
+        let bpt = breakpoint::code(fvp, id, ret, None, space, true, false)?;
+        let stop = fvp
+            .run(sim.id)
+            .and_then(|()| fvp.wait_for_stop_or(sim.id, timeout, interrupted));
+        breakpoint::delete(fvp, id, bpt)?;
+        stop
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::iris_client::{error_reply, reply};
+        use serde_json::json;
+
+        fn engine(id: u64) -> serde_json::Value {
+            reply(
+                id,
+                json!({"instId": 1, "instName": "framework.SimulationEngine"}),
+            )
+        }
+
+        #[test]
+        fn unsupported_unit_is_unsupported() {
+            let (mut iris, _server) =
+                FastModelIris::scripted(&[engine(0), error_reply(1, "Unknown step unit")]);
+            let res = by(&mut iris, 3, 1, Unit::Cycle);
+            assert!(matches!(res, Err(IrisError::Unsupported(_))), "{:?}", res);
+        }
 
-    #[derive(Serialize, Deserialize, Debug)]
-    #[serde(rename_all = "camelCase")]
-    pub enum Unit {
-        Instruction,
-        Cycle,
+        #[test]
+        fn step_runs_until_halted() {
+            let halted = json!({"ticks": 10, "tickHz": 1000, "running": false});
+            let (mut iris, _server) = FastModelIris::scripted(&[
+                engine(0),
+                reply(1, json!(null)),
+                reply(2, json!(null)),
+                reply(3, halted),
+            ]);
+            by(&mut iris, 3, 1, Unit::Cycle).unwrap();
+        }
     }
-    iris_rpc_fn!(setup "step_setup"
-        Setup {
-            #[serde(rename = "instId")]
-            id: u32,
-            steps: u64,
-            unit: Unit
-        } -> ()
-    );
-    iris_rpc_fn!(remaining "step_getRemainingSteps"
-        Remain {
-            #[serde(rename = "instId")]
-            id: u32,
-            unit: Unit
-        } -> u64
-    );
 }
 
 pub mod simulation_time {
     use serde::Deserialize;
 
+    /// The simulation halting, as seen by `FastModelIris::wait_for_stop`
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct StopEvent {
+        /// The simulation time when it halted
+        pub ticks: u64,
+        /// Whether the halt was requested by the interrupt callback
+        pub interrupted: bool,
+    }
+
+    /// How `FastModelIris::run_to` ended
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum RunTo {
+        /// The core stopped at the address
+        Reached,
+        /// The simulation stopped elsewhere first, with the core at `pc`
+        Stopped { pc: u64 },
+        /// The timeout expired first, and the simulation was stopped
+        TimedOut,
+    }
+
     #[derive(Deserialize, Debug)]
     #[serde(rename_all = "camelCase")]
     pub struct Time {
@@ -680,6 +3085,16 @@ pub mod simulation_time {
 }
 
 pub mod simulation {
+    /// How much of the model `FastModelIris::reset` resets
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ResetKind {
+        /// Reset every component, failing if any of them can't be reset
+        Cold,
+        /// Reset the components that support it and leave the rest as they
+        /// are
+        Warm,
+    }
+
     iris_rpc_fn!(reset "simulation_reset"
         Reset {
             #[serde(rename = "instId")]
@@ -706,11 +3121,22 @@ pub mod event_stream {
             to_id: u32,
             #[serde(rename = "evSrcId")]
             source: u32,
+            #[serde(rename = "ecFunc", skip_serializing_if = "Option::is_none")]
+            func: Option<String>,
             #[serde(rename = "ringBuffer")]
             buffer: bool,
         } -> u64
     );
 
+    iris_rpc_fn!(destroy "eventStream_destroy"
+        Destroy {
+            #[serde(rename = "instId")]
+            id: u32,
+            #[serde(rename = "esId")]
+            es_id: u64,
+        } -> ()
+    );
+
     iris_rpc_fn!(trace_ranges "eventStream_setTraceRanges"
         TraceRanges {
             #[serde(rename = "instId")]
@@ -725,6 +3151,8 @@ pub mod event_stream {
 
 pub mod event {
     use serde::Deserialize;
+    use serde_json::Value;
+    use std::sync::mpsc::Receiver;
 
     #[derive(Deserialize, Debug)]
     pub struct Field {
@@ -751,11 +3179,72 @@ pub mod event {
     iris_rpc_fn!(sources "event_getEventSources"
         Sources { #[serde(rename = "instId")] id: u32, } -> Vec<SourceInfo>
     );
+
+    /// The events that can be subscribed to with `FastModelIris::subscribe`
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum EventKind {
+        /// A breakpoint was hit
+        BreakpointHit,
+        /// The simulation started or stopped running
+        SimulationTime,
+        /// Semihosting is waiting on input
+        SemihostingInputRequest,
+        /// Any other event source, by name
+        Source(String),
+    }
+
+    impl EventKind {
+        /// The name of the Iris event source
+        pub fn source_name(&self) -> &str {
+            match self {
+                Self::BreakpointHit => "IRIS_BREAKPOINT_HIT",
+                Self::SimulationTime => "IRIS_SIMULATION_TIME_EVENT",
+                Self::SemihostingInputRequest => "IRIS_SEMIHOSTING_INPUT_REQUEST",
+                Self::Source(name) => name,
+            }
+        }
+    }
+
+    /// An event sent by Iris
+    #[derive(Debug, Clone)]
+    pub struct Event {
+        /// The event stream, and so subscription, the event belongs to
+        pub es_id: u64,
+        /// The instance the event happened on
+        pub instance_id: Option<u32>,
+        /// The simulation time of the event
+        pub time: Option<u64>,
+        /// The fields of the event, by name
+        pub fields: Value,
+    }
+
+    impl Event {
+        pub(crate) fn from_params(mut params: Value) -> Self {
+            Self {
+                es_id: params["esId"].as_u64().unwrap_or(0),
+                instance_id: params["sInstId"].as_u64().map(|id| id as u32),
+                time: params["time"].as_u64(),
+                fields: params["fields"].take(),
+            }
+        }
+    }
+
+    /// Events received from Iris, as returned by `FastModelIris::subscribe`
+    pub struct Subscription {
+        /// The id to pass to `FastModelIris::unsubscribe`
+        pub id: u64,
+        pub events: Receiver<Event>,
+    }
 }
 
 pub mod resource {
+    use crate::iris_client::FastModelIris;
+    use crate::IrisError;
     use serde::Deserialize;
     use serde_json::Value;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
     #[derive(Deserialize, Debug)]
     pub struct ResourceInfo {
         #[serde(rename = "bitWidth")]
@@ -778,16 +3267,152 @@ pub mod resource {
         GetList {
             #[serde(rename = "instId")]
             id: u32,
+            // Only list the resources in the group with exactly this name
             #[serde(skip_serializing_if = "Option::is_none")]
             group: Option<String>,
+            // Only list the resource with this id
             #[serde(rename = "rscId", skip_serializing_if = "Option::is_none")]
             resource_id: Option<u32>,
         } -> Vec<ResourceInfo>
     );
 
+    #[derive(Deserialize, Debug)]
+    pub struct ResourceGroup {
+        pub name: String,
+        pub description: Option<String>,
+        #[serde(rename = "rscIds", default)]
+        pub resource_ids: Vec<u64>,
+    }
+
+    iris_rpc_fn!(groups "resource_getListOfResourceGroups"
+        GetGroups {
+            #[serde(rename = "instId")]
+            id: u32
+        } -> Vec<ResourceGroup>
+    );
+
+    /// A resource of an instance, with its group and access mode resolved
+    #[derive(Debug, Clone)]
+    pub struct Resource {
+        pub id: u64,
+        pub name: String,
+        /// The first group that lists this resource, if any does
+        pub group: Option<String>,
+        pub bit_width: u64,
+        pub readable: bool,
+        pub writable: bool,
+    }
+
+    /// The resources of an instance, for searching with iterators
+    #[derive(Debug, Clone)]
+    pub struct Resources(Vec<Resource>);
+
+    impl Resources {
+        pub fn iter(&self) -> std::slice::Iter<'_, Resource> {
+            self.0.iter()
+        }
+
+        /// The resources in the group called `group`
+        pub fn by_group<'a>(&'a self, group: &'a str) -> impl Iterator<Item = &'a Resource> {
+            self.iter()
+                .filter(move |res| res.group.as_deref() == Some(group))
+        }
+
+        /// The resources that may be written
+        pub fn writable_only(&self) -> impl Iterator<Item = &Resource> {
+            self.iter().filter(|res| res.writable)
+        }
+
+        /// The resource called `name`
+        pub fn by_name(&self, name: &str) -> Option<&Resource> {
+            self.iter().find(|res| res.name == name)
+        }
+    }
+
+    impl<'a> IntoIterator for &'a Resources {
+        type Item = &'a Resource;
+        type IntoIter = std::slice::Iter<'a, Resource>;
+        fn into_iter(self) -> Self::IntoIter {
+            self.iter()
+        }
+    }
+
+    impl IntoIterator for Resources {
+        type Item = Resource;
+        type IntoIter = std::vec::IntoIter<Resource>;
+        fn into_iter(self) -> Self::IntoIter {
+            self.0.into_iter()
+        }
+    }
+
+    /// Build the resource table of an instance from the cached resource list
+    /// and its groups. Iris gives the access mode as `rwMode`, which is "r",
+    /// "w" or "rw" and defaults to "rw" when missing.
+    pub fn table(fvp: &mut FastModelIris, id: u32) -> Result<Resources, IrisError> {
+        let list = cached_list(fvp, id)?;
+        let mut group_of = HashMap::new();
+        for group in groups(fvp, id)? {
+            for &rsc_id in &group.resource_ids {
+                group_of.entry(rsc_id).or_insert_with(|| group.name.clone());
+            }
+        }
+        Ok(Resources(
+            list.iter()
+                .map(|info| {
+                    let mode = info.rw_mode.as_deref().unwrap_or("rw");
+                    Resource {
+                        id: info.id,
+                        name: info.name.clone(),
+                        group: group_of.get(&info.id).cloned(),
+                        bit_width: info.bit_width,
+                        readable: mode.contains('r'),
+                        writable: mode.contains('w'),
+                    }
+                })
+                .collect(),
+        ))
+    }
+
+    /// List the resources in `group`, an exact group name, whose names start
+    /// with `prefix`. Iris filters by group itself, but has no filter on the
+    /// name, so the prefix is matched here. The gdb stubs pick their
+    /// registers out of `cached_list` instead, which costs no request once
+    /// the list is cached.
+    pub fn get_list_filtered(
+        fvp: &mut FastModelIris,
+        id: u32,
+        group: Option<&str>,
+        prefix: Option<&str>,
+    ) -> Result<Vec<ResourceInfo>, IrisError> {
+        let mut list = get_list(fvp, id, group.map(str::to_string), None)?;
+        if let Some(prefix) = prefix {
+            list.retain(|res| res.name.starts_with(prefix));
+        }
+        Ok(list)
+    }
+
+    /// The resources of an instance, as returned by `get_list` without a
+    /// group or resource id. The list is only fetched from Iris the first
+    /// time; see `FastModelIris::invalidate_resources`.
+    pub fn cached_list(
+        fvp: &mut FastModelIris,
+        id: u32,
+    ) -> Result<Arc<Vec<ResourceInfo>>, IrisError> {
+        if let Some(list) = fvp.resources.get(&id) {
+            return Ok(list.clone());
+        }
+        let list = Arc::new(get_list(fvp, id, None, None)?);
+        fvp.resources.insert(id, list.clone());
+        Ok(list)
+    }
+
     #[derive(Deserialize, Debug)]
     pub struct ResourceRead {
         pub data: Vec<u64>,
+        /// Pairs of resource id and Iris error code, one for each resource
+        /// that could not be read.
+        #[serde(default)]
+        pub error: Vec<u64>,
     }
 
     iris_rpc_fn!(read "resource_read"
@@ -798,7 +3423,691 @@ pub mod resource {
             resource_ids: Vec<u64>,
         } -> ResourceRead
     );
+
+    /// Read several resources in one request, returning the full value of
+    /// each one that could be read as 64 bit words, least significant first,
+    /// keyed by resource id. Iris packs the values in request order, with
+    /// resources wider than 64 bits taking more than one word.
+    pub fn read_words(
+        fvp: &mut FastModelIris,
+        id: u32,
+        resources: &[&ResourceInfo],
+    ) -> Result<HashMap<u64, Vec<u64>>, IrisError> {
+        let ids = resources.iter().map(|res| res.id).collect();
+        let res = read(fvp, id, ids)?;
+        let failed: Vec<u64> = res.error.chunks(2).map(|pair| pair[0]).collect();
+        let mut values = HashMap::new();
+        let mut offset = 0;
+        for info in resources {
+            let len = info.bit_width.div_ceil(64).max(1) as usize;
+            if let Some(words) = res.data.get(offset..offset + len) {
+                if !failed.contains(&info.id) {
+                    values.insert(info.id, words.to_vec());
+                }
+            }
+            offset += len;
+        }
+        Ok(values)
+    }
+
+    /// A resource's value as bytes, as read by `read_bytes`
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct ResourceBytes {
+        /// The value, least significant byte first
+        pub bytes: Vec<u8>,
+        /// How many bits of `bytes` the resource has; the bits above them
+        /// in the last byte are not part of the value
+        pub bit_width: u64,
+    }
+
+    /// Read a resource of any width as bytes, for vector registers and
+    /// other resources wider than 64 bits. The bytes are little endian:
+    /// byte `n` holds bits `8n` to `8n + 7`, whatever the byte order of the
+    /// model, as Iris sends values as 64 bit words, least significant word
+    /// first. There are as many bytes as it takes to hold `bit_width` bits.
+    pub fn read_bytes(
+        fvp: &mut FastModelIris,
+        id: u32,
+        resource: &ResourceInfo,
+    ) -> Result<ResourceBytes, IrisError> {
+        let words = read_words(fvp, id, &[resource])?
+            .remove(&resource.id)
+            .ok_or_else(|| IrisError::Protocol(format!("Could not read {}", resource.name)))?;
+        let mut bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        bytes.truncate(resource.bit_width.div_ceil(8) as usize);
+        Ok(ResourceBytes {
+            bytes,
+            bit_width: resource.bit_width,
+        })
+    }
+
+    /// Read the resource called `name`, looked up in the cached resource
+    /// list. Only the low 64 bits of wider resources are returned.
+    pub fn read_by_name(fvp: &mut FastModelIris, id: u32, name: &str) -> Result<u64, IrisError> {
+        let resources = cached_list(fvp, id)?;
+        let info = resources
+            .iter()
+            .find(|res| res.name == name)
+            .ok_or_else(|| IrisError::ResourceNotFound(name.to_string()))?;
+        read_values(fvp, id, &[info])?
+            .get(&info.id)
+            .copied()
+            .ok_or_else(|| IrisError::Protocol(format!("Could not read {}", name)))
+    }
+
+    /// Write `value` to the resource called `name`, looked up in the cached
+    /// resource list.
+    pub fn write_by_name(
+        fvp: &mut FastModelIris,
+        id: u32,
+        name: &str,
+        value: u64,
+    ) -> Result<(), IrisError> {
+        let rsc_id = cached_list(fvp, id)?
+            .iter()
+            .find(|res| res.name == name)
+            .map(|res| res.id)
+            .ok_or_else(|| IrisError::ResourceNotFound(name.to_string()))?;
+        write(fvp, id, vec![rsc_id], vec![value])
+    }
+
+    /// Replace the value of the resource called `name` with `update` of its
+    /// current value, returning the new value. The read and the write are
+    /// separate requests, so the model should be halted for nothing else to
+    /// change the resource in between.
+    pub fn update_by_name(
+        fvp: &mut FastModelIris,
+        id: u32,
+        name: &str,
+        update: impl FnOnce(u64) -> u64,
+    ) -> Result<u64, IrisError> {
+        let resources = cached_list(fvp, id)?;
+        let info = resources
+            .iter()
+            .find(|res| res.name == name)
+            .ok_or_else(|| IrisError::ResourceNotFound(name.to_string()))?;
+        let old = read_values(fvp, id, &[info])?
+            .get(&info.id)
+            .copied()
+            .ok_or_else(|| IrisError::Protocol(format!("Could not read {}", name)))?;
+        let new = update(old);
+        write(fvp, id, vec![info.id], vec![new])?;
+        Ok(new)
+    }
+
+    /// Like `read_words`, but keep only the low 64 bits of each resource.
+    pub fn read_values(
+        fvp: &mut FastModelIris,
+        id: u32,
+        resources: &[&ResourceInfo],
+    ) -> Result<HashMap<u64, u64>, IrisError> {
+        Ok(read_words(fvp, id, resources)?
+            .into_iter()
+            .map(|(id, words)| (id, words[0]))
+            .collect())
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct ResourceWrite {
+        /// Pairs of resource id and Iris error code, one for each resource
+        /// that could not be written.
+        #[serde(default)]
+        pub error: Vec<u64>,
+    }
+
+    iris_rpc_fn!(write_raw "resource_write"
+        Write {
+            #[serde(rename = "instId")]
+            id: u32,
+            #[serde(rename = "rscIds")]
+            resource_ids: Vec<u64>,
+            data: Vec<u64>,
+        } -> Option<ResourceWrite>
+    );
+
+    /// Write `values[n]` to the resource `ids[n]`. Fails if any of the
+    /// resources could not be written, such as when a resource is read-only.
+    /// Values wider than the resource are rejected before anything is sent.
+    pub fn write(
+        fvp: &mut FastModelIris,
+        id: u32,
+        ids: Vec<u64>,
+        values: Vec<u64>,
+    ) -> Result<(), IrisError> {
+        if ids.len() != values.len() {
+            return Err(IrisError::InvalidArgument(format!(
+                "{} resource ids given with {} values",
+                ids.len(),
+                values.len()
+            )));
+        }
+        let resources = cached_list(fvp, id)?;
+        for (rsc_id, &value) in ids.iter().zip(&values) {
+            let info = match resources.iter().find(|res| res.id == *rsc_id) {
+                Some(info) => info,
+                None => continue,
+            };
+            if (1..64).contains(&info.bit_width) && value >> info.bit_width != 0 {
+                return Err(IrisError::ValueTooWide {
+                    resource: info.name.clone(),
+                    bit_width: info.bit_width,
+                    value,
+                });
+            }
+        }
+        match write_raw(fvp, id, ids, values)? {
+            Some(ResourceWrite { error }) if !error.is_empty() => {
+                let failed = error
+                    .chunks(2)
+                    .map(|pair| format!("{:?}", pair))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                Err(IrisError::Protocol(format!(
+                    "Could not write resources [id, error code]: {}",
+                    failed
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::iris_client::{received, reply};
+        use serde_json::json;
+
+        fn info(id: u64, name: &str) -> Value {
+            json!({"rscId": id, "name": name, "cname": name, "bitWidth": 32,
+                   "description": null, "parent_id": null})
+        }
+
+        #[test]
+        fn wide_values_take_several_words() {
+            let mut list = vec![info(0, "R0"), info(1, "Q0"), info(2, "R1")];
+            list[1]["bitWidth"] = json!(128);
+            let list: Vec<ResourceInfo> = serde_json::from_value(json!(list)).unwrap();
+            let low = 0x89ab_cdef_0000_0001u64;
+            let data = json!({"data": [1, low, 0x0123_4567, 3]});
+            let (mut iris, _server) = FastModelIris::scripted(&[reply(0, data)]);
+            let values = read_words(&mut iris, 3, &list.iter().collect::<Vec<_>>()).unwrap();
+            assert_eq!(values[&0], [1]);
+            let q0 = &values[&1];
+            let q0 = u128::from(q0[0]) | u128::from(q0[1]) << 64;
+            assert_eq!(q0, 0x0123_4567_89ab_cdef_0000_0001);
+            // The words after a wide value belong to the next resource
+            assert_eq!(values[&2], [3]);
+        }
+
+        #[test]
+        fn filtered_list_matches_name_prefix_within_group() {
+            // The core group of a Cortex-M model, as Iris lists it
+            let core = json!([
+                info(0, "R0"),
+                info(1, "R1"),
+                info(15, "R15"),
+                info(16, "XPSR"),
+                info(17, "MSP"),
+                info(18, "RESERVED"),
+            ]);
+            let (mut iris, server) = FastModelIris::scripted(&[reply(0, core)]);
+            let list = get_list_filtered(&mut iris, 3, Some("Core"), Some("R")).unwrap();
+            let names: Vec<_> = list.iter().map(|res| res.name.as_str()).collect();
+            assert_eq!(names, ["R0", "R1", "R15", "RESERVED"]);
+            // The group is left to Iris
+            let requests = received(&server);
+            assert_eq!(requests[0]["params"], json!({"instId": 3, "group": "Core"}));
+        }
+    }
+}
+
+/// System registers, such as SCTLR or VBAR on Cortex-A cores and the SCB
+/// registers on Cortex-M ones, found by name among an instance's resources.
+pub mod sysreg {
+    use crate::iris_client::FastModelIris;
+    use crate::resource::{self, Resource};
+    use crate::IrisError;
+
+    /// Whether Iris puts system registers in the resource group `group`.
+    /// Models differ in how they name these groups, but they have in
+    /// common that the name says "system", or "CP15" on Armv7-A.
+    fn is_system_group(group: &str) -> bool {
+        let group = group.to_ascii_lowercase();
+        group.contains("system") || group == "cp15"
+    }
+
+    /// The resource names a system register may go by: the name itself,
+    /// and for a name without an exception level suffix its EL1 and
+    /// Non-secure banked names, so that `SCTLR` finds `SCTLR_EL1` on
+    /// AArch64 and `SCTLR_NS` on a core with the Security Extensions.
+    fn candidates(name: &str) -> Vec<String> {
+        let name = name.to_ascii_uppercase();
+        if name.contains("_EL") || name.ends_with("_NS") || name.ends_with("_S") {
+            return vec![name];
+        }
+        let el1 = format!("{}_EL1", name);
+        let non_secure = format!("{}_NS", name);
+        vec![name, el1, non_secure]
+    }
+
+    /// The system registers of the instance, those in its system groups
+    pub fn list(fvp: &mut FastModelIris, id: u32) -> Result<Vec<Resource>, IrisError> {
+        Ok(fvp
+            .resources(id)?
+            .into_iter()
+            .filter(|res| res.group.as_deref().is_some_and(is_system_group))
+            .collect())
+    }
+
+    /// Find the system register called `name`, ignoring case and trying the
+    /// names of `candidates` in turn. A register in a system group is
+    /// preferred over a core register of the same name.
+    pub fn find(fvp: &mut FastModelIris, id: u32, name: &str) -> Result<Resource, IrisError> {
+        let resources = fvp.resources(id)?;
+        for candidate in candidates(name) {
+            let mut found: Vec<&Resource> = resources
+                .iter()
+                .filter(|res| res.name.eq_ignore_ascii_case(&candidate))
+                .collect();
+            found.sort_by_key(|res| !res.group.as_deref().is_some_and(is_system_group));
+            if let Some(res) = found.first() {
+                return Ok((*res).clone());
+            }
+        }
+        Err(IrisError::ResourceNotFound(name.to_string()))
+    }
+
+    /// Read the system register called `name`; see `find`.
+    pub fn read(fvp: &mut FastModelIris, id: u32, name: &str) -> Result<u64, IrisError> {
+        let res = find(fvp, id, name)?;
+        if !res.readable {
+            return Err(IrisError::InvalidArgument(format!(
+                "{} is write only",
+                res.name
+            )));
+        }
+        resource::read_by_name(fvp, id, &res.name)
+    }
+
+    /// Write `value` to the system register called `name`; see `find`.
+    pub fn write(
+        fvp: &mut FastModelIris,
+        id: u32,
+        name: &str,
+        value: u64,
+    ) -> Result<(), IrisError> {
+        let res = find(fvp, id, name)?;
+        if !res.writable {
+            return Err(IrisError::InvalidArgument(format!(
+                "{} is read only",
+                res.name
+            )));
+        }
+        resource::write(fvp, id, vec![res.id], vec![value])
+    }
+}
+
+pub mod disasm {
+    use crate::iris_client::FastModelIris;
+    use crate::IrisError;
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug)]
+    pub struct Mode {
+        pub name: String,
+        pub description: Option<String>,
+    }
+
+    iris_rpc_fn!(modes "disassembler_getModes"
+        GetModes {
+            #[serde(rename = "instId")]
+            id: u32
+        } -> Vec<Mode>
+    );
+
+    iris_rpc_fn!(current_mode "disassembler_getCurrentMode"
+        GetCurrentMode {
+            #[serde(rename = "instId")]
+            id: u32
+        } -> String
+    );
+
+    #[derive(Deserialize, Debug)]
+    pub struct DisasmLine {
+        pub address: u64,
+        /// The encoding of the instruction, as hex digits printed by the model
+        pub opcode: String,
+        #[serde(rename = "disass")]
+        pub text: String,
+    }
+
+    iris_rpc_fn!(get "disassembler_getDisassembly"
+        GetDisassembly {
+            #[serde(rename = "instId")]
+            id: u32,
+            address: u64,
+            count: u64,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            mode: Option<String>
+        } -> Vec<DisasmLine>
+    );
+
+    /// Disassemble `count` instructions from `address` with the model's own
+    /// decoder, in `mode` or else the mode the core is executing in.
+    pub fn at(
+        fvp: &mut FastModelIris,
+        id: u32,
+        address: u64,
+        count: u64,
+        mode: Option<&str>,
+    ) -> Result<Vec<DisasmLine>, IrisError> {
+        get(fvp, id, address, count, mode.map(str::to_string))
+    }
+}
+
+pub mod image {
+    use crate::iris_client::FastModelIris;
+    use crate::{memory, resource, IrisError};
+    use std::convert::TryInto;
+    use std::fs;
+    use std::path::Path;
+
+    /// Program header type of a segment that is loaded into memory
+    const PT_LOAD: u32 = 1;
+
+    /// A loaded segment of an ELF image
+    #[derive(Debug)]
+    pub struct Segment {
+        /// The physical address the segment was placed at
+        pub address: u64,
+        /// The number of bytes copied from the file
+        pub file_size: u64,
+        /// The size of the segment in memory; the bytes past `file_size`
+        /// are zeroed
+        pub mem_size: u64,
+    }
+
+    /// What `load_elf` placed in memory
+    #[derive(Debug)]
+    pub struct LoadSummary {
+        /// The entry point from the ELF header, moved by `offset`
+        pub entry: u64,
+        /// How far the image was moved from its link addresses
+        pub offset: u64,
+        /// The memory space the segments were written to
+        pub space: u64,
+        pub segments: Vec<Segment>,
+        /// The initial stack pointer and reset vector, taken from the first
+        /// two words of the lowest segment of a 32 bit image, where an
+        /// M-profile core expects its vector table. The low bit of the reset
+        /// vector is left as it is in the image.
+        pub vector_table: Option<(u32, u32)>,
+    }
+
+    fn malformed(path: &Path, what: &str) -> IrisError {
+        IrisError::InvalidArgument(format!(
+            "{} is not a valid ELF file: {}",
+            path.display(),
+            what
+        ))
+    }
+
+    /// Read an unsigned little endian field of `width` bytes at `offset`
+    fn field(data: &[u8], offset: usize, width: usize) -> Option<u64> {
+        let bytes = data.get(offset..offset.checked_add(width)?)?;
+        let mut word = [0u8; 8];
+        word[..width].copy_from_slice(bytes);
+        Some(u64::from_le_bytes(word))
+    }
+
+    /// The parts of an ELF file needed to load it
+    struct ElfFile<'a> {
+        entry: u64,
+        is_32: bool,
+        /// The PT_LOAD segments, as their physical address, the bytes from
+        /// the file and their size in memory
+        segments: Vec<(u64, &'a [u8], u64)>,
+    }
+
+    /// Parse the header and program headers of a little endian ELF file
+    fn parse<'a>(path: &Path, data: &'a [u8]) -> Result<ElfFile<'a>, IrisError> {
+        if data.get(..4) != Some(b"\x7fELF") {
+            return Err(malformed(path, "bad magic"));
+        }
+        let is_32 = match data.get(4) {
+            Some(1) => true,
+            Some(2) => false,
+            _ => return Err(malformed(path, "unknown class")),
+        };
+        if data.get(5) != Some(&1) {
+            return Err(IrisError::Unsupported(format!(
+                "{} is not a little endian ELF file",
+                path.display()
+            )));
+        }
+        let word = if is_32 { 4 } else { 8 };
+        let header = |offset, width| {
+            field(data, offset, width).ok_or_else(|| malformed(path, "truncated header"))
+        };
+        let entry = header(24, word)?;
+        let phoff = header(24 + word, word)? as usize;
+        let (phentsize, phnum) = if is_32 {
+            (header(42, 2)? as usize, header(44, 2)? as usize)
+        } else {
+            (header(54, 2)? as usize, header(56, 2)? as usize)
+        };
+        let mut segments = Vec::new();
+        for index in 0..phnum {
+            let ph = phoff.saturating_add(index * phentsize);
+            let ph_field = |offset: usize, width| {
+                field(data, ph.saturating_add(offset), width)
+                    .ok_or_else(|| malformed(path, "truncated program header"))
+            };
+            if ph_field(0, 4)? as u32 != PT_LOAD {
+                continue;
+            }
+            // The 64 bit header moves p_flags up to second place
+            let (offset, paddr, filesz, memsz) = if is_32 {
+                (
+                    ph_field(4, 4)?,
+                    ph_field(12, 4)?,
+                    ph_field(16, 4)?,
+                    ph_field(20, 4)?,
+                )
+            } else {
+                (
+                    ph_field(8, 8)?,
+                    ph_field(24, 8)?,
+                    ph_field(32, 8)?,
+                    ph_field(40, 8)?,
+                )
+            };
+            let bytes = (offset as usize)
+                .checked_add(filesz as usize)
+                .and_then(|end| data.get(offset as usize..end))
+                .ok_or_else(|| malformed(path, "segment extends past the end of the file"))?;
+            segments.push((paddr, bytes, memsz.max(filesz)));
+        }
+        Ok(ElfFile {
+            entry,
+            is_32,
+            segments,
+        })
+    }
+
+    /// Write all of `bytes` at `address`, split into requests as
+    /// `FastModelIris::set_max_request_bytes` allows, calling `progress`
+    /// with the number of bytes written so far after each one.
+    fn write_chunks(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        address: u64,
+        bytes: &[u8],
+        mut progress: Option<&mut dyn FnMut(u64, u64)>,
+    ) -> Result<(), IrisError> {
+        let total = bytes.len() as u64;
+        let mut done = 0;
+        for chunk in bytes.chunks(fvp.max_request_bytes) {
+            let addr = address + done;
+            let written = memory::write(fvp, id, space, addr, 1, chunk)?;
+            if written < chunk.len() as u64 {
+                return Err(IrisError::MemoryFault(addr + written));
+            }
+            done += written;
+            if let Some(progress) = progress.as_mut() {
+                progress(done, total);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a raw binary image to `space` at `address`. `progress`, when
+    /// given, is called with the bytes written so far and the total after
+    /// every request. With `verify`, the memory is read back afterwards and
+    /// any difference from the image is an error.
+    pub fn load_bin(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        address: u64,
+        bytes: &[u8],
+        progress: Option<&mut dyn FnMut(u64, u64)>,
+        verify: bool,
+    ) -> Result<(), IrisError> {
+        write_chunks(fvp, id, space, address, bytes, progress)?;
+        if verify {
+            if let Some(offset) = memory::compare(fvp, id, space, address, bytes)? {
+                return Err(IrisError::VerifyFailed(address + offset));
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the raw binary image in the file at `path` as `load_bin` does,
+    /// returning its size.
+    pub fn load_bin_file(
+        fvp: &mut FastModelIris,
+        id: u32,
+        space: u64,
+        address: u64,
+        path: &Path,
+        progress: Option<&mut dyn FnMut(u64, u64)>,
+        verify: bool,
+    ) -> Result<u64, IrisError> {
+        let bytes = fs::read(path)?;
+        load_bin(fvp, id, space, address, &bytes, progress, verify)?;
+        Ok(bytes.len() as u64)
+    }
+
+    /// Load the PT_LOAD segments of the ELF file at `path` into the memory
+    /// space that the PC of the instance fetches from, each at its physical
+    /// address. Bytes of a segment beyond those in the file are zeroed.
+    /// Registers are left alone; the returned summary has the entry point
+    /// and, for 32 bit images, the vector table for the caller to set the
+    /// PC and SP from.
+    pub fn load_elf(
+        fvp: &mut FastModelIris,
+        id: u32,
+        path: &Path,
+    ) -> Result<LoadSummary, IrisError> {
+        load_elf_at(fvp, id, path, 0)
+    }
+
+    /// Load a position independent ELF file as `load_elf` does, with every
+    /// segment moved up by `offset` bytes from its physical address. The
+    /// addresses wrap, so a wrapped offset moves the image down.
+    pub fn load_elf_at(
+        fvp: &mut FastModelIris,
+        id: u32,
+        path: &Path,
+        offset: u64,
+    ) -> Result<LoadSummary, IrisError> {
+        let data = fs::read(path)?;
+        let ElfFile {
+            entry,
+            is_32,
+            segments,
+        } = parse(path, &data)?;
+        let space_id = match resource::read_by_name(fvp, id, "PC_MEMSPACE") {
+            Ok(space) => space,
+            Err(IrisError::ResourceNotFound(_)) => 0,
+            Err(e) => return Err(e),
+        };
+        let space = memory::spaces(fvp, id)?
+            .into_iter()
+            .find(|space| space.id == space_id)
+            .ok_or_else(|| {
+                IrisError::InvalidArgument(format!(
+                    "Instance {} has no memory space {}",
+                    id, space_id
+                ))
+            })?;
+        let min = space.min_addr.unwrap_or(0);
+        let max = space.max_addr.unwrap_or(u64::MAX);
+        let mut loaded = Vec::new();
+        for (address, bytes, mem_size) in segments {
+            if mem_size == 0 {
+                continue;
+            }
+            let address = address.wrapping_add(offset);
+            let last = address.checked_add(mem_size - 1);
+            if address < min || last.is_none_or(|last| last > max) {
+                return Err(IrisError::InvalidArgument(format!(
+                    "Segment at {:#x}..{:#x} is outside memory space {} ({:#x}..={:#x})",
+                    address,
+                    address.wrapping_add(mem_size),
+                    space.name,
+                    min,
+                    max
+                )));
+            }
+            write_chunks(fvp, id, space_id, address, bytes, None)?;
+            let file_size = bytes.len() as u64;
+            if mem_size > file_size {
+                memory::fill(
+                    fvp,
+                    id,
+                    space_id,
+                    address + file_size,
+                    1,
+                    0,
+                    mem_size - file_size,
+                )?;
+            }
+            loaded.push((
+                Segment {
+                    address,
+                    file_size,
+                    mem_size,
+                },
+                bytes,
+            ));
+        }
+        let vector_table = if is_32 {
+            loaded
+                .iter()
+                .filter(|(_, bytes)| bytes.len() >= 8)
+                .min_by_key(|(segment, _)| segment.address)
+                .map(|(_, bytes)| {
+                    (
+                        u32::from_le_bytes(bytes[..4].try_into().unwrap()),
+                        u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+                    )
+                })
+        } else {
+            None
+        };
+        Ok(LoadSummary {
+            entry: entry.wrapping_add(offset),
+            offset,
+            space: space_id,
+            segments: loaded.into_iter().map(|(segment, _)| segment).collect(),
+            vector_table,
+        })
+    }
 }
 
-pub use iris_client::FastModelIris;
+pub use error::IrisError;
+pub use iris_client::{FastModelIris, SharedIris};
 pub mod gdb;
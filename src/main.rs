@@ -1,15 +1,21 @@
 use std::convert::TryInto;
-use std::io::{stdin, stdout};
+use std::fs::File;
+use std::io::{empty, stderr, stdin, stdout};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 use clap::{Parser, Subcommand};
-use gdbstub::GdbStub;
+use gdbstub::conn::ConnectionExt;
+use gdbstub::stub::{GdbStub, GdbStubError};
 
+use cornea::gdb::EventLoop;
 #[allow(unused)]
 use cornea::{
     breakpoint, checkpoint, event, event_stream, instance_registry, memory, resource, simulation,
-    simulation_time, step, FastModelIris,
+    simulation_time, step, FastModelIris, IrisError,
 };
+use instance_registry::ArchKind;
 
 #[derive(Parser, Debug)]
 struct Cli {
@@ -17,6 +23,9 @@ struct Cli {
     command: Command,
     #[clap(short, long)]
     port: Option<u16>,
+    /// Log the messages exchanged with Iris to this file
+    #[clap(long)]
+    trace: Option<PathBuf>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -25,16 +34,21 @@ enum Command {
     ResourceList(InstanceArgs),
     /// Print the children of this instance
     ChildList(OptionalInstanceArgs),
+    /// Print the instances that execute software
+    CpuList,
     /// Read memory from the prespective of an instance
     MemoryRead(ReadMemArgs),
     /// Break at a pc range
     Break(ReadMemArgs),
     /// Reset the platform
     Reset,
+    /// Stop the simulation if it is running
+    Stop,
     /// Read a reesource on an instance
     ResourceRead(ResourceReadArgs),
-    /// Provide a GDB server for the iris server over a pipe
-    GdbProxy(InstanceArgs),
+    /// Provide a GDB server for the iris server over a pipe, or over TCP
+    /// with --listen
+    GdbProxy(GdbProxyArgs),
 }
 
 #[derive(Parser, Debug)]
@@ -49,6 +63,61 @@ struct InstanceArgs {
     inst: String,
 }
 
+#[derive(Parser, Debug)]
+struct GdbProxyArgs {
+    /// The names of the instances to debug. The last components of a name,
+    /// such as `cpu0`, are enough when they are unique. Several Armv7-M
+    /// cores are shown to gdb as threads
+    #[clap(required = true)]
+    inst: Vec<String>,
+    /// Wait for gdb to connect on this TCP port instead of using stdio
+    #[clap(short, long)]
+    listen: Option<u16>,
+    /// Access memory through this Iris memory space instead of the one the
+    /// PC is in
+    #[clap(short, long)]
+    space: Option<u64>,
+    /// On Armv8-M with TrustZone, access memory through the space the PC
+    /// is in each time the core stops, following its security state
+    #[clap(long)]
+    follow_security_state: bool,
+    /// Stop the model and report SIGALRM when a continue runs for longer
+    /// than this many seconds
+    #[clap(short, long)]
+    timeout: Option<u64>,
+    /// Stop an Armv7-M model and report SIGXCPU when a continue executes
+    /// no instructions for this many seconds
+    #[clap(long)]
+    watchdog: Option<u64>,
+    /// Service the semihosting calls of Armv7-M firmware. The model's own
+    /// semihosting must be disabled
+    #[clap(long)]
+    semihosting: bool,
+    /// Let semihosting firmware read the files in this directory
+    #[clap(long, requires = "semihosting")]
+    semihosting_root: Option<PathBuf>,
+    /// Set hardware breakpoints for gdb's `break` on Armv7-M, so that it
+    /// works on code in flash
+    #[clap(long)]
+    hw_breakpoints: bool,
+    /// The size in bytes of the blocks gdb's `load` erases Armv7-M flash
+    /// in, 4096 unless given
+    #[clap(long)]
+    flash_block_size: Option<u64>,
+    /// The largest gdb packet, in bytes, which bounds the memory gdb reads
+    /// or writes at once
+    #[clap(long, default_value_t = 4096)]
+    packet_size: usize,
+    /// With --listen, serve each Armv7-M core on a port of its own, counting
+    /// up from the one given, for one gdb to connect to each core
+    #[clap(long, requires = "listen")]
+    separate: bool,
+    /// Run an Armv7-M model again once gdb detaches, rather than leaving
+    /// it halted
+    #[clap(long)]
+    resume_on_detach: bool,
+}
+
 #[derive(Parser, Debug)]
 struct ReadMemArgs {
     /// The name of the instance to read from
@@ -77,7 +146,7 @@ fn enable_events(
     my_id: u32,
     cpus: &[instance_registry::Instance],
     event_names: &[&str],
-) -> std::io::Result<()> {
+) -> Result<(), IrisError> {
     for cpu in cpus {
         let sources = event_names
             .iter()
@@ -94,6 +163,7 @@ fn enable_events(
                 disable: false,
                 to_id: my_id,
                 source: src.id,
+                func: None,
                 buffer: false,
             })
             .collect::<Vec<_>>();
@@ -174,20 +244,136 @@ fn print_hex_dump(address: u64, buff: &[u8], group_by: GroupBy) {
                 print!(" ");
             }
         }
-        println!("");
+        println!();
+    }
+}
+
+/// The connection to gdb, over a pipe or TCP
+type GdbConnection = Box<dyn ConnectionExt<Error = std::io::Error>>;
+
+/// Report how a gdb session ended. A connection error, such as gdb exiting
+/// mid-session, ends the session like a disconnect does.
+fn report_disconnect<T: std::fmt::Debug>(res: Result<T, GdbStubError<(), std::io::Error>>) {
+    match res {
+        Ok(reason) => eprintln!("Disconnected with {:?}", reason),
+        // The Iris error behind it was logged as it happened
+        Err(e) if e.is_target_error() => eprintln!("gdb session ended by an Iris error"),
+        Err(e) if e.is_connection_error() => {
+            if let Some((e, _)) = e.into_connection_error() {
+                eprintln!("gdb session ended: {}", e);
+            }
+        }
+        Err(e) => eprintln!("gdb session ended: {:?}", e),
     }
 }
 
-fn get_iris(port: Option<u16>) -> Result<FastModelIris, std::io::Error> {
+/// Serve gdb the Armv7-M core `instance_id` until it disconnects
+fn serve_armv7m(
+    fvp: &mut FastModelIris,
+    instance_id: u32,
+    conn: GdbConnection,
+    args: &GdbProxyArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use cornea::gdb::semihosting::Semihosting;
+    use cornea::gdb::t32::{BreakpointKind, IrisGdbStub, SessionEnd};
+
+    let mut builder = IrisGdbStub::builder(fvp, instance_id);
+    if args.semihosting {
+        // Over a pipe, stdin and stdout belong to gdb
+        let mut semihosting = match args.listen {
+            Some(_) => Semihosting::new(stdout(), stdin()),
+            None => Semihosting::new(stderr(), empty()),
+        };
+        if let Some(root) = &args.semihosting_root {
+            semihosting = semihosting.files(root);
+        }
+        builder = builder.semihosting(semihosting);
+    }
+    if let Some(space) = args.space {
+        builder = builder.memory_space(space);
+    }
+    if args.follow_security_state {
+        builder = builder.follow_security_state();
+    }
+    if args.hw_breakpoints {
+        builder = builder.default_breakpoint_kind(BreakpointKind::Hardware);
+    }
+    if let Some(size) = args.flash_block_size {
+        builder = builder.flash_block_size(size);
+    }
+    if let Some(timeout) = args.timeout {
+        builder = builder.resume_timeout(Duration::from_secs(timeout));
+    }
+    if let Some(watchdog) = args.watchdog {
+        let timeout = Duration::from_secs(watchdog);
+        builder = builder.watchdog(timeout, timeout.min(Duration::from_secs(1)));
+    }
+    if args.resume_on_detach {
+        builder = builder.on_detach(SessionEnd::Resume);
+    }
+    // gdb expects to find the target halted
+    let (mut proxy, _) = builder.attach(false)?;
+    let stub = GdbStub::builder(conn)
+        .packet_buffer_size(args.packet_size)
+        .build()?;
+    let res = stub.run_blocking::<EventLoop<_, _>>(&mut proxy);
+    if let Ok(reason) = &res {
+        proxy.end_session(reason)?;
+    }
+    report_disconnect(res);
+    Ok(())
+}
+
+/// Serve each of the Armv7-M cores to its own gdb, on consecutive ports
+/// from `port`. Each core is served from a thread with its own connection to
+/// the Iris server, so that a stub waiting on a continue doesn't hold up the
+/// others.
+fn serve_separately(
+    fvp: &FastModelIris,
+    instance_ids: &[u32],
+    port: u16,
+    args: &GdbProxyArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use cornea::gdb::t32::GdbOverTcp;
+
+    let server = fvp
+        .peer_addr()
+        .ok_or("The address of the Iris server is unknown")?;
+    std::thread::scope(|scope| {
+        let threads: Vec<_> = instance_ids
+            .iter()
+            .zip(port..)
+            .map(|(&instance_id, port)| {
+                scope.spawn(move || -> Result<(), String> {
+                    let mut fvp = FastModelIris::connect(server).map_err(|e| e.to_string())?;
+                    eprintln!("Waiting for gdb on port {}", port);
+                    let conn =
+                        GdbOverTcp::listen(("localhost", port)).map_err(|e| e.to_string())?;
+                    serve_armv7m(&mut fvp, instance_id, Box::new(conn), args)
+                        .map_err(|e| e.to_string())?;
+                    fvp.close().map_err(|e| e.to_string())
+                })
+            })
+            .collect();
+        for thread in threads {
+            if let Err(e) = thread.join().expect("a gdb thread panicked") {
+                eprintln!("{}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn get_iris(port: Option<u16>) -> Result<FastModelIris, IrisError> {
     if let Some(port) = port {
-        FastModelIris::from_port(None, port)
+        FastModelIris::connect(("127.0.0.1", port))
     } else {
-        let mut fvp = FastModelIris::from_port(None, 7100);
+        let mut fvp = FastModelIris::connect(("127.0.0.1", 7100));
         for port in 7101..7105 {
             if fvp.is_ok() {
                 break;
             }
-            fvp = FastModelIris::from_port(None, port)
+            fvp = FastModelIris::connect(("127.0.0.1", port))
         }
         fvp
     }
@@ -196,15 +382,14 @@ fn get_iris(port: Option<u16>) -> Result<FastModelIris, std::io::Error> {
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Cli::parse();
     let mut fvp = get_iris(args.port)?;
-    let _my_id = fvp.register()?;
+    if let Some(path) = args.trace {
+        fvp.set_trace(File::create(path)?);
+    }
     use Command::*;
     match args.command {
         ResourceList(InstanceArgs { inst }) => {
             let instance = instance_registry::get_instance_by_name(&mut fvp, inst).unwrap();
-            println!(
-                "{:<6}│{:^6}│ {:>20} │ {}",
-                "type", "bits", "name", "description"
-            );
+            println!("{:<6}│{:^6}│ {:>20} │ description", "type", "bits", "name");
             println!("{:═<6}╪{:═^6}╪═{:═>20}═╪═{:═<20}", "", "", "", "");
             for res in resource::get_list(&mut fvp, instance.id, None, None)? {
                 let typ = if res.parameter_info.is_none() {
@@ -220,14 +405,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
         ResourceRead(ResourceReadArgs { inst, resource }) => {
             let instance = instance_registry::get_instance_by_name(&mut fvp, inst)?;
-            println!("{:>8} │ {}", "value", "name");
+            println!("{:>8} │ name", "value");
             println!("{:═>8}═╪═{:═<35}", "", "");
-            for res in resource::get_list(&mut fvp, instance.id, None, None)? {
-                if res.name.starts_with(&resource) {
-                    let val = resource::read(&mut fvp, instance.id, vec![res.id])?;
-                    if !val.data.is_empty() {
-                        println!("{:>8x} │ {}", val.data[0], res.name);
-                    }
+            for res in resource::get_list_filtered(&mut fvp, instance.id, None, Some(&resource))? {
+                let val = resource::read(&mut fvp, instance.id, vec![res.id])?;
+                if !val.data.is_empty() {
+                    println!("{:>8x} │ {}", val.data[0], res.name);
                 }
             }
         }
@@ -242,6 +425,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
             }
         }
+        CpuList => {
+            for instance in fvp.instances()? {
+                if instance.is_cpu {
+                    println!("{}", instance.name);
+                }
+            }
+        }
         MemoryRead(ReadMemArgs {
             inst,
             addr,
@@ -255,8 +445,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let buf: Vec<_> = memory
                 .data
                 .into_iter()
-                .map(|u| u.to_le_bytes())
-                .flatten()
+                .flat_map(|u| u.to_le_bytes())
                 .collect();
             print_hex_dump(addr, &buf, group_by.unwrap_or(GroupBy::U8));
         }
@@ -271,8 +460,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let addr = u64::from_str_radix(&addr, 16)?;
             let size = size.and_then(|s| u64::from_str_radix(&s, 16).ok());
             let bp = breakpoint::code(&mut fvp, instance.id, addr, size, 0, false, false)?;
-            simulation_time::run(&mut fvp, sim.id)?;
-            while simulation_time::get(&mut fvp, sim.id)?.running {}
+            fvp.run(sim.id)?;
+            fvp.wait_for_stop(sim.id, None)?;
             breakpoint::delete(&mut fvp, instance.id, bp)?;
         }
         Reset => {
@@ -280,24 +469,74 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 &mut fvp,
                 "framework.SimulationEngine".to_string(),
             )?;
-            simulation::reset(&mut fvp, sim.id, false)?;
-            simulation::wait(&mut fvp, sim.id)?;
+            fvp.reset(sim.id, simulation::ResetKind::Cold)?;
         }
-        GdbProxy(InstanceArgs { inst }) => {
-            let instance = instance_registry::get_instance_by_name(&mut fvp, inst.clone())?;
-            let res = resource::get_list(&mut fvp, instance.id, None, None)?;
-            if res.iter().any(|r| r.name == "X30") {
-                use cornea::gdb::a64::{GdbOverPipe, IrisGdbStub};
+        Stop => {
+            let sim = instance_registry::get_instance_by_name(
+                &mut fvp,
+                "framework.SimulationEngine".to_string(),
+            )?;
+            fvp.stop(sim.id)?;
+        }
+        GdbProxy(args) => {
+            use cornea::gdb::t32::{GdbOverPipe, GdbOverTcp};
 
-                let mut proxy = IrisGdbStub::from_instance(&mut fvp, instance.id)?;
-                let mut stub = GdbStub::new(GdbOverPipe::new(stdin(), stdout()));
-                eprintln!("Disconnected with {:?}", stub.run(&mut proxy)?);
-            } else {
-                use cornea::gdb::t32::{GdbOverPipe, IrisGdbStub};
+            let mut instances = Vec::with_capacity(args.inst.len());
+            for name in &args.inst {
+                match fvp.find_instance(name)? {
+                    Some(instance) => instances.push(instance),
+                    None => return Err(format!("No instance named {}", name).into()),
+                }
+            }
+            let instance = instances[0].clone();
+            let arch = fvp.detect_arch(instance.id)?;
+            if instances.len() > 1 && arch != ArchKind::Armv7m {
+                return Err("Only Armv7-M cores can be debugged together".into());
+            }
+            if args.separate && arch != ArchKind::Armv7m {
+                return Err("Only Armv7-M cores can be served separately".into());
+            }
+            if let (true, Some(port)) = (args.separate, args.listen) {
+                let ids: Vec<u32> = instances.iter().map(|instance| instance.id).collect();
+                serve_separately(&fvp, &ids, port, &args)?;
+                fvp.close()?;
+                return Ok(());
+            }
+            let conn: GdbConnection = match args.listen {
+                Some(port) => {
+                    eprintln!("Waiting for gdb on port {}", port);
+                    Box::new(GdbOverTcp::listen(("localhost", port))?)
+                }
+                None => Box::new(GdbOverPipe::new(stdin(), stdout())),
+            };
+            if instances.len() > 1 {
+                use cornea::gdb::multi::IrisGdbStubMulti;
+
+                let ids: Vec<u32> = instances.iter().map(|instance| instance.id).collect();
+                let mut proxy = IrisGdbStubMulti::from_instances(&mut fvp, &ids)?;
+                if let Some(timeout) = args.timeout {
+                    proxy = proxy.with_resume_timeout(Duration::from_secs(timeout));
+                }
+                let stub = GdbStub::builder(conn)
+                    .packet_buffer_size(args.packet_size)
+                    .build()?;
+                report_disconnect(stub.run_blocking::<EventLoop<_, _>>(&mut proxy));
+            } else if arch == ArchKind::AArch64 {
+                use cornea::gdb::a64::IrisGdbStub;
 
                 let mut proxy = IrisGdbStub::from_instance(&mut fvp, instance.id)?;
-                let mut stub = GdbStub::new(GdbOverPipe::new(stdin(), stdout()));
-                eprintln!("Disconnected with {:?}", stub.run(&mut proxy)?);
+                if let Some(space) = args.space {
+                    proxy = proxy.with_memory_space(space)?;
+                }
+                if let Some(timeout) = args.timeout {
+                    proxy = proxy.with_resume_timeout(Duration::from_secs(timeout));
+                }
+                let stub = GdbStub::builder(conn)
+                    .packet_buffer_size(args.packet_size)
+                    .build()?;
+                report_disconnect(stub.run_blocking::<EventLoop<_, _>>(&mut proxy));
+            } else {
+                serve_armv7m(&mut fvp, instance.id, conn, &args)?;
             }
         }
     }